@@ -0,0 +1,48 @@
+//! Compares the coin selection strategies on a synthetic wallet.
+//!
+//! ```text
+//! cargo run --example coinselect [UTXOS] [PAYMENTS] [DUST_THRESHOLD]
+//! ```
+
+use bitcoin::coinselect::{self, CoinSelector, Strategy};
+use std::env;
+
+fn main() {
+    let args: Vec<usize> = env::args().skip(1).map(|a| a.parse().expect("expected a number")).collect();
+    let utxo_count = args.first().copied().unwrap_or(500);
+    let payment_count = args.get(1).copied().unwrap_or(200);
+    let dust_threshold = args.get(2).copied().unwrap_or(10) as i32;
+
+    let utxos = coinselect::synthetic_utxos(utxo_count, 1);
+    let payments: Vec<i32> = coinselect::synthetic_utxos(payment_count, 2)
+        .iter()
+        .map(|value| value / 2 + 1)
+        .collect();
+    println!(
+        "{} outputs worth {}, {} payments worth {}, dust threshold {}",
+        utxos.len(),
+        utxos.iter().map(|&v| v as i64).sum::<i64>(),
+        payments.len(),
+        payments.iter().map(|&v| v as i64).sum::<i64>(),
+        dust_threshold
+    );
+    println!();
+    println!(
+        "{:<18} {:>8} {:>10} {:>10} {:>6} {:>11} {:>11}",
+        "strategy", "failed", "changeless", "avg inputs", "dust", "final utxos", "final dust"
+    );
+    for strategy in Strategy::ALL {
+        let selector = CoinSelector::new(strategy).dust_threshold(dust_threshold);
+        let report = coinselect::simulate(&selector, &utxos, &payments, 3);
+        println!(
+            "{:<18} {:>8} {:>10} {:>10.2} {:>6} {:>11} {:>11}",
+            strategy.name(),
+            report.failures,
+            report.changeless,
+            report.average_inputs(),
+            report.dust,
+            report.final_utxos,
+            report.final_dust_utxos
+        );
+    }
+}
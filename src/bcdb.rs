@@ -1,45 +1,1325 @@
-use std::env;
-use leveldb::kv::KV;
-use leveldb::options::{Options, WriteOptions, ReadOptions};
-use leveldb::database::Database;
-use byteorder::{ByteOrder, LittleEndian};
+use crate::block::{Block, BlockHeader};
+use crate::blockfiles::{BlockFiles, BlockPos};
+use crate::chainstate::{BlockUndo, Coin, SpentCoin};
+use crate::config::Config;
+use crate::encode;
+use crate::filter::BlockFilter;
+use crate::store::{Backend, ChainStore, DiskStore, WriteBatch};
+use crate::transaction::Transaction;
+use crate::utils;
 
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
-pub struct BlockchainDb {
-    database: Database<i32>,
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Headers by block hash.
+type Headers = HashMap<Vec<u8>, BlockHeader>;
+
+/// Number of blocks below the tip whose files are never pruned.
+pub const MIN_BLOCKS_TO_KEEP: u64 = 288;
+
+/// Key of the hash of the last block.
+const TIP_KEY: &[u8] = b"l";
+/// Key of the tip in the first version, which stored whole blocks in a
+/// format that can no longer be read.
+const LEGACY_TIP_KEY: &[u8] = b"1";
+/// Key of the height of the last block, as a little-endian `u64`.
+const TIP_HEIGHT_KEY: &[u8] = b"t";
+/// Key of the hash of the block the UTXO set and undo data are up to date with.
+const UTXO_TIP_KEY: &[u8] = b"c";
+/// Key of the highest height whose block file was pruned, as a little-endian `u64`.
+const PRUNE_HEIGHT_KEY: &[u8] = b"P";
+/// Prefix of the keys mapping a block hash to its 80-byte header.
+const HEADER_PREFIX: u8 = b'h';
+/// Prefix of the keys mapping a block hash to its transactions, for blocks
+/// stored in the database rather than in block files.
+const BODY_PREFIX: u8 = b'b';
+/// Prefix of the keys mapping a block hash to its [`BlockPos`], or to an
+/// empty value once its file was pruned or if the chain was started from a
+/// UTXO snapshot above it.
+const POS_PREFIX: u8 = b'p';
+/// Prefix of the keys mapping a big-endian `u32` block file number to the
+/// highest height of a block in it, as a little-endian `u64`.
+const FILE_PREFIX: u8 = b'i';
+/// Prefix of the keys mapping a block hash to its compact filter.
+const FILTER_PREFIX: u8 = b'f';
+/// Prefix of the keys mapping a big-endian `u64` height to the hash of the
+/// block at that height in the active chain.
+const HEIGHT_PREFIX: u8 = b'n';
+/// Prefix of the keys mapping a txid and a big-endian `u32` output index to
+/// an unspent [`Coin`].
+const COIN_PREFIX: u8 = b'u';
+/// Prefix of the keys mapping a block hash to its [`BlockUndo`].
+const UNDO_PREFIX: u8 = b'r';
+/// Prefix of the keys marking a block hash as invalidated by the operator.
+const INVALID_PREFIX: u8 = b'x';
+
+/// Blocks, headers and chain state kept in a [`ChainStore`], with the block
+/// bodies optionally in [`BlockFiles`].
+pub struct BlockchainDb<S: ChainStore = DiskStore> {
+    store: S,
+    files: Option<BlockFiles>,
+    /// Size in bytes the block files are pruned down to, if pruning.
+    prune_target: Option<u64>,
 }
 
 impl BlockchainDb {
-    pub fn new(local_path: &str) -> Result<Self> {
-        let mut dir_path = env::current_dir()?;
-        dir_path.push(local_path);
+    /// Opens the database at `path` with the default backend.
+    pub fn new(path: &Path) -> Result<Self> {
+        BlockchainDb::open(Backend::default(), path)
+    }
+
+    /// Opens the database at `path`, refusing one written by the first
+    /// version rather than starting a new chain in it.
+    pub fn open(backend: Backend, path: &Path) -> Result<Self> {
+        let store = DiskStore::open(backend, path)?;
+        if store.get(LEGACY_TIP_KEY)?.is_some() {
+            return Err(format!(
+                "{} holds a chain in the original format, which is no longer supported; \
+                 move it away or delete it to start a new chain",
+                path.display()
+            )
+            .into());
+        }
+        Ok(BlockchainDb::with_store(store))
+    }
+
+    /// Opens the database and block files selected by `config`, creating
+    /// the data directory if needed.
+    pub fn from_config(config: &Config) -> Result<Self> {
+        if let Some(dir) = config.db_path().parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let files = BlockFiles::open(&config.blocks_dir())?;
+        Ok(BlockchainDb::open(config.backend(), config.db_path())?
+            .with_block_files(files)
+            .with_prune_target(config.prune_target()))
+    }
+}
 
-        let mut opts = Options::new();
-        opts.create_if_missing = true;
+impl<S: ChainStore> BlockchainDb<S> {
+    /// Uses `store` for everything, block bodies included.
+    pub fn with_store(store: S) -> Self {
+        BlockchainDb {
+            store,
+            files: None,
+            prune_target: None,
+        }
+    }
+
+    /// Appends the bodies of new blocks to `files`. Bodies already in the
+    /// database stay readable.
+    pub fn with_block_files(mut self, files: BlockFiles) -> Self {
+        self.files = Some(files);
+        self
+    }
 
-        let database = Database::open(&dir_path, opts)?;
+    /// Deletes the oldest block files once they take more than `target`
+    /// bytes. Needs block files.
+    pub fn with_prune_target(mut self, target: Option<u64>) -> Self {
+        self.prune_target = target;
+        self
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
 
-        Ok(BlockchainDb { database })
+    pub fn block_files(&self) -> Option<&BlockFiles> {
+        self.files.as_ref()
     }
 
     pub fn write(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
-        let write_opts = WriteOptions::new();
-        self.database.put(write_opts, from_u8(key), val).map_err(Into::into)
+        self.store.put(key, val)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.store.delete(key)
+    }
+
+    /// Applies every write of `batch` atomically and waits until it is on disk.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.store.write_batch(batch)
     }
 
     pub fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        let read_options = ReadOptions::new();
-        self.database.get(read_options, from_u8(key)).map_err(Into::into)
+        self.store.get(key)
+    }
+
+    /// Returns the hash of the last block, if a chain exists.
+    pub fn read_tip(&self) -> Result<Option<Vec<u8>>> {
+        self.read(TIP_KEY)
+    }
+
+    pub fn write_tip(&mut self, hash: &[u8]) -> Result<()> {
+        self.write(TIP_KEY, hash)
+    }
+
+    /// Returns the height of the last block, if it was recorded.
+    pub fn read_tip_height(&self) -> Result<Option<u64>> {
+        read_u64(self.read(TIP_HEIGHT_KEY)?)
+    }
+
+    /// Returns the hash of the block at `height` in the active chain.
+    pub fn read_hash_at(&self, height: u64) -> Result<Option<Vec<u8>>> {
+        self.read(&height_key(height))
+    }
+
+    /// Returns the highest height whose block data was pruned, if any was.
+    pub fn read_prune_height(&self) -> Result<Option<u64>> {
+        read_u64(self.read(PRUNE_HEIGHT_KEY)?)
+    }
+
+    pub fn read_header(&self, hash: &[u8]) -> Result<Option<BlockHeader>> {
+        match self.read(&prefixed(HEADER_PREFIX, hash))? {
+            Some(data) => BlockHeader::deserialize(&data)
+                .map(Some)
+                .ok_or_else(|| "invalid block header".into()),
+            None => Ok(None),
+        }
+    }
+
+    pub fn write_header(&mut self, header: &BlockHeader) -> Result<()> {
+        self.write(&prefixed(HEADER_PREFIX, &header.hash()), &header.serialize())
+    }
+
+    /// Returns whether the transactions of a block are stored. Pruned blocks
+    /// count as stored, since they were once.
+    pub fn has_block_data(&self, hash: &[u8]) -> Result<bool> {
+        Ok(self.read(&prefixed(POS_PREFIX, hash))?.is_some()
+            || self.read(&prefixed(BODY_PREFIX, hash))?.is_some())
+    }
+
+    /// Returns whether the data of a block is gone, because its file was
+    /// pruned or the chain was started from a UTXO snapshot above it.
+    pub fn is_pruned(&self, hash: &[u8]) -> Result<bool> {
+        Ok(self.read(&prefixed(POS_PREFIX, hash))?.is_some_and(|pos| pos.is_empty()))
+    }
+
+    /// Reads a full block, which needs both its header and its body. Pruned
+    /// blocks have no body.
+    pub fn read_block(&self, hash: &[u8]) -> Result<Option<Block>> {
+        let Some(header) = self.read_header(hash)? else {
+            return Ok(None);
+        };
+        if let Some(pos) = self.read(&prefixed(POS_PREFIX, hash))? {
+            if pos.is_empty() {
+                return Ok(None);
+            }
+            let pos: BlockPos = encode::deserialize(&pos)?;
+            let files = self.files.as_ref().ok_or("block files are not open")?;
+            return Ok(Some(Block::deserialize(&files.read(&pos)?)?));
+        }
+        let Some(body) = self.read(&prefixed(BODY_PREFIX, hash))? else {
+            return Ok(None);
+        };
+        let transactions: Vec<Transaction> = encode::deserialize(&body)?;
+        Ok(Some(Block {
+            header,
+            transactions,
+        }))
+    }
+
+    /// Reads the compact filter of a block. Blocks stored before filters
+    /// were introduced have none.
+    pub fn read_filter(&self, hash: &[u8]) -> Result<Option<BlockFilter>> {
+        match self.read(&prefixed(FILTER_PREFIX, hash))? {
+            Some(data) => BlockFilter::deserialize(&data)
+                .map(Some)
+                .ok_or_else(|| "invalid block filter".into()),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the unspent output `vout` of `txid`, if there is one.
+    pub fn read_coin(&self, txid: &[u8], vout: u32) -> Result<Option<Coin>> {
+        match self.read(&coin_key(txid, vout))? {
+            Some(data) => Ok(Some(encode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns every unspent output with its txid and index.
+    pub fn read_coins(&self) -> Result<Vec<(Vec<u8>, u32, Coin)>> {
+        self.store
+            .scan_prefix(&[COIN_PREFIX])
+            .map(|entry| {
+                let (key, val) = entry?;
+                // the prefix and a `u32` output index, as `check` expects
+                if key.len() < 1 + 4 {
+                    return Err("malformed coin key".into());
+                }
+                let (txid, vout) = key[1..].split_at(key.len() - 5);
+                let vout = u32::from_be_bytes(vout.try_into()?);
+                Ok((txid.to_vec(), vout, encode::deserialize(&val)?))
+            })
+            .collect()
+    }
+
+    /// Returns the outputs a block spent, kept until its file is pruned.
+    pub fn read_undo(&self, hash: &[u8]) -> Result<Option<BlockUndo>> {
+        match self.read(&prefixed(UNDO_PREFIX, hash))? {
+            Some(data) => Ok(Some(encode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores a block on top of the tip and makes it the tip at `height`,
+    /// updating the UTXO set, in one atomic write. Fails without writing
+    /// anything if the block spends an output that is not in the UTXO set.
+    pub fn connect_block(&mut self, block: &Block, height: u64) -> Result<()> {
+        let hash = block.hash();
+        let mut batch = WriteBatch::new();
+        let undo = self.apply_block(&mut batch, block, height)?;
+        if !self.has_block_data(&hash)? || self.is_pruned(&hash)? {
+            self.put_block(&mut batch, block, height)?;
+        }
+        batch.put(&prefixed(UNDO_PREFIX, &hash), &encode::serialize(&undo));
+        batch.put(UTXO_TIP_KEY, &hash);
+        put_tip(&mut batch, &hash, height);
+        self.write_batch(batch)
+    }
+
+    /// Removes the tip from the active chain, putting back the outputs it
+    /// spent. The block itself stays stored. Returns the disconnected block.
+    pub fn disconnect_tip(&mut self) -> Result<Block> {
+        let tip = self.read_tip()?.ok_or("there is no chain")?;
+        let height = self.read_tip_height()?.ok_or("the tip height is unknown")?;
+        if height == 0 {
+            return Err("the genesis block cannot be disconnected".into());
+        }
+        let block = self.read_block(&tip)?.ok_or("the data of the tip block is missing")?;
+        let undo = self.read_undo(&tip)?.ok_or("the undo data of the tip block is missing")?;
+
+        let prev = block.header.prev_block_hash;
+        let mut batch = WriteBatch::new();
+        undo_block(&mut batch, &block, &undo);
+        batch.put(UTXO_TIP_KEY, &prev);
+        batch.delete(&height_key(height));
+        batch.put(TIP_KEY, &prev);
+        batch.put(TIP_HEIGHT_KEY, &(height - 1).to_le_bytes());
+        self.write_batch(batch)?;
+        Ok(block)
+    }
+
+    /// Returns whether a block was marked invalid by
+    /// [`BlockchainDb::invalidate_block`].
+    pub fn is_invalid(&self, hash: &[u8]) -> Result<bool> {
+        Ok(self.read(&prefixed(INVALID_PREFIX, hash))?.is_some())
+    }
+
+    /// Marks a block invalid and, if it is in the active chain, disconnects
+    /// it and every block above it, putting back the outputs they spent.
+    /// The tip then moves to the best remaining branch. Returns the
+    /// disconnected blocks, tip first.
+    pub fn invalidate_block(&mut self, hash: &[u8]) -> Result<Vec<Block>> {
+        let header = self.read_header(hash)?.ok_or("block not found")?;
+        if header.is_genesis() {
+            return Err("the genesis block cannot be invalidated".into());
+        }
+        let height = self.height_of(hash)?.ok_or("the ancestors of the block are missing")?;
+        let tip_height = self.read_tip_height()?.ok_or("the tip height is unknown")?;
+        let active = self.read_hash_at(height)?.as_deref() == Some(hash);
+        if active {
+            for h in height..=tip_height {
+                let hash = self.read_hash_at(h)?.ok_or("the height index is incomplete")?;
+                if self.is_pruned(&hash)? || self.read(&prefixed(UNDO_PREFIX, &hash))?.is_none() {
+                    return Err(format!("the block at height {} is pruned and cannot be disconnected", h).into());
+                }
+            }
+        }
+
+        // marked first, so an interrupted run leaves it invalid and can be repeated
+        self.write(&prefixed(INVALID_PREFIX, hash), &[])?;
+        let mut disconnected = Vec::new();
+        if active {
+            for _ in height..=tip_height {
+                disconnected.push(self.disconnect_tip()?);
+            }
+        }
+        self.activate_best_chain()?;
+        Ok(disconnected)
+    }
+
+    /// Clears the invalid mark of a block and its descendants, then moves
+    /// the tip to the best chain, which may include them again. Returns the
+    /// hashes of the connected blocks.
+    pub fn reconsider_block(&mut self, hash: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.read_header(hash)?.ok_or("block not found")?;
+        let mut batch = WriteBatch::new();
+        for key in self.store.keys_with_prefix(&[INVALID_PREFIX])? {
+            if self.descends_from(&key[1..], hash)? {
+                batch.delete(&key);
+            }
+        }
+        self.write_batch(batch)?;
+        self.activate_best_chain()
+    }
+
+    /// Moves the tip to the highest block whose ancestors are all stored
+    /// and valid, if it is above the tip, disconnecting the blocks that are
+    /// not in its branch. A block that fails to connect is marked invalid
+    /// and the next best branch is tried. Returns the hashes of the
+    /// connected blocks.
+    pub fn activate_best_chain(&mut self) -> Result<Vec<Vec<u8>>> {
+        let mut connected = Vec::new();
+        'search: loop {
+            let tip_height = self.read_tip_height()?.ok_or("the tip height is unknown")?;
+            let (headers, heights) = self.chain_heights()?;
+            let mut candidates: Vec<(&Vec<u8>, u64)> = heights
+                .iter()
+                .filter(|&(_, &height)| height > tip_height)
+                .map(|(hash, &height)| (hash, height))
+                .collect();
+            candidates.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+            // the blocks from the active chain up to the best candidate
+            let mut branch = None;
+            for (hash, mut height) in candidates {
+                let mut path = Vec::new();
+                let mut current = hash.clone();
+                let joins_active_chain = loop {
+                    if self.read_hash_at(height)?.as_deref() == Some(current.as_slice()) {
+                        break true;
+                    }
+                    if height == 0 {
+                        break false;
+                    }
+                    path.push(current.clone());
+                    current = headers[&current].prev_block_hash.to_vec();
+                    height -= 1;
+                };
+                let mut pruned = false;
+                for hash in &path {
+                    pruned |= self.is_pruned(hash)?;
+                }
+                if joins_active_chain && !pruned {
+                    branch = Some((height, path));
+                    break;
+                }
+            }
+            let Some((fork_height, path)) = branch else {
+                return Ok(connected);
+            };
+
+            while self.read_tip_height()?.ok_or("the tip height is unknown")? > fork_height {
+                self.disconnect_tip()?;
+            }
+            for (height, hash) in (fork_height + 1..).zip(path.iter().rev()) {
+                let block = self.read_block(hash)?.ok_or("a block of the branch is missing")?;
+                if let Err(e) = self.connect_block(&block, height) {
+                    utils::log(&format!("Block {} is invalid: {}", utils::hex_string(hash), e));
+                    self.write(&prefixed(INVALID_PREFIX, hash), &[])?;
+                    continue 'search;
+                }
+                connected.push(hash.clone());
+            }
+        }
+    }
+
+    /// Starts an empty database from a UTXO snapshot: the headers of the
+    /// chain from the genesis block to the base block, and the UTXO set at
+    /// the base block. The blocks up to the base count as pruned, so new
+    /// blocks can be connected on top but none below can be disconnected.
+    pub fn load_utxo_snapshot(
+        &mut self,
+        headers: &[BlockHeader],
+        coins: &[(Vec<u8>, u32, Coin)],
+    ) -> Result<()> {
+        if self.read_tip()?.is_some() || !self.store.keys_with_prefix(&[HEADER_PREFIX])?.is_empty() {
+            return Err("the database already holds a chain".into());
+        }
+        let base = headers.last().ok_or("the snapshot has no headers")?;
+        let base_hash = base.hash();
+        let base_height = headers.len() as u64 - 1;
+
+        let mut batch = WriteBatch::new();
+        for (height, header) in headers.iter().enumerate() {
+            let hash = header.hash();
+            batch.put(&prefixed(HEADER_PREFIX, &hash), &header.serialize());
+            batch.put(&prefixed(POS_PREFIX, &hash), &[]);
+            batch.put(&height_key(height as u64), &hash);
+        }
+        for (txid, vout, coin) in coins {
+            batch.put(&coin_key(txid, *vout), &encode::serialize(coin));
+        }
+        batch.put(PRUNE_HEIGHT_KEY, &base_height.to_le_bytes());
+        batch.put(UTXO_TIP_KEY, &base_hash);
+        put_tip(&mut batch, &base_hash, base_height);
+        self.write_batch(batch)
+    }
+
+    /// Deletes the oldest block files while they take more than the prune
+    /// target, keeping the files of the last [`MIN_BLOCKS_TO_KEEP`] blocks
+    /// and the file being written. Headers, filters and the UTXO set stay.
+    /// Returns the numbers of the deleted files.
+    pub fn prune(&mut self) -> Result<Vec<u32>> {
+        let (Some(target), Some(files)) = (self.prune_target, &self.files) else {
+            return Ok(Vec::new());
+        };
+        let tip_height = self.read_tip_height()?.unwrap_or(0);
+        let list = files.list()?;
+        let current = files.current();
+        let mut total: u64 = list.iter().map(|&(_, size)| size).sum();
+
+        let mut pruned = Vec::new();
+        for (file, size) in list {
+            if total <= target || file >= current {
+                break;
+            }
+            // files without an entry hold no committed block
+            let max_height = read_u64(self.read(&file_key(file))?)?;
+            if max_height.is_some_and(|height| height + MIN_BLOCKS_TO_KEEP > tip_height) {
+                break;
+            }
+
+            let mut batch = WriteBatch::new();
+            for entry in self.store.scan_prefix(&[POS_PREFIX]) {
+                let (key, val) = entry?;
+                if !val.is_empty() && encode::deserialize::<BlockPos>(&val)?.file == file {
+                    batch.put(&key, &[]);
+                    batch.delete(&prefixed(UNDO_PREFIX, &key[1..]));
+                }
+            }
+            batch.delete(&file_key(file));
+            if let Some(height) = max_height {
+                let prune_height = self.read_prune_height()?.map_or(height, |h| h.max(height));
+                batch.put(PRUNE_HEIGHT_KEY, &prune_height.to_le_bytes());
+            }
+            self.write_batch(batch)?;
+            if let Some(files) = &self.files {
+                files.remove(file)?;
+            }
+            total -= size;
+            pruned.push(file);
+        }
+        Ok(pruned)
+    }
+
+    /// Stores the header, the body and the compact filter of a block.
+    fn put_block(&mut self, batch: &mut WriteBatch, block: &Block, height: u64) -> Result<()> {
+        let hash = block.hash();
+        match &mut self.files {
+            Some(files) => {
+                let pos = files.append(&block.serialize())?;
+                batch.put(&prefixed(POS_PREFIX, &hash), &encode::serialize(&pos));
+                let max_height = read_u64(self.store.get(&file_key(pos.file))?)?;
+                let max_height = max_height.map_or(height, |h| h.max(height));
+                batch.put(&file_key(pos.file), &max_height.to_le_bytes());
+            }
+            None => {
+                batch.put(&prefixed(BODY_PREFIX, &hash), &encode::serialize(&block.transactions));
+            }
+        }
+        batch.put(&prefixed(FILTER_PREFIX, &hash), &BlockFilter::build(block).serialize());
+        batch.put(&prefixed(HEADER_PREFIX, &hash), &block.header.serialize());
+        Ok(())
+    }
+
+    /// Adds the UTXO set changes of a block at `height` to `batch` and
+    /// returns the coins it spends.
+    fn apply_block(&self, batch: &mut WriteBatch, block: &Block, height: u64) -> Result<BlockUndo> {
+        let mut undo = BlockUndo::default();
+        // outputs created in this block, which later transactions may spend
+        let mut created: HashMap<(Vec<u8>, u32), Coin> = HashMap::new();
+        let mut spent: HashSet<(Vec<u8>, u32)> = HashSet::new();
+        for tx in &block.transactions {
+            if !tx.is_coinbase() {
+                for input in &tx.vin {
+                    let outpoint = (input.txid.clone(), input.vout as u32);
+                    if created.remove(&outpoint).is_some() {
+                        continue;
+                    }
+                    let coin = if spent.contains(&outpoint) {
+                        None
+                    } else {
+                        self.read_coin(&outpoint.0, outpoint.1)?
+                    };
+                    let Some(coin) = coin else {
+                        return Err(format!(
+                            "block {} spends missing output {}:{}",
+                            utils::hex_string(&block.hash()),
+                            utils::hex_string(&outpoint.0),
+                            outpoint.1
+                        )
+                        .into());
+                    };
+                    batch.delete(&coin_key(&outpoint.0, outpoint.1));
+                    spent.insert(outpoint.clone());
+                    undo.spent.push(SpentCoin {
+                        txid: outpoint.0,
+                        vout: outpoint.1,
+                        coin,
+                    });
+                }
+            }
+            for (vout, output) in tx.vout.iter().enumerate() {
+                let coin = Coin {
+                    output: output.clone(),
+                    height,
+                    is_coinbase: tx.is_coinbase(),
+                };
+                created.insert((tx.id.clone(), vout as u32), coin);
+            }
+        }
+        for ((txid, vout), coin) in created {
+            batch.put(&coin_key(&txid, vout), &encode::serialize(&coin));
+        }
+        Ok(undo)
+    }
+}
+
+impl<S: ChainStore> BlockchainDb<S> {
+    /// Checks that the tip is fully stored and indexed and that the UTXO
+    /// set matches it. Every block is connected in a single batch, so this
+    /// only fails for databases left behind by a crash of an older version
+    /// or damaged on disk.
+    fn is_consistent(&self) -> Result<bool> {
+        let Some(tip) = self.read_tip()? else {
+            return Ok(self.store.keys_with_prefix(&[HEADER_PREFIX])?.is_empty());
+        };
+        Ok(self.chain_is_consistent(&tip)? && self.read(UTXO_TIP_KEY)? == Some(tip))
+    }
+
+    fn chain_is_consistent(&self, tip: &[u8]) -> Result<bool> {
+        let Some(height) = self.read_tip_height()? else {
+            return Ok(false);
+        };
+        Ok(self.read_hash_at(height)?.as_deref() == Some(tip)
+            && self.read(&height_key(height + 1))?.is_none()
+            && self.read_header(tip)?.is_some()
+            && self.has_block_data(tip)?
+            && (self.read(&prefixed(FILTER_PREFIX, tip))?.is_some() || self.is_pruned(tip)?))
+    }
+
+    /// Repairs a database whose tip, indexes or UTXO set do not match the
+    /// stored blocks. The tip moves back to the last block whose ancestors
+    /// are all stored, or to the highest such block if the tip itself is
+    /// unknown, and the height index and missing filters are rebuilt. The
+    /// UTXO set is then brought to the tip, rebuilding it from the blocks
+    /// if needed. Returns a description of each repair.
+    pub fn recover(&mut self) -> Result<Vec<String>> {
+        if self.is_consistent()? {
+            return Ok(Vec::new());
+        }
+        let mut repairs = Vec::new();
+        match self.read_tip()? {
+            Some(tip) if self.chain_is_consistent(&tip)? => {}
+            _ => repairs.extend(self.repair_chain()?),
+        }
+        repairs.extend(self.repair_utxo_set()?);
+        Ok(repairs)
+    }
+
+    fn repair_chain(&mut self) -> Result<Vec<String>> {
+        let mut repairs = Vec::new();
+        let (headers, heights) = self.chain_heights()?;
+
+        let old_tip = self.read_tip()?;
+        let mut tip = None;
+        if let Some(mut current) = old_tip.clone() {
+            // keep the tip's chain, dropping blocks that are not fully stored
+            while let Some(header) = headers.get(&current) {
+                if heights.contains_key(&current) {
+                    tip = Some(current);
+                    break;
+                }
+                current = header.prev_block_hash.to_vec();
+            }
+        }
+        if tip.is_none() {
+            tip = heights
+                .iter()
+                .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+                .map(|(hash, _)| hash.clone());
+        }
+
+        let mut batch = WriteBatch::new();
+        for key in self.store.keys_with_prefix(&[HEIGHT_PREFIX])? {
+            batch.delete(&key);
+        }
+        match &tip {
+            Some(tip) => {
+                if old_tip.as_ref() != Some(tip) {
+                    repairs.push(format!("moved the tip to {}", utils::hex_string(tip)));
+                }
+                let mut current = tip.clone();
+                loop {
+                    let header = &headers[&current];
+                    batch.put(&height_key(heights[&current]), &current);
+                    if self.read(&prefixed(FILTER_PREFIX, &current))?.is_none() {
+                        if let Ok(Some(block)) = self.read_block(&current) {
+                            let filter = BlockFilter::build(&block).serialize();
+                            batch.put(&prefixed(FILTER_PREFIX, &current), &filter);
+                            repairs.push(format!("rebuilt the filter of {}", utils::hex_string(&current)));
+                        }
+                    }
+                    if header.prev_block_hash == [0; 32] {
+                        break;
+                    }
+                    current = header.prev_block_hash.to_vec();
+                }
+                batch.put(TIP_KEY, tip);
+                batch.put(TIP_HEIGHT_KEY, &heights[tip].to_le_bytes());
+            }
+            None => {
+                if old_tip.is_some() {
+                    repairs.push("removed the tip, no complete block is stored".to_string());
+                }
+                batch.delete(TIP_KEY);
+                batch.delete(TIP_HEIGHT_KEY);
+            }
+        }
+        repairs.push("rebuilt the height index".to_string());
+        self.write_batch(batch)?;
+        Ok(repairs)
+    }
+
+    /// Reads every header and computes the height of each block whose
+    /// ancestors are all stored and none of which is invalid.
+    fn chain_heights(&self) -> Result<(Headers, HashMap<Vec<u8>, u64>)> {
+        let mut headers = HashMap::new();
+        for key in self.store.keys_with_prefix(&[HEADER_PREFIX])? {
+            // undecodable headers are treated as missing
+            if let Ok(Some(header)) = self.read_header(&key[1..]) {
+                headers.insert(key[1..].to_vec(), header);
+            }
+        }
+        let mut bodies: HashSet<Vec<u8>> = HashSet::new();
+        for prefix in [BODY_PREFIX, POS_PREFIX] {
+            for key in self.store.keys_with_prefix(&[prefix])? {
+                bodies.insert(key[1..].to_vec());
+            }
+        }
+        for key in self.store.keys_with_prefix(&[INVALID_PREFIX])? {
+            bodies.remove(&key[1..]);
+        }
+
+        let mut heights: HashMap<Vec<u8>, u64> = HashMap::new();
+        for hash in headers.keys() {
+            let mut path = Vec::new();
+            let mut current = hash.clone();
+            let base = loop {
+                if let Some(&height) = heights.get(&current) {
+                    break Some(height);
+                }
+                let Some(header) = headers.get(&current).filter(|_| bodies.contains(&current)) else {
+                    break None;
+                };
+                path.push(current.clone());
+                if header.prev_block_hash == [0; 32] {
+                    break Some(u64::MAX);
+                }
+                current = header.prev_block_hash.to_vec();
+            };
+            let Some(base) = base else { continue };
+            for (offset, hash) in path.iter().rev().enumerate() {
+                heights.insert(hash.clone(), base.wrapping_add(offset as u64 + 1));
+            }
+        }
+        Ok((headers, heights))
+    }
+
+    /// Brings the UTXO set to the tip: back along its undo data to a block
+    /// of the active chain, then forward along the chain. Without undo data
+    /// it is rebuilt from the genesis block, which fails once blocks were
+    /// pruned.
+    fn repair_utxo_set(&mut self) -> Result<Vec<String>> {
+        let tip = self.read_tip()?;
+        let mut utxo_tip = self.read(UTXO_TIP_KEY)?;
+        if utxo_tip == tip {
+            return Ok(Vec::new());
+        }
+        let mut repairs = Vec::new();
+
+        // the UTXO set is at `utxo_tip`, which has height `height`
+        let mut height = match &utxo_tip {
+            Some(hash) => self.height_of(hash)?,
+            None => None,
+        };
+        while let (Some(hash), Some(h)) = (utxo_tip.clone(), height) {
+            if self.read_hash_at(h)?.as_deref() == Some(hash.as_slice()) {
+                break;
+            }
+            let (Some(block), Some(undo)) = (self.read_block(&hash)?, self.read_undo(&hash)?) else {
+                height = None;
+                break;
+            };
+            let mut batch = WriteBatch::new();
+            undo_block(&mut batch, &block, &undo);
+            batch.put(UTXO_TIP_KEY, &block.header.prev_block_hash);
+            self.write_batch(batch)?;
+            repairs.push(format!("disconnected {} from the UTXO set", utils::hex_string(&hash)));
+            utxo_tip = Some(block.header.prev_block_hash.to_vec());
+            height = h.checked_sub(1);
+        }
+
+        let start = match height {
+            Some(h) if utxo_tip.is_some() => h + 1,
+            _ => {
+                let mut batch = WriteBatch::new();
+                for prefix in [COIN_PREFIX, UNDO_PREFIX] {
+                    for key in self.store.keys_with_prefix(&[prefix])? {
+                        batch.delete(&key);
+                    }
+                }
+                batch.delete(UTXO_TIP_KEY);
+                self.write_batch(batch)?;
+                repairs.push("rebuilt the UTXO set".to_string());
+                0
+            }
+        };
+        let Some(tip_height) = self.read_tip_height()?.filter(|_| tip.is_some()) else {
+            return Ok(repairs);
+        };
+        for h in start..=tip_height {
+            let hash = self.read_hash_at(h)?.ok_or("the height index is incomplete")?;
+            let block = self.read_block(&hash)?.ok_or_else(|| {
+                format!("cannot rebuild the UTXO set, the block at height {} is pruned or missing", h)
+            })?;
+            let mut batch = WriteBatch::new();
+            let undo = self.apply_block(&mut batch, &block, h)?;
+            batch.put(&prefixed(UNDO_PREFIX, &hash), &encode::serialize(&undo));
+            batch.put(UTXO_TIP_KEY, &hash);
+            self.write_batch(batch)?;
+        }
+        if start > 0 {
+            repairs.push(format!("connected blocks {} to {} to the UTXO set", start, tip_height));
+        }
+        Ok(repairs)
+    }
+
+    /// Returns whether `hash` is `ancestor` or a block above it.
+    fn descends_from(&self, hash: &[u8], ancestor: &[u8]) -> Result<bool> {
+        let mut current = hash.to_vec();
+        loop {
+            if current == ancestor {
+                return Ok(true);
+            }
+            match self.read_header(&current)? {
+                Some(header) if !header.is_genesis() => current = header.prev_block_hash.to_vec(),
+                _ => return Ok(false),
+            }
+        }
     }
+
+    /// Counts the headers from `hash` back to the genesis block.
+    fn height_of(&self, hash: &[u8]) -> Result<Option<u64>> {
+        let mut height = 0;
+        let Some(mut header) = self.read_header(hash)? else {
+            return Ok(None);
+        };
+        while header.prev_block_hash != [0; 32] {
+            let Some(prev) = self.read_header(&header.prev_block_hash)? else {
+                return Ok(None);
+            };
+            header = prev;
+            height += 1;
+        }
+        Ok(Some(height))
+    }
+}
+
+/// What kind of problem [`BlockchainDb::check`] found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultKind {
+    /// A record cannot be read or decoded.
+    Undecodable,
+    /// A header or block does not hash to the hash it is stored under, or
+    /// the transactions do not match the Merkle root.
+    HashMismatch,
+    /// The previous block of a header is missing or damaged.
+    BrokenLink,
+    /// A record belongs to a block whose header is not stored.
+    Orphaned,
+    /// The tip, the height index or the UTXO set do not match the blocks.
+    Index,
 }
 
-/// Converts the last 4 bytes (or fewer) of a byte slice into an `i32` using little-endian order.
-fn from_u8(key: &[u8]) -> i32 {
-    let mut buffer = [0u8; 4];
-    let key_end = key.len().min(4);
-    let buffer_start = 4 - key_end;
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FaultKind::Undecodable => "undecodable",
+            FaultKind::HashMismatch => "hash mismatch",
+            FaultKind::BrokenLink => "broken link",
+            FaultKind::Orphaned => "orphaned",
+            FaultKind::Index => "index",
+        })
+    }
+}
 
-    buffer[buffer_start..].copy_from_slice(&key[key.len() - key_end..]);
+/// A problem found by [`BlockchainDb::check`].
+#[derive(Clone, Debug)]
+pub struct Fault {
+    pub kind: FaultKind,
+    /// The block concerned, if any.
+    pub hash: Option<Vec<u8>>,
+    pub detail: String,
+    /// The damaged record, if any.
+    key: Option<Vec<u8>>,
+}
+
+impl Fault {
+    fn new(kind: FaultKind, key: &[u8], detail: impl Into<String>) -> Self {
+        let hash = match key.first() {
+            Some(&COIN_PREFIX) | None => None,
+            _ => Some(key[1..].to_vec()),
+        };
+        Fault {
+            kind,
+            hash,
+            detail: detail.into(),
+            key: Some(key.to_vec()),
+        }
+    }
+
+    fn index(detail: impl Into<String>) -> Self {
+        Fault {
+            kind: FaultKind::Index,
+            hash: None,
+            detail: detail.into(),
+            key: None,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.hash {
+            Some(hash) => write!(f, "{}: block {}: {}", self.kind, utils::hex_string(hash), self.detail),
+            None => write!(f, "{}: {}", self.kind, self.detail),
+        }
+    }
+}
+
+/// The result of [`BlockchainDb::check`].
+#[derive(Clone, Debug, Default)]
+pub struct CheckReport {
+    /// Number of stored headers.
+    pub blocks: u64,
+    pub faults: Vec<Fault>,
+}
+
+impl<S: ChainStore> BlockchainDb<S> {
+    /// Reads every stored record without changing anything and reports
+    /// the records that cannot be decoded, headers and blocks that do not
+    /// match their hash, headers whose previous block is missing, records
+    /// of unknown blocks, and a tip, height index or UTXO set that do not
+    /// match the blocks.
+    pub fn check(&self) -> Result<CheckReport> {
+        let mut faults = Vec::new();
+
+        let mut headers: Headers = HashMap::new();
+        let mut damaged: HashSet<Vec<u8>> = HashSet::new();
+        let mut blocks = 0;
+        for entry in self.store.scan_prefix(&[HEADER_PREFIX]) {
+            let (key, val) = entry?;
+            let hash = key[1..].to_vec();
+            blocks += 1;
+            match BlockHeader::deserialize(&val) {
+                None => faults.push(Fault::new(FaultKind::Undecodable, &key, "the header is not 80 bytes")),
+                Some(header) if header.hash() != hash => faults.push(Fault::new(
+                    FaultKind::HashMismatch,
+                    &key,
+                    format!("the header hashes to {}", utils::hex_string(&header.hash())),
+                )),
+                Some(header) => {
+                    headers.insert(hash, header);
+                    continue;
+                }
+            }
+            damaged.insert(hash);
+        }
+
+        for prefix in [POS_PREFIX, BODY_PREFIX] {
+            for entry in self.store.scan_prefix(&[prefix]) {
+                let (key, val) = entry?;
+                let Some(header) = headers.get(&key[1..]) else {
+                    if !damaged.contains(&key[1..]) {
+                        faults.push(Fault::new(FaultKind::Orphaned, &key, "block data without a header"));
+                    }
+                    continue;
+                };
+                if prefix == POS_PREFIX && val.is_empty() {
+                    continue;
+                }
+                match self.decode_block_data(prefix, header, &val) {
+                    Err(e) => faults.push(Fault::new(FaultKind::Undecodable, &key, format!("block data: {}", e))),
+                    Ok(block) if block.hash() != key[1..] => faults.push(Fault::new(
+                        FaultKind::HashMismatch,
+                        &key,
+                        format!("the stored block hashes to {}", utils::hex_string(&block.hash())),
+                    )),
+                    Ok(block) if block.header.merkle_root != Block::compute_merkle_root(&block.transactions) => {
+                        faults.push(Fault::new(
+                            FaultKind::HashMismatch,
+                            &key,
+                            "the transactions do not match the Merkle root",
+                        ))
+                    }
+                    Ok(_) => continue,
+                }
+                damaged.insert(key[1..].to_vec());
+            }
+        }
+
+        for (prefix, what) in [(FILTER_PREFIX, "filter"), (UNDO_PREFIX, "undo data"), (INVALID_PREFIX, "invalid mark")] {
+            for entry in self.store.scan_prefix(&[prefix]) {
+                let (key, val) = entry?;
+                if !headers.contains_key(&key[1..]) {
+                    if !damaged.contains(&key[1..]) {
+                        faults.push(Fault::new(FaultKind::Orphaned, &key, format!("{} without a header", what)));
+                    }
+                    continue;
+                }
+                let decodes = match prefix {
+                    FILTER_PREFIX => BlockFilter::deserialize(&val).is_some(),
+                    UNDO_PREFIX => encode::deserialize::<BlockUndo>(&val).is_ok(),
+                    _ => true,
+                };
+                if !decodes {
+                    faults.push(Fault::new(FaultKind::Undecodable, &key, format!("the {} cannot be decoded", what)));
+                }
+            }
+        }
+
+        for (hash, header) in &headers {
+            let prev = &header.prev_block_hash[..];
+            if header.is_genesis() || headers.contains_key(prev) {
+                continue;
+            }
+            let detail = if damaged.contains(prev) {
+                format!("the previous block {} is damaged", utils::hex_string(prev))
+            } else {
+                format!("the previous block {} is missing", utils::hex_string(prev))
+            };
+            faults.push(Fault::new(FaultKind::BrokenLink, &prefixed(HEADER_PREFIX, hash), detail));
+        }
+
+        for entry in self.store.scan_prefix(&[COIN_PREFIX]) {
+            let (key, val) = entry?;
+            if key.len() < 5 || encode::deserialize::<Coin>(&val).is_err() {
+                faults.push(Fault::new(FaultKind::Undecodable, &key, "an unspent output cannot be decoded"));
+            }
+        }
+
+        faults.extend(self.check_indexes(&headers)?);
+        Ok(CheckReport { blocks, faults })
+    }
+
+    /// Checks that the height index runs from the genesis block to the tip
+    /// along stored blocks, and that the UTXO set is at the tip.
+    fn check_indexes(&self, headers: &Headers) -> Result<Vec<Fault>> {
+        let mut faults = Vec::new();
+        let tip = self.read_tip()?;
+        let tip_height = self.read_tip_height().unwrap_or_else(|_| {
+            faults.push(Fault::index("the tip height cannot be decoded"));
+            None
+        });
+        match &tip {
+            None if !headers.is_empty() => faults.push(Fault::index("no tip is recorded")),
+            Some(tip) if !headers.contains_key(tip) => {
+                faults.push(Fault::index(format!("the tip {} is not a stored block", utils::hex_string(tip))))
+            }
+            _ => {}
+        }
+
+        let mut prev: Option<Vec<u8>> = None;
+        let mut expected = 0u64;
+        for entry in self.store.scan_prefix(&[HEIGHT_PREFIX]) {
+            let (key, hash) = entry?;
+            let height = u64::from_be_bytes(key[1..].try_into().map_err(|_| "invalid height key")?);
+            if height != expected {
+                faults.push(Fault::index(format!("the height index skips from {} to {}", expected, height)));
+            }
+            expected = height + 1;
+            let Some(header) = headers.get(&hash) else {
+                faults.push(Fault::index(format!("height {} points to a missing block", height)));
+                prev = None;
+                continue;
+            };
+            let follows = match &prev {
+                Some(prev) => header.prev_block_hash[..] == prev[..],
+                None => height > 0 || header.is_genesis(),
+            };
+            if !follows {
+                faults.push(Fault::index(format!("the block at height {} does not follow the one below", height)));
+            }
+            if !self.has_block_data(&hash)? {
+                faults.push(Fault::index(format!("the block at height {} has no data", height)));
+            } else if !self.is_pruned(&hash)? && self.read(&prefixed(FILTER_PREFIX, &hash))?.is_none() {
+                faults.push(Fault::index(format!("the block at height {} has no filter", height)));
+            }
+            prev = Some(hash);
+        }
+
+        if let Some(tip) = &tip {
+            if prev.as_ref() != Some(tip) || tip_height != expected.checked_sub(1) {
+                faults.push(Fault::index("the height index does not end at the tip"));
+            }
+        }
+        if self.read(UTXO_TIP_KEY)? != tip {
+            faults.push(Fault::index("the UTXO set is not at the tip"));
+        }
+        Ok(faults)
+    }
+
+    /// Deletes what [`BlockchainDb::check`] finds damaged: every record of
+    /// a block with a damaged header or body, and then of the blocks above
+    /// it, and undecodable or orphaned records. The height index, the tip
+    /// and the UTXO set are then rebuilt, leaving the tip at the last valid
+    /// block. Returns a description of each repair.
+    pub fn repair(&mut self) -> Result<Vec<String>> {
+        let mut repairs = Vec::new();
+        let mut rebuild_utxo_set = false;
+        loop {
+            let report = self.check()?;
+            let mut batch = WriteBatch::new();
+            let mut deleted = HashSet::new();
+            for fault in &report.faults {
+                let Some(key) = &fault.key else { continue };
+                let block_damaged = matches!(key[0], HEADER_PREFIX | POS_PREFIX | BODY_PREFIX)
+                    && fault.kind != FaultKind::Orphaned;
+                if block_damaged {
+                    let hash = &key[1..];
+                    if deleted.insert(hash.to_vec()) {
+                        for prefix in [HEADER_PREFIX, POS_PREFIX, BODY_PREFIX, FILTER_PREFIX, UNDO_PREFIX] {
+                            batch.delete(&prefixed(prefix, hash));
+                        }
+                        repairs.push(format!("deleted block {} ({})", utils::hex_string(hash), fault.kind));
+                    }
+                } else if deleted.insert(key.clone()) {
+                    batch.delete(key);
+                    rebuild_utxo_set |= matches!(key[0], UNDO_PREFIX | COIN_PREFIX);
+                    repairs.push(format!("deleted a record ({})", fault));
+                }
+            }
+            if batch.is_empty() {
+                break;
+            }
+            self.write_batch(batch)?;
+        }
+
+        if rebuild_utxo_set {
+            self.delete(UTXO_TIP_KEY)?;
+        }
+        repairs.extend(self.repair_chain()?);
+        repairs.extend(self.repair_utxo_set()?);
+        Ok(repairs)
+    }
 
-    LittleEndian::read_i32(&buffer)
-}
\ No newline at end of file
+    /// Decodes the body of a block stored under `prefix`.
+    fn decode_block_data(&self, prefix: u8, header: &BlockHeader, val: &[u8]) -> Result<Block> {
+        if prefix == POS_PREFIX {
+            let pos: BlockPos = encode::deserialize(val)?;
+            let files = self.files.as_ref().ok_or("block files are not open")?;
+            return Ok(Block::deserialize(&files.read(&pos)?)?);
+        }
+        Ok(Block {
+            header: header.clone(),
+            transactions: encode::deserialize(val)?,
+        })
+    }
+}
+
+/// Adds the writes undoing the UTXO set changes of `block` to `batch`.
+fn undo_block(batch: &mut WriteBatch, block: &Block, undo: &BlockUndo) {
+    for tx in &block.transactions {
+        for vout in 0..tx.vout.len() {
+            batch.delete(&coin_key(&tx.id, vout as u32));
+        }
+    }
+    for spent in &undo.spent {
+        batch.put(&coin_key(&spent.txid, spent.vout), &encode::serialize(&spent.coin));
+    }
+}
+
+/// Makes `hash`, at `height`, the last block of the active chain.
+fn put_tip(batch: &mut WriteBatch, hash: &[u8], height: u64) {
+    batch.put(&height_key(height), hash);
+    batch.put(TIP_KEY, hash);
+    batch.put(TIP_HEIGHT_KEY, &height.to_le_bytes());
+}
+
+fn read_u64(data: Option<Vec<u8>>) -> Result<Option<u64>> {
+    match data {
+        Some(data) => Ok(Some(u64::from_le_bytes(data.try_into().map_err(|_| "invalid number")?))),
+        None => Ok(None),
+    }
+}
+
+fn height_key(height: u64) -> Vec<u8> {
+    prefixed(HEIGHT_PREFIX, &height.to_be_bytes())
+}
+
+fn file_key(file: u32) -> Vec<u8> {
+    prefixed(FILE_PREFIX, &file.to_be_bytes())
+}
+
+fn coin_key(txid: &[u8], vout: u32) -> Vec<u8> {
+    let mut key = prefixed(COIN_PREFIX, txid);
+    key.extend_from_slice(&vout.to_be_bytes());
+    key
+}
+
+fn prefixed(prefix: u8, hash: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(hash.len() + 1);
+    key.push(prefix);
+    key.extend_from_slice(hash);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::network::Network;
+    use crate::proofofwork::Miner;
+    use crate::store::MemoryStore;
+    use crate::{transaction, wallet};
+
+    #[test]
+    fn recovery_repairs_partially_written_blocks() {
+        let params = Network::Regtest.params();
+        let address = wallet::calc_address(0x6f, &[7; 20]);
+        let mut blockchain = Blockchain::in_memory(&params, &address);
+        let hashes = blockchain.generate(3, &address, Vec::new());
+        assert_eq!(blockchain.get_best_height(), 3);
+        let tip = blockchain.tip.clone();
+
+        // a block whose header and tip were written without its body
+        let coinbase = transaction::new_coinbase_tx(&address, b"partial", params.subsidy);
+        let miner = Miner::default();
+        let partial = Block::new_with_miner(vec![coinbase], tip.clone(), params.target_bits, &miner)
+            .unwrap()
+            .unwrap();
+        let mut db = blockchain.db;
+        db.write_header(&partial.header).unwrap();
+        db.write_tip(&partial.hash()).unwrap();
+        // and indexes lost for the blocks below it
+        db.delete(&height_key(3)).unwrap();
+        db.delete(&prefixed(FILTER_PREFIX, &hashes[1])).unwrap();
+
+        let mut blockchain = Blockchain::open_db(db, params).unwrap();
+        assert_eq!(blockchain.tip, tip);
+        assert_eq!(blockchain.get_best_height(), 3);
+        assert_eq!(blockchain.db.read_hash_at(3).unwrap(), Some(tip));
+        assert!(blockchain.db.read_filter(&hashes[1]).unwrap().is_some());
+        assert!(blockchain.db.recover().unwrap().is_empty());
+    }
+
+    #[test]
+    fn block_files_are_pruned_and_utxo_set_survives() {
+        let dir = utils::TempDir::new("prune-test");
+        let params = Network::Regtest.params();
+        let address = wallet::calc_address(0x6f, &[9; 20]);
+        let files = BlockFiles::open(dir.path()).unwrap().max_file_size(4096);
+        let db = BlockchainDb::with_store(MemoryStore::new())
+            .with_block_files(files)
+            .with_prune_target(Some(8192));
+        let mut blockchain = Blockchain::with_db(db, params.clone(), &address);
+        let hashes = blockchain.generate(MIN_BLOCKS_TO_KEEP as usize + 40, &address, Vec::new());
+
+        let db = &blockchain.db;
+        let first_file = db.block_files().unwrap().list().unwrap()[0].0;
+        assert!(first_file > 0, "old block files are deleted");
+        let prune_height = db.read_prune_height().unwrap().unwrap();
+        assert!(prune_height + MIN_BLOCKS_TO_KEEP <= blockchain.get_best_height());
+        assert!(db.is_pruned(&hashes[0]).unwrap());
+        assert!(blockchain.get_block(&hashes[0]).is_none());
+        assert!(blockchain.get_header(&hashes[0]).is_some());
+        let recent = hashes.last().unwrap();
+        assert!(blockchain.get_block(recent).is_some());
+        // every coinbase, pruned or not, is still unspent
+        let coins = db.read_coins().unwrap();
+        assert_eq!(coins.len(), hashes.len() + 1);
+
+        // undo data puts back spent outputs when a block is disconnected
+        let pub_key_hash = &blockchain.get_block(recent).unwrap().transactions[0].vout[0].pub_key_hash;
+        assert_eq!(blockchain.find_utxo(pub_key_hash.clone()).len(), hashes.len() + 1);
+        let block = blockchain.db.disconnect_tip().unwrap();
+        assert_eq!(block.hash(), *recent);
+        assert_eq!(blockchain.db.read_coins().unwrap().len(), hashes.len());
+    }
+
+    #[test]
+    fn databases_of_the_original_format_are_refused() {
+        let dir = utils::TempDir::new("legacy-db-test");
+        let path = dir.path().join("blockchain.db");
+        let mut store = DiskStore::open(Backend::default(), &path).unwrap();
+        store.put(LEGACY_TIP_KEY, &[7; 32]).unwrap();
+        drop(store);
+
+        let err = BlockchainDb::new(&path).err().unwrap().to_string();
+        assert!(err.contains("original format"), "{}", err);
+    }
+
+    #[test]
+    fn invalidated_blocks_leave_the_chain_until_reconsidered() {
+        let params = Network::Regtest.params();
+        let address = wallet::calc_address(0x6f, &[3; 20]);
+        let mut blockchain = Blockchain::in_memory(&params, &address);
+        let hashes = blockchain.generate(3, &address, Vec::new());
+        assert!(blockchain.invalidate_block(&blockchain.db.read_hash_at(0).unwrap().unwrap()).is_err());
+
+        let disconnected = blockchain.invalidate_block(&hashes[1]).unwrap();
+        let disconnected: Vec<_> = disconnected.iter().map(Block::hash).collect();
+        assert_eq!(disconnected, vec![hashes[2].clone(), hashes[1].clone()]);
+        assert_eq!(blockchain.tip, hashes[0]);
+        assert_eq!(blockchain.get_best_height(), 1);
+        assert_eq!(blockchain.db.read_coins().unwrap().len(), 2);
+        assert!(blockchain.db.is_invalid(&hashes[1]).unwrap());
+
+        // a new branch grows from the new tip
+        let other = wallet::calc_address(0x6f, &[4; 20]);
+        let fork = blockchain.generate(1, &other, Vec::new());
+        assert_eq!(blockchain.get_best_height(), 2);
+
+        // the invalidated branch is longer, so reconsidering switches back
+        let connected = blockchain.reconsider_block(&hashes[1]).unwrap();
+        assert_eq!(connected, vec![hashes[1].clone(), hashes[2].clone()]);
+        assert_eq!(blockchain.tip, hashes[2]);
+        assert_eq!(blockchain.get_best_height(), 3);
+        assert_eq!(blockchain.db.read_coins().unwrap().len(), 4);
+        assert!(blockchain.get_coin(&blockchain.get_block(&fork[0]).unwrap().transactions[0].id, 0).is_none());
+        assert!(blockchain.db.recover().unwrap().is_empty());
+    }
+
+    #[test]
+    fn check_reports_damage_and_repair_resets_the_tip() {
+        let params = Network::Regtest.params();
+        let address = wallet::calc_address(0x6f, &[6; 20]);
+        let mut blockchain = Blockchain::in_memory(&params, &address);
+        let hashes = blockchain.generate(4, &address, Vec::new());
+        let mut db = blockchain.db;
+        assert!(db.check().unwrap().faults.is_empty());
+
+        db.write(&prefixed(BODY_PREFIX, &hashes[1]), b"garbage").unwrap();
+        db.write(&prefixed(FILTER_PREFIX, &[9; 32]), b"").unwrap();
+        db.delete(&height_key(1)).unwrap();
+        let report = db.check().unwrap();
+        assert_eq!(report.blocks, 5);
+        let kinds: Vec<FaultKind> = report.faults.iter().map(|fault| fault.kind).collect();
+        assert!(kinds.contains(&FaultKind::Undecodable));
+        assert!(kinds.contains(&FaultKind::Orphaned));
+        assert!(kinds.contains(&FaultKind::Index));
+        let undecodable = report.faults.iter().find(|f| f.kind == FaultKind::Undecodable).unwrap();
+        assert_eq!(undecodable.hash.as_ref(), Some(&hashes[1]));
+
+        db.repair().unwrap();
+        assert!(db.check().unwrap().faults.is_empty());
+        assert_eq!(db.read_tip().unwrap(), Some(hashes[0].clone()));
+        assert_eq!(db.read_tip_height().unwrap(), Some(1));
+        assert!(db.read_header(&hashes[3]).unwrap().is_none());
+        assert_eq!(db.read_coins().unwrap().len(), 2);
+
+        // a coin key too short for an output index is an error, not a panic
+        db.write(&[COIN_PREFIX, 1], b"").unwrap();
+        assert!(db.read_coins().is_err());
+        assert_eq!(db.check().unwrap().faults[0].kind, FaultKind::Undecodable);
+        db.repair().unwrap();
+        assert_eq!(db.read_coins().unwrap().len(), 2);
+    }
+}
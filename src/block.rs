@@ -1,64 +1,246 @@
-use crate::{proofofwork::ProofOfWork, transaction::Transaction, utils};
+use crate::{
+    encode::{self, Decodable, Encodable, Reader},
+    merkle,
+    network::ChainParams,
+    proofofwork::{Miner, ProofOfWork},
+    transaction::Transaction,
+    utils,
+};
 
-use bincode;
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Serialize, Deserialize)]
+pub const BLOCK_VERSION: i32 = 1;
+/// Size of a serialized [`BlockHeader`].
+pub const HEADER_SIZE: usize = 80;
+
+/// The fixed-size part of a block covered by the proof of work.
+///
+/// It serializes to exactly [`HEADER_SIZE`] bytes: the version, previous
+/// block hash, Merkle root, timestamp, bits and nonce, with the integers in
+/// little-endian order. The block hash is the double SHA-256 of those bytes.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub version: i32,
+    pub prev_block_hash: [u8; 32],
+    pub merkle_root: [u8; 32],
+    pub time_stamp: u32,
+    /// Number of leading zero bits the block hash must have.
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    /// serializes the header into its canonical 80 bytes
+    pub fn serialize(&self) -> [u8; HEADER_SIZE] {
+        let mut data = [0u8; HEADER_SIZE];
+        data[0..4].copy_from_slice(&self.version.to_le_bytes());
+        data[4..36].copy_from_slice(&self.prev_block_hash);
+        data[36..68].copy_from_slice(&self.merkle_root);
+        data[68..72].copy_from_slice(&self.time_stamp.to_le_bytes());
+        data[72..76].copy_from_slice(&self.bits.to_le_bytes());
+        data[76..80].copy_from_slice(&self.nonce.to_le_bytes());
+        data
+    }
+
+    /// deserializes a header from its canonical 80 bytes
+    pub fn deserialize(data: &[u8]) -> Option<BlockHeader> {
+        if data.len() != HEADER_SIZE {
+            return None;
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        Some(BlockHeader {
+            version: u32_at(0) as i32,
+            prev_block_hash: data[4..36].try_into().unwrap(),
+            merkle_root: data[36..68].try_into().unwrap(),
+            time_stamp: u32_at(68),
+            bits: u32_at(72),
+            nonce: u32_at(76),
+        })
+    }
+
+    /// computes the hash of the header
+    pub fn hash(&self) -> Vec<u8> {
+        utils::sha256d(&self.serialize())
+    }
+
+    /// Returns true for the genesis block, which has no parent.
+    pub fn is_genesis(&self) -> bool {
+        self.prev_block_hash == [0u8; 32]
+    }
+
+    /// checks that the header hash meets the target given by its bits
+    pub fn validate_pow(&self) -> bool {
+        ProofOfWork::new(self).validate()
+    }
+}
+
+impl Encodable for BlockHeader {
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.serialize());
+    }
+}
+
+impl Decodable for BlockHeader {
+    fn consensus_decode(reader: &mut Reader<'_>) -> encode::Result<Self> {
+        let header = BlockHeader::deserialize(reader.read_bytes(HEADER_SIZE)?).unwrap();
+        if header.version != BLOCK_VERSION {
+            return Err(encode::Error::UnsupportedVersion(header.version));
+        }
+        Ok(header)
+    }
+}
+
+/// A block is its 80-byte header followed by the transactions.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Block {
-    pub time_stamp: i64,
+    pub header: BlockHeader,
     pub transactions: Vec<Transaction>,
-    pub prev_block_hash: Vec<u8>,
-    pub hash: Vec<u8>,
-    pub nonce: u32,
+}
+
+impl Encodable for Block {
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+        self.header.consensus_encode(out);
+        self.transactions.consensus_encode(out);
+    }
+}
+
+impl Decodable for Block {
+    fn consensus_decode(reader: &mut Reader<'_>) -> encode::Result<Self> {
+        Ok(Block {
+            header: BlockHeader::consensus_decode(reader)?,
+            transactions: Vec::consensus_decode(reader)?,
+        })
+    }
 }
 
 impl Block {
-    pub fn new(transactions: Vec<Transaction>, prev_block_hash: Vec<u8>) -> Block {
-        let mut block = Block {
-            time_stamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as i64,
-            transactions,
-            prev_block_hash,
-            hash: Vec::new(),
+    /// Mines a block stamped with [`utils::get_time`], which honours the mock time.
+    pub fn new(transactions: Vec<Transaction>, prev_block_hash: Vec<u8>, target_bits: u16) -> Result<Block, String> {
+        Block::new_with_time(utils::get_time(), transactions, prev_block_hash, target_bits)
+    }
+
+    /// Mines a block carrying the given timestamp.
+    pub fn new_with_time(
+        time_stamp: i64,
+        transactions: Vec<Transaction>,
+        prev_block_hash: Vec<u8>,
+        target_bits: u16,
+    ) -> Result<Block, String> {
+        let mut block = Block::unmined(time_stamp, transactions, &prev_block_hash, target_bits)?;
+        block.mine(&Miner::default());
+        Ok(block)
+    }
+
+    /// Mines a block with `miner`, returning `None` if the search is cancelled.
+    pub fn new_with_miner(
+        transactions: Vec<Transaction>,
+        prev_block_hash: Vec<u8>,
+        target_bits: u16,
+        miner: &Miner,
+    ) -> Result<Option<Block>, String> {
+        let mut block =
+            Block::unmined(utils::get_time(), transactions, &prev_block_hash, target_bits)?;
+        if block.mine(miner) {
+            Ok(Some(block))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Builds a block with a zero nonce. An empty `prev_block_hash` denotes
+    /// the genesis block. Fails if the hash is not 32 bytes long or the
+    /// timestamp does not fit the header.
+    fn unmined(
+        time_stamp: i64,
+        transactions: Vec<Transaction>,
+        prev_block_hash: &[u8],
+        target_bits: u16,
+    ) -> Result<Block, String> {
+        let prev = if prev_block_hash.is_empty() {
+            [0u8; 32]
+        } else {
+            prev_block_hash.try_into().map_err(|_| {
+                format!("previous block hash has {} bytes, expected 32", prev_block_hash.len())
+            })?
+        };
+        let time_stamp = u32::try_from(time_stamp)
+            .map_err(|_| format!("timestamp {} does not fit in a block header", time_stamp))?;
+        let header = BlockHeader {
+            version: BLOCK_VERSION,
+            prev_block_hash: prev,
+            merkle_root: Block::compute_merkle_root(&transactions),
+            time_stamp,
+            bits: target_bits as u32,
             nonce: 0,
         };
+        Ok(Block {
+            header,
+            transactions,
+        })
+    }
 
-        let pow = ProofOfWork::new(&block);
-        let (nonce, hash) = pow.run();
-        block.nonce = nonce;
-        block.hash = hash;
-        println!("hash: {}", utils::hex_string(&block.hash));
-        block
+    /// Searches for the proof of work and stores the nonce. The timestamp
+    /// moves forward if every nonce fails.
+    fn mine(&mut self, miner: &Miner) -> bool {
+        let (solution, stats) = miner.mine(&ProofOfWork::new(&self.header));
+        let Some(solution) = solution else {
+            return false;
+        };
+        self.header.time_stamp = solution.time_stamp;
+        self.header.nonce = solution.nonce;
+        utils::log(&format!(
+            "hash: {} ({} hashes, {:.0} H/s)",
+            utils::hex_string(&solution.hash),
+            stats.hashes,
+            stats.hashes_per_second()
+        ));
+        true
     }
 
-    /// deserializes a block from a byte array
-    pub fn deserialize(data: &[u8]) -> Block {
-        bincode::deserialize(data).expect("Failed to deserialize block")
+    /// deserializes a block from its canonical encoding
+    pub fn deserialize(data: &[u8]) -> encode::Result<Block> {
+        encode::deserialize(data)
     }
 
-    /// serializes a block into a byte array
+    /// serializes a block into its canonical encoding
     pub fn serialize(&self) -> Vec<u8> {
-        bincode::serialize(self).expect("Failed to serialize block")
+        encode::serialize(self)
     }
 
-    /// generates a new genesis block
-    pub fn new_genesis_block(coinbase: Vec<Transaction>) -> Block {
-        Block::new(coinbase, vec![])
+    /// generates the genesis block of a network
+    pub fn new_genesis_block(coinbase: Vec<Transaction>, params: &ChainParams) -> Result<Block, String> {
+        Block::new_with_time(params.genesis_time_stamp, coinbase, vec![], params.target_bits)
     }
 
-    /// computes the hash of the block
-    pub fn hash_transaction(&self) -> Vec<u8> {
-        let mut tx_hashes = Vec::new();
+    /// computes the Merkle root of the transaction ids
+    pub fn compute_merkle_root(transactions: &[Transaction]) -> [u8; 32] {
+        let ids: Vec<Vec<u8>> = transactions.iter().map(|tx| tx.id.clone()).collect();
+        merkle::merkle_root(&ids)
+    }
 
-        for tx in &self.transactions.clone() {
-            let mut tx_hash = tx.id.clone();
-            tx_hashes.append(&mut tx_hash);
+    /// Checks what can be checked without the chain: the difficulty of
+    /// `params`, the proof of work, that a coinbase comes first if there is
+    /// one, and the Merkle root.
+    pub fn check(&self, params: &ChainParams) -> Result<(), String> {
+        if self.header.bits != params.target_bits as u32 {
+            return Err(format!("block has difficulty {}, expected {}", self.header.bits, params.target_bits));
+        }
+        if !self.header.validate_pow() {
+            return Err("block hash does not meet its target".to_string());
         }
+        // blocks mined by `send` have no coinbase
+        if self.transactions.iter().skip(1).any(|tx| tx.is_coinbase()) {
+            return Err("only the first transaction of a block may be a coinbase".to_string());
+        }
+        if self.header.merkle_root != Block::compute_merkle_root(&self.transactions) {
+            return Err("block Merkle root does not match its transactions".to_string());
+        }
+        Ok(())
+    }
 
-        utils::compute_sha256(&tx_hashes)
+    /// computes the hash of the block, which is the hash of its header
+    pub fn hash(&self) -> Vec<u8> {
+        self.header.hash()
     }
 
     pub fn get_transactions(&self) -> Vec<Transaction> {
@@ -66,10 +248,90 @@ impl Block {
     }
 
     pub fn get_pre_block_hash(&self) -> String {
-        self.prev_block_hash.clone().into_iter().map(|x| format!("{:02x}", x)).collect()
+        utils::hex_string(&self.header.prev_block_hash)
     }
 
     pub fn get_hash(&self) -> String {
-        self.hash.clone().into_iter().map(|x| format!("{:02x}", x)).collect()
+        utils::hex_string(&self.hash())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{transaction, wallet};
+
+    fn mine_at(time_stamp: i64, prev_block_hash: Vec<u8>) -> Block {
+        let address = wallet::calc_address(0x6f, &[7u8; 20]);
+        let coinbase = transaction::new_coinbase_tx(&address, b"test", 10);
+        utils::set_mock_time(Some(time_stamp));
+        let block = Block::new(vec![coinbase], prev_block_hash, 1).unwrap();
+        utils::set_mock_time(None);
+        block
+    }
+
+    #[test]
+    fn mock_time_gives_deterministic_blocks() {
+        let genesis = mine_at(1_700_000_000, vec![]);
+        let next = mine_at(1_700_000_600, genesis.hash());
+
+        assert_eq!(genesis.header.time_stamp, 1_700_000_000);
+        assert_eq!(genesis.get_hash(), "139024a55edaf2729fd3a50a62c4f98e833d9998cbfeeee72da7557deb819ecc");
+        assert_eq!(next.get_hash(), "3b6860a10f189d1d744faa9004833770d62e4e566b96263cd10bde1d29bfbca6");
+        assert_eq!(mine_at(1_700_000_000, vec![]).hash(), genesis.hash());
+
+        assert!(Block::new_with_time(1_700_000_000, vec![], vec![1; 31], 1).is_err());
+        assert!(Block::new_with_time(1 << 32, vec![], vec![], 1).is_err());
+        assert!(Block::new_with_time(-1, vec![], vec![], 1).is_err());
+    }
+
+    #[test]
+    fn block_encoding_matches_published_vector() {
+        // vector from docs/serialization.md
+        let genesis = mine_at(1_700_000_000, vec![]);
+        let expected = concat!(
+            "01000000",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "10005e3af3b298b82a402ee410d38ec4e9068f3156a479d6ace8cd53a8bd71b7",
+            "00f15365",
+            "01000000",
+            "03000000",
+            "01",
+            "01000000010000000000000474657374010a00000000000000",
+            "140707070707070707070707070707070707070707",
+        );
+        let bytes = genesis.serialize();
+        assert_eq!(hex::encode(&bytes), expected);
+
+        let decoded = Block::deserialize(&bytes).unwrap();
+        assert_eq!(decoded.header, genesis.header);
+        assert_eq!(decoded.transactions[0].id, genesis.transactions[0].id);
+        assert_eq!(decoded.serialize(), bytes);
+        assert!(Block::deserialize(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut unknown_version = bytes.clone();
+        unknown_version[0] = 2;
+        assert_eq!(
+            Block::deserialize(&unknown_version).err(),
+            Some(encode::Error::UnsupportedVersion(2))
+        );
+    }
+
+    #[test]
+    fn header_serialization_round_trips() {
+        let header = BlockHeader {
+            version: BLOCK_VERSION,
+            prev_block_hash: [1; 32],
+            merkle_root: [2; 32],
+            time_stamp: 1_700_000_000,
+            bits: 16,
+            nonce: 0xdead_beef,
+        };
+        let bytes = header.serialize();
+
+        assert_eq!(bytes.len(), HEADER_SIZE);
+        assert_eq!(&bytes[76..], &[0xef, 0xbe, 0xad, 0xde]);
+        assert_eq!(BlockHeader::deserialize(&bytes), Some(header));
+        assert_eq!(BlockHeader::deserialize(&bytes[1..]), None);
     }
 }
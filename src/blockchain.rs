@@ -1,66 +1,292 @@
 use crate::{
-    bcdb::BlockchainDb,
-    block::Block,
+    bcdb::{self, BlockchainDb},
+    block::{Block, BlockHeader},
+    chainstate::Coin,
+    config::Config,
+    filter::BlockFilter,
+    network::ChainParams,
+    proofofwork::Miner,
+    store::{ChainStore, DiskStore, MemoryStore},
     transaction::{self, TXOutput, Transaction},
     utils, wallet,
 };
 
 use data_encoding::HEXLOWER;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-const DB_FILE: &str = "blockchain.db";
+/// An unspent transaction output together with its outpoint.
+#[derive(Clone, Debug)]
+pub struct Utxo {
+    pub txid: Vec<u8>,
+    pub vout: usize,
+    pub output: TXOutput,
+}
 
-pub struct Blockchain {
+pub struct Blockchain<S: ChainStore = DiskStore> {
     pub tip: Vec<u8>, // hash of the last block
-    pub db: BlockchainDb,
+    pub db: BlockchainDb<S>,
+    pub params: ChainParams,
+    /// Miner used by [`Blockchain::mine_block`]; its cancel handle aborts mining.
+    pub miner: Miner,
 }
 
 impl Blockchain {
-    pub fn new(address: &str) -> Self {
-        let mut db = BlockchainDb::new(DB_FILE).expect("Failed to initialize the database");
+    pub fn new(config: &Config, address: &str) -> Self {
+        let db = BlockchainDb::from_config(config).expect("Failed to initialize the database");
+        Blockchain::with_db(db, config.params().clone(), address)
+    }
 
-        let tip = if let Some(last_hash) = db.read(b"1").unwrap() {
+    /// Opens an existing chain, returning `None` if there is none.
+    pub fn open(config: &Config) -> Option<Self> {
+        if !config.db_path().exists() {
+            return None;
+        }
+        let db = BlockchainDb::from_config(config).ok()?;
+        Blockchain::open_db(db, config.params().clone())
+    }
+}
+
+impl Blockchain<MemoryStore> {
+    /// Creates a chain that is kept in memory only.
+    pub fn in_memory(params: &ChainParams, address: &str) -> Self {
+        Blockchain::with_db(BlockchainDb::with_store(MemoryStore::new()), params.clone(), address)
+    }
+}
+
+impl<S: ChainStore> Blockchain<S> {
+    /// Uses the chain in `db`, creating its genesis block paying `address`
+    /// if it is empty.
+    pub fn with_db(mut db: BlockchainDb<S>, params: ChainParams, address: &str) -> Self {
+        recover(&mut db).expect("Failed to recover the database");
+
+        let tip = if let Some(last_hash) = db.read_tip().unwrap() {
             last_hash
-        } else if address == "" {
+        } else if address.is_empty() {
             panic!("Please create blockchain first");
         } else {
-            println!("No existing blockchain found. Creating a new one...");
-            let coinbase = transaction::new_coinbase_tx(address);
-            let genesis_block = Block::new_genesis_block(vec![coinbase]);
-            db.write(&genesis_block.hash, &genesis_block.serialize())
-                .unwrap();
-            db.write(b"1", &genesis_block.hash).unwrap();
-            genesis_block.hash
+            utils::log("No existing blockchain found. Creating a new one...");
+            let coinbase = transaction::new_coinbase_tx(
+                address,
+                params.genesis_message.as_bytes(),
+                params.subsidy,
+            );
+            let genesis_block = Block::new_genesis_block(vec![coinbase], &params)
+                .expect("Failed to create the genesis block");
+            db.connect_block(&genesis_block, 0).unwrap();
+            genesis_block.hash()
         };
 
-        Blockchain { tip, db }
+        Blockchain {
+            tip,
+            db,
+            params,
+            miner: Miner::default(),
+        }
+    }
+
+    /// Uses the chain in `db`, returning `None` if it is empty.
+    pub fn open_db(mut db: BlockchainDb<S>, params: ChainParams) -> Option<Self> {
+        recover(&mut db).ok()?;
+        let tip = db.read_tip().ok()??;
+        Some(Blockchain {
+            tip,
+            db,
+            params,
+            miner: Miner::default(),
+        })
     }
 
-    pub fn mine_block(&mut self, transactions: Vec<Transaction>) {
+    /// Mines a block on top of the tip and returns its hash, or `None` if
+    /// mining was cancelled or the block could not be stored.
+    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Option<Vec<u8>> {
         // Verify each transaction, logging an error for any invalid transaction.
         for tx in &transactions {
-            if tx.verify(self) == false {
+            if !tx.verify(self) {
                 panic!("ERROR: Invalid transaction");
             }
         }
 
         let last_hash = self.tip.clone();
         // Create a new block with the provided transactions and the last hash.
-        let new_block = Block::new(transactions, last_hash);
+        let new_block =
+            match Block::new_with_miner(transactions, last_hash, self.params.target_bits, &self.miner) {
+                Ok(block) => block?,
+                Err(e) => {
+                    utils::log(&format!("Failed to build block: {}", e));
+                    return None;
+                }
+            };
 
-        // Attempt to write the new block to the database, logging any errors.
-        if let Err(_) = self.db.write(&new_block.hash, &new_block.serialize()) {
-            println!("Failed to write block");
-            return;
+        // Store the block and move the tip in one write, logging any errors.
+        let height = self.get_best_height() + 1;
+        if self.db.connect_block(&new_block, height).is_err() {
+            utils::log("Failed to write block");
+            return None;
         }
 
-        // Update the tip of the blockchain, logging any errors.
-        if let Err(_) = self.db.write(b"1", &new_block.hash) {
-            println!("Failed to update last hash");
-            return;
+        let hash = new_block.hash();
+        match self.db.prune() {
+            Ok(files) => {
+                for file in files {
+                    utils::log(&format!("Pruned block file {}", file));
+                }
+            }
+            Err(e) => utils::log(&format!("Failed to prune block files: {}", e)),
         }
 
-        self.tip = new_block.hash;
+        self.tip = hash.clone();
+        Some(hash)
+    }
+
+    /// Validates a block that was not mined here and connects it on top
+    /// of the tip. Every transaction must spend unspent outputs, each at
+    /// most once, worth at least what it pays, and the coinbase may claim
+    /// no more than the subsidy and the fees.
+    pub fn accept_block(&mut self, block: &Block) -> bcdb::Result<()> {
+        if block.header.prev_block_hash[..] != self.tip[..] {
+            return Err("block does not extend the tip".into());
+        }
+        block.check(&self.params)?;
+        let mut spent = HashSet::new();
+        // outputs created in this block, which later transactions may spend
+        let mut created: HashMap<(&[u8], usize), &TXOutput> = HashMap::new();
+        let mut fees = 0i64;
+        for tx in &block.transactions {
+            if !tx.is_coinbase() {
+                let txid = utils::hex_string(&tx.id);
+                let mut input_value = 0i64;
+                for (idx, input) in tx.vin.iter().enumerate() {
+                    let outpoint = (input.txid.as_slice(), input.vout);
+                    if !spent.insert(outpoint) {
+                        return Err(format!("transaction {} spends an output spent earlier in the block", txid).into());
+                    }
+                    let prev_output = match created.get(&outpoint) {
+                        Some(&output) => output.clone(),
+                        None => match self.get_coin(&input.txid, input.vout) {
+                            Some(coin) => coin.output,
+                            None => return Err(format!("transaction {} is invalid", txid).into()),
+                        },
+                    };
+                    if !tx.verify_input(idx, &prev_output) {
+                        return Err(format!("transaction {} is invalid", txid).into());
+                    }
+                    input_value += prev_output.value as i64;
+                }
+                let output_value =
+                    output_value(tx).ok_or_else(|| format!("transaction {} has a negative output", txid))?;
+                if output_value > input_value {
+                    return Err(format!(
+                        "transaction {} pays {} but its inputs are worth {}",
+                        txid, output_value, input_value
+                    )
+                    .into());
+                }
+                fees += input_value - output_value;
+            }
+            for (vout, output) in tx.vout.iter().enumerate() {
+                created.insert((tx.id.as_slice(), vout), output);
+            }
+        }
+        if let Some(coinbase) = block.transactions.first().filter(|tx| tx.is_coinbase()) {
+            let reward = output_value(coinbase).ok_or("the coinbase has a negative output")?;
+            let allowed = self.params.subsidy as i64 + fees;
+            if reward > allowed {
+                return Err(format!("the coinbase pays {} but the subsidy and fees are {}", reward, allowed).into());
+            }
+        }
+        let height = self.get_best_height() + 1;
+        self.db.connect_block(block, height)?;
+        self.tip = block.hash();
+        Ok(())
+    }
+
+    /// Disconnects `hash` and the blocks above it if it is in the active
+    /// chain, and keeps it out of the chain until it is reconsidered.
+    /// Returns the disconnected blocks, tip first.
+    pub fn invalidate_block(&mut self, hash: &[u8]) -> bcdb::Result<Vec<Block>> {
+        let disconnected = self.db.invalidate_block(hash);
+        self.tip = self.db.read_tip()?.ok_or("there is no chain")?;
+        disconnected
+    }
+
+    /// Lets `hash` and its descendants back into the chain, switching to
+    /// them if they make a longer chain. Returns the connected hashes.
+    pub fn reconsider_block(&mut self, hash: &[u8]) -> bcdb::Result<Vec<Vec<u8>>> {
+        let connected = self.db.reconsider_block(hash);
+        self.tip = self.db.read_tip()?.ok_or("there is no chain")?;
+        connected
+    }
+
+    /// Mines `count` blocks, each with a coinbase paying `address`, and
+    /// returns their hashes. The first block also holds `transactions`.
+    pub fn generate(
+        &mut self,
+        count: usize,
+        address: &str,
+        mut transactions: Vec<Transaction>,
+    ) -> Vec<Vec<u8>> {
+        let mut hashes = Vec::with_capacity(count);
+        for _ in 0..count {
+            // Committing to the height keeps coinbases to the same address distinct.
+            let height = self.get_best_height() + 1;
+            let coinbase =
+                transaction::new_coinbase_tx(address, &height.to_le_bytes(), self.params.subsidy);
+            transactions.insert(0, coinbase);
+            match self.mine_block(std::mem::take(&mut transactions)) {
+                Some(hash) => hashes.push(hash),
+                None => break,
+            }
+        }
+        hashes
+    }
+
+    /// Returns the height of the tip; the genesis block has height 0.
+    pub fn get_best_height(&self) -> u64 {
+        match self.db.read_tip_height() {
+            Ok(Some(height)) => height,
+            _ => self.header_iterator().count() as u64 - 1,
+        }
+    }
+
+    /// Returns an iterator walking the chain from the tip back to the genesis block.
+    pub fn iterator(&self) -> BlockchainIterator<'_, S> {
+        BlockchainIterator {
+            prev_block_hash: self.tip.clone(),
+            db: &self.db,
+        }
+    }
+
+    /// Returns an iterator over the headers from the tip back to the genesis
+    /// block, which never reads block bodies.
+    pub fn header_iterator(&self) -> HeaderIterator<'_, S> {
+        HeaderIterator {
+            prev_block_hash: self.tip.clone(),
+            db: &self.db,
+        }
+    }
+
+    pub fn get_header(&self, hash: &[u8]) -> Option<BlockHeader> {
+        self.db.read_header(hash).unwrap()
+    }
+
+    pub fn get_block(&self, hash: &[u8]) -> Option<Block> {
+        self.db.read_block(hash).unwrap()
+    }
+
+    /// Returns the output `vout` of `txid` if it is unspent.
+    pub fn get_coin(&self, txid: &[u8], vout: usize) -> Option<Coin> {
+        self.db.read_coin(txid, u32::try_from(vout).ok()?).unwrap()
+    }
+
+    pub fn get_block_filter(&self, hash: &[u8]) -> Option<BlockFilter> {
+        self.db.read_filter(hash).unwrap()
+    }
+
+    /// Returns false only if the filter of the block proves that none of
+    /// `items` is in it. Blocks without a filter may contain anything.
+    pub fn block_may_contain(&self, hash: &[u8], items: &[Vec<u8>]) -> bool {
+        self.get_block_filter(hash)
+            .is_none_or(|filter| filter.match_any(hash, items))
     }
 
     pub fn find_spendable_outputs(
@@ -68,54 +294,60 @@ impl Blockchain {
         pub_key_hash: &[u8],
         amount: i32,
     ) -> (i32, HashMap<String, Vec<usize>>) {
-        let unspent_transaction = self.find_unspent_transactions(pub_key_hash);
-
         let mut accumulated: i32 = 0;
         let mut unspent_outputs: HashMap<String, Vec<usize>> = HashMap::new();
 
-        'outer: for tx in &unspent_transaction {
-            let txid_hex = HEXLOWER.encode(&tx.get_id());
-            for idx in 0..tx.get_vout().len() {
-                let txout = tx.get_vout()[idx].clone();
-                if txout.is_locked_with_key(pub_key_hash) {
-                    accumulated += txout.get_value();
-                    if unspent_outputs.contains_key(txid_hex.as_str()) {
-                        unspent_outputs
-                            .get_mut(txid_hex.as_str())
-                            .unwrap()
-                            .push(idx);
-                    } else {
-                        unspent_outputs.insert(txid_hex.clone(), vec![idx]);
-                    }
-                    if accumulated >= amount {
-                        break 'outer;
-                    }
-                }
+        for utxo in self.find_unspent_outputs(pub_key_hash) {
+            if accumulated >= amount {
+                break;
             }
+            accumulated += utxo.output.value;
+            unspent_outputs
+                .entry(HEXLOWER.encode(&utxo.txid))
+                .or_default()
+                .push(utxo.vout);
         }
 
         (accumulated, unspent_outputs)
     }
 
-    /// Finds all unspent transaction outputs and returns transactions with spent outputs removed.
-    /// 1. Some outputs are not tied to an input, such as coinbase mining rewards.
-    /// 2. The input of a transaction can refer to the output of multiple previous transactions.
-    /// 3. An input must reference an output.
+    /// Returns the outputs locked to `pub_key_hash` that no transaction in
+    /// the chain spends, newest first.
+    pub fn find_unspent_outputs(&self, pub_key_hash: &[u8]) -> Vec<Utxo> {
+        let mut coins: Vec<(Vec<u8>, u32, Coin)> = self
+            .db
+            .read_coins()
+            .unwrap()
+            .into_iter()
+            .filter(|(_, _, coin)| coin.output.is_locked_with_key(pub_key_hash))
+            .collect();
+        coins.sort_by(|a, b| b.2.height.cmp(&a.2.height).then_with(|| (&a.0, a.1).cmp(&(&b.0, b.1))));
+        coins
+            .into_iter()
+            .map(|(txid, vout, coin)| Utxo {
+                txid,
+                vout: vout as usize,
+                output: coin.output,
+            })
+            .collect()
+    }
+
     pub fn find_unspent_transactions(&self, pub_key_hash: &[u8]) -> Vec<Transaction> {
         let mut unspent_txs = Vec::new();
         let mut spent_txos: HashMap<String, Vec<usize>> = HashMap::new();
-        let mut blockchain_iterator = BlockchainIterator {
-            prev_block_hash: self.tip.clone(),
-            db: &self.db,
-        };
+        let wanted = [pub_key_hash.to_vec()];
 
-        loop {
-            let block = blockchain_iterator.next();
-            if block.is_none() {
-                break;
+        for header in self.header_iterator() {
+            // skip the body of blocks whose filter rules out the key
+            let hash = header.hash();
+            if !self.block_may_contain(&hash, &wanted) {
+                continue;
             }
+            let Some(block) = self.get_block(&hash) else {
+                continue;
+            };
 
-            for tx in block.unwrap().get_transactions() {
+            for tx in block.get_transactions() {
                 let txid_hex = HEXLOWER.encode(&tx.get_id());
                 let txout = tx.get_vout();
                 'outer: for idx in 0..txout.len() {
@@ -157,25 +389,14 @@ impl Blockchain {
     }
 
     pub fn find_utxo(&self, pub_key_hash: Vec<u8>) -> Vec<TXOutput> {
-        let mut utxo = Vec::new();
-        let unspent_txs = self.find_unspent_transactions(&pub_key_hash);
-
-        for tx in unspent_txs {
-            for out in tx.get_vout() {
-                if out.is_locked_with_key(&pub_key_hash) {
-                    utxo.push(out);
-                }
-            }
-        }
-
-        utxo
+        self.find_unspent_outputs(&pub_key_hash)
+            .into_iter()
+            .map(|utxo| utxo.output)
+            .collect()
     }
 
     pub fn find_transaction(&self, txid: &[u8]) -> Option<Transaction> {
-        let mut blockchain_iterator = BlockchainIterator {
-            prev_block_hash: self.tip.clone(),
-            db: &self.db,
-        };
+        let mut blockchain_iterator = self.iterator();
 
         loop {
             let option = blockchain_iterator.next();
@@ -193,10 +414,7 @@ impl Blockchain {
     }
 
     pub fn print_chain(&self) {
-        let mut blockchain_iterator = BlockchainIterator {
-            prev_block_hash: self.tip.clone(),
-            db: &self.db,
-        };
+        let mut blockchain_iterator = self.iterator();
 
         loop {
             let option = blockchain_iterator.next();
@@ -210,7 +428,7 @@ impl Blockchain {
                 for input in tx.get_vin() {
                     let txid_hex = HEXLOWER.encode(&input.get_txid());
                     let pub_key_hash = utils::hash_pub_key(&input.get_pub_key());
-                    let address = wallet::calc_address(&pub_key_hash);
+                    let address = wallet::calc_address(self.params.address_version, &pub_key_hash);
                     println!(
                         "Transaction input txid = {}, vout = {}, from = {}",
                         txid_hex,
@@ -221,7 +439,7 @@ impl Blockchain {
                 let cur_txid_hex = HEXLOWER.encode(&tx.get_id());
                 for output in tx.get_vout() {
                     let pub_key_hash = output.get_pub_key_hash();
-                    let address = wallet::calc_address(&pub_key_hash);
+                    let address = wallet::calc_address(self.params.address_version, &pub_key_hash);
                     println!(
                         "Transaction output current txid = {}, value = {}, to = {}",
                         cur_txid_hex,
@@ -235,19 +453,68 @@ impl Blockchain {
     }
 }
 
-pub struct BlockchainIterator<'a> {
+/// Walks the blocks back to the genesis block. It panics on a block that
+/// cannot be read; [`BlockchainDb::check`] finds out why.
+pub struct BlockchainIterator<'a, S: ChainStore = DiskStore> {
     prev_block_hash: Vec<u8>,
-    db: &'a BlockchainDb,
+    db: &'a BlockchainDb<S>,
 }
 
-impl<'a> BlockchainIterator<'a> {
-    pub fn next(&mut self) -> Option<Block> {
-        if let Some(encoded_block) = self.db.read(&self.prev_block_hash).unwrap() {
-            let block = Block::deserialize(&encoded_block);
-            self.prev_block_hash = block.prev_block_hash.clone();
-            Some(block)
-        } else {
-            None
-        }
+impl<S: ChainStore> Iterator for BlockchainIterator<'_, S> {
+    type Item = Block;
+
+    fn next(&mut self) -> Option<Block> {
+        let block = self
+            .db
+            .read_block(&self.prev_block_hash)
+            .unwrap_or_else(|e| panic!("{}", read_error("block", &self.prev_block_hash, e)))?;
+        self.prev_block_hash = block.header.prev_block_hash.to_vec();
+        Some(block)
     }
 }
+
+/// Walks the headers back to the genesis block, panicking like
+/// [`BlockchainIterator`] on one that cannot be read.
+pub struct HeaderIterator<'a, S: ChainStore = DiskStore> {
+    prev_block_hash: Vec<u8>,
+    db: &'a BlockchainDb<S>,
+}
+
+impl<S: ChainStore> Iterator for HeaderIterator<'_, S> {
+    type Item = BlockHeader;
+
+    fn next(&mut self) -> Option<BlockHeader> {
+        let header = self
+            .db
+            .read_header(&self.prev_block_hash)
+            .unwrap_or_else(|e| panic!("{}", read_error("header", &self.prev_block_hash, e)))?;
+        self.prev_block_hash = header.prev_block_hash.to_vec();
+        Some(header)
+    }
+}
+
+fn read_error(what: &str, hash: &[u8], e: Box<dyn std::error::Error>) -> String {
+    format!(
+        "Failed to read {} {}: {}. Run `checkdb` to find damaged records and `checkdb --repair` to remove them.",
+        what,
+        utils::hex_string(hash),
+        e
+    )
+}
+
+/// Repairs a partially written database, logging what was fixed.
+fn recover<S: ChainStore>(db: &mut BlockchainDb<S>) -> bcdb::Result<()> {
+    let repairs = db.recover()?;
+    for repair in &repairs {
+        utils::log(&format!("Database recovery: {}", repair));
+    }
+    Ok(())
+}
+
+/// Sums the outputs of `tx`, or returns `None` if one of them is negative.
+fn output_value(tx: &Transaction) -> Option<i64> {
+    tx.vout
+        .iter()
+        .map(|output| (output.value >= 0).then_some(output.value as i64))
+        .sum()
+}
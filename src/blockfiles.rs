@@ -0,0 +1,153 @@
+//! Block bodies appended to flat `blkNNNNN.dat` files.
+//!
+//! Each record is the magic bytes, the length of the block as a
+//! little-endian `u32` and the block in its canonical encoding. The database
+//! keeps a [`BlockPos`] per block; a record written by a block whose
+//! database batch never committed is simply never referenced. A new file is
+//! started once the current one reaches the size limit, so old blocks can
+//! be pruned a whole file at a time.
+
+use crate::encode::{self, Decodable, Encodable, Reader};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Marks the start of every record.
+const RECORD_MAGIC: [u8; 4] = *b"BRSB";
+/// Size after which a new block file is started.
+pub const MAX_BLOCK_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Where a block is stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockPos {
+    pub file: u32,
+    /// Offset of the encoded block, after the record header.
+    pub offset: u64,
+    pub len: u32,
+}
+
+impl Encodable for BlockPos {
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+        self.file.consensus_encode(out);
+        self.offset.consensus_encode(out);
+        self.len.consensus_encode(out);
+    }
+}
+
+impl Decodable for BlockPos {
+    fn consensus_decode(reader: &mut Reader<'_>) -> encode::Result<Self> {
+        Ok(BlockPos {
+            file: u32::consensus_decode(reader)?,
+            offset: u64::consensus_decode(reader)?,
+            len: u32::consensus_decode(reader)?,
+        })
+    }
+}
+
+/// The block files in a directory.
+pub struct BlockFiles {
+    dir: PathBuf,
+    max_file_size: u64,
+    /// Number of the file new blocks are appended to.
+    current: u32,
+}
+
+impl BlockFiles {
+    /// Opens the block files in `dir`, creating the directory if needed.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut files = BlockFiles {
+            dir: dir.to_path_buf(),
+            max_file_size: MAX_BLOCK_FILE_SIZE,
+            current: 0,
+        };
+        files.current = files.list()?.last().map_or(0, |&(file, _)| file);
+        Ok(files)
+    }
+
+    /// Sets the size after which a new file is started.
+    pub fn max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size.max(1);
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Number of the file new blocks are appended to.
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    pub fn path(&self, file: u32) -> PathBuf {
+        self.dir.join(format!("blk{:05}.dat", file))
+    }
+
+    /// Appends an encoded block and waits until it is on disk.
+    pub fn append(&mut self, data: &[u8]) -> io::Result<BlockPos> {
+        let len = u32::try_from(data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "block is too large"))?;
+        let size = fs::metadata(self.path(self.current)).map_or(0, |meta| meta.len());
+        if size > 0 && size + data.len() as u64 > self.max_file_size {
+            self.current += 1;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(self.current))?;
+        let offset = file.seek(SeekFrom::End(0))? + 8;
+        let mut record = Vec::with_capacity(data.len() + 8);
+        record.extend_from_slice(&RECORD_MAGIC);
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(data);
+        file.write_all(&record)?;
+        file.sync_data()?;
+        Ok(BlockPos {
+            file: self.current,
+            offset,
+            len,
+        })
+    }
+
+    /// Reads the encoded block at `pos`.
+    pub fn read(&self, pos: &BlockPos) -> io::Result<Vec<u8>> {
+        let mut file = File::open(self.path(pos.file))?;
+        file.seek(SeekFrom::Start(pos.offset - 8))?;
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+        if header[..4] != RECORD_MAGIC || header[4..] != pos.len.to_le_bytes() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no block record at this position"));
+        }
+        let mut data = vec![0u8; pos.len as usize];
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Returns the number and size of each block file, in order.
+    pub fn list(&self) -> io::Result<Vec<(u32, u64)>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(file) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("blk")?.strip_suffix(".dat")?.parse().ok())
+            else {
+                continue;
+            };
+            files.push((file, entry.metadata()?.len()));
+        }
+        files.sort_unstable();
+        Ok(files)
+    }
+
+    pub fn remove(&self, file: u32) -> io::Result<()> {
+        match fs::remove_file(self.path(file)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
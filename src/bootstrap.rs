@@ -0,0 +1,460 @@
+//! Chain files and UTXO snapshots for bootstrapping a node.
+//!
+//! A chain file is [`CHAIN_FILE_MAGIC`], a format version as a
+//! little-endian `u32`, and then every block of the active chain from the
+//! genesis block up, each as its length as a little-endian `u32` followed by
+//! its canonical encoding. Importing one validates each block as if it had
+//! been received from a peer.
+//!
+//! A UTXO snapshot is [`SNAPSHOT_MAGIC`], a format version, the headers of
+//! the chain from the genesis block to a base block, the UTXO set at that
+//! block and its commitment. The commitment is the double SHA-256 of the
+//! base block hash followed by the encoded coins, so a node that trusts a
+//! commitment can start at the base block without downloading its history.
+
+use crate::bcdb::{BlockchainDb, Result};
+use crate::block::{Block, BlockHeader};
+use crate::blockchain::Blockchain;
+use crate::chainstate::Coin;
+use crate::encode::{self, Decodable, Encodable, Reader};
+use crate::network::ChainParams;
+use crate::store::ChainStore;
+use crate::utils;
+
+use std::io::{self, Read, Write};
+
+/// Marks the start of a chain file.
+pub const CHAIN_FILE_MAGIC: [u8; 4] = *b"BRSC";
+/// Marks the start of a UTXO snapshot.
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"BRSU";
+/// Version of both formats.
+const FORMAT_VERSION: u32 = 1;
+
+/// Writes every block of the active chain to `out`, genesis first, and
+/// returns how many were written. Fails if a block was pruned.
+pub fn export_chain<S: ChainStore, W: Write>(blockchain: &Blockchain<S>, mut out: W) -> Result<u64> {
+    out.write_all(&CHAIN_FILE_MAGIC)?;
+    out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    let height = blockchain.get_best_height();
+    for h in 0..=height {
+        let hash = blockchain.db.read_hash_at(h)?.ok_or("the height index is incomplete")?;
+        let block = blockchain
+            .db
+            .read_block(&hash)?
+            .ok_or_else(|| format!("the block at height {} is pruned", h))?;
+        let data = block.serialize();
+        out.write_all(&(data.len() as u32).to_le_bytes())?;
+        out.write_all(&data)?;
+    }
+    out.flush()?;
+    Ok(height + 1)
+}
+
+/// Reads the blocks of a chain file in order.
+pub struct ChainFileReader<R: Read> {
+    input: R,
+}
+
+impl<R: Read> ChainFileReader<R> {
+    /// Checks the file header of `input`.
+    pub fn new(mut input: R) -> Result<Self> {
+        let mut header = [0u8; 8];
+        input.read_exact(&mut header)?;
+        if header[..4] != CHAIN_FILE_MAGIC {
+            return Err("not a chain file".into());
+        }
+        let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(format!("unsupported chain file version {}", version).into());
+        }
+        Ok(ChainFileReader { input })
+    }
+
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        let mut len = [0u8; 4];
+        let mut read = 0;
+        while read < len.len() {
+            match self.input.read(&mut len[read..])? {
+                0 if read == 0 => return Ok(None),
+                0 => return Err("the chain file is truncated".into()),
+                n => read += n,
+            }
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        let mut data = Vec::new();
+        self.input.by_ref().take(len as u64).read_to_end(&mut data)?;
+        if data.len() != len {
+            return Err("the chain file is truncated".into());
+        }
+        Ok(Some(Block::deserialize(&data)?))
+    }
+}
+
+impl<R: Read> Iterator for ChainFileReader<R> {
+    type Item = Result<Block>;
+
+    fn next(&mut self) -> Option<Result<Block>> {
+        self.read_block().transpose()
+    }
+}
+
+/// What [`import_chain`] did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// Blocks validated and connected.
+    pub connected: u64,
+    /// Blocks that were already in the active chain.
+    pub skipped: u64,
+}
+
+/// Connects the blocks of a chain file to the chain in `db`, starting it
+/// from the file's genesis block if `db` is empty. Blocks already in the
+/// active chain are skipped; every other block must extend the tip and
+/// pass the same checks as a block from a peer. Blocks connected before an
+/// invalid one stay connected.
+pub fn import_chain<S, I>(
+    mut db: BlockchainDb<S>,
+    params: ChainParams,
+    blocks: I,
+) -> Result<(Blockchain<S>, ImportSummary)>
+where
+    S: ChainStore,
+    I: IntoIterator<Item = Result<Block>>,
+{
+    let mut blocks = blocks.into_iter();
+    let genesis = blocks.next().ok_or("the chain file holds no blocks")??;
+    let mut summary = ImportSummary::default();
+    db.recover()?;
+    match db.read_hash_at(0)? {
+        Some(hash) if hash == genesis.hash() => summary.skipped += 1,
+        Some(_) => return Err("the chain file starts from a different genesis block".into()),
+        None => {
+            if !genesis.header.is_genesis() {
+                return Err("the chain file does not start with a genesis block".into());
+            }
+            genesis.check(&params)?;
+            db.connect_block(&genesis, 0)?;
+            summary.connected += 1;
+        }
+    }
+
+    let mut blockchain = Blockchain::open_db(db, params).ok_or("failed to open the chain")?;
+    for (height, block) in (1u64..).zip(blocks) {
+        let block = block?;
+        let hash = block.hash();
+        if blockchain.db.read_hash_at(height)?.as_deref() == Some(hash.as_slice()) {
+            summary.skipped += 1;
+            continue;
+        }
+        if height != blockchain.get_best_height() + 1 {
+            return Err(format!("the block at height {} forks from the local chain", height).into());
+        }
+        blockchain
+            .accept_block(&block)
+            .map_err(|e| format!("block {} at height {}: {}", utils::hex_string(&hash), height, e))?;
+        summary.connected += 1;
+    }
+    Ok((blockchain, summary))
+}
+
+/// The UTXO set at a block, with the headers leading to it.
+pub struct UtxoSnapshot {
+    /// Headers from the genesis block to the base block.
+    pub headers: Vec<BlockHeader>,
+    /// Unspent outputs by txid and index, in that order.
+    pub coins: Vec<(Vec<u8>, u32, Coin)>,
+}
+
+impl UtxoSnapshot {
+    /// Takes a snapshot at the tip of `blockchain`.
+    pub fn from_chain<S: ChainStore>(blockchain: &Blockchain<S>) -> Result<Self> {
+        let mut headers = Vec::new();
+        for h in 0..=blockchain.get_best_height() {
+            let hash = blockchain.db.read_hash_at(h)?.ok_or("the height index is incomplete")?;
+            headers.push(blockchain.db.read_header(&hash)?.ok_or("a header is missing")?);
+        }
+        let mut coins = blockchain.db.read_coins()?;
+        coins.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+        Ok(UtxoSnapshot { headers, coins })
+    }
+
+    pub fn base_hash(&self) -> Vec<u8> {
+        self.headers.last().map(BlockHeader::hash).unwrap_or_default()
+    }
+
+    /// Height of the base block.
+    pub fn height(&self) -> u64 {
+        self.headers.len().saturating_sub(1) as u64
+    }
+
+    /// Commits to the base block and the coins.
+    pub fn commitment(&self) -> Vec<u8> {
+        let mut data = self.base_hash();
+        encode_coins(&mut data, &self.coins);
+        utils::sha256d(&data)
+    }
+
+    pub fn write_to<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let mut data = SNAPSHOT_MAGIC.to_vec();
+        FORMAT_VERSION.consensus_encode(&mut data);
+        self.headers.consensus_encode(&mut data);
+        encode_coins(&mut data, &self.coins);
+        data.extend_from_slice(&self.commitment());
+        out.write_all(&data)?;
+        out.flush()
+    }
+
+    /// Reads a snapshot, checking that it matches the commitment it carries.
+    pub fn read_from<R: Read>(mut input: R) -> Result<Self> {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        let mut reader = Reader::new(&data);
+        if reader.read_array::<4>()? != SNAPSHOT_MAGIC {
+            return Err("not a UTXO snapshot".into());
+        }
+        let version = u32::consensus_decode(&mut reader)?;
+        if version != FORMAT_VERSION {
+            return Err(format!("unsupported snapshot version {}", version).into());
+        }
+        let headers = Vec::consensus_decode(&mut reader)?;
+        let count = reader.read_len()?;
+        let mut coins = Vec::new();
+        for _ in 0..count {
+            let txid = reader.read_var_bytes()?;
+            let vout = u32::consensus_decode(&mut reader)?;
+            coins.push((txid, vout, Coin::consensus_decode(&mut reader)?));
+        }
+        let commitment = reader.read_array::<32>()?;
+        if !reader.is_empty() {
+            return Err(encode::Error::TrailingBytes.into());
+        }
+        let snapshot = UtxoSnapshot { headers, coins };
+        if snapshot.commitment() != commitment {
+            return Err("the snapshot does not match its commitment".into());
+        }
+        Ok(snapshot)
+    }
+
+    /// Starts the chain in the empty `db` from this snapshot, after
+    /// checking that the headers form a chain with valid proof of work.
+    /// Whether the coins can be trusted is up to the caller, who should
+    /// compare [`UtxoSnapshot::commitment`] with a known one first.
+    pub fn load_into<S: ChainStore>(&self, mut db: BlockchainDb<S>, params: ChainParams) -> Result<Blockchain<S>> {
+        let mut prev = [0u8; 32];
+        for (height, header) in self.headers.iter().enumerate() {
+            if header.prev_block_hash != prev {
+                return Err(format!("the header at height {} does not follow the previous one", height).into());
+            }
+            if header.bits != params.target_bits as u32 || !header.validate_pow() {
+                return Err(format!("the header at height {} has invalid proof of work", height).into());
+            }
+            prev.copy_from_slice(&header.hash());
+        }
+        db.load_utxo_snapshot(&self.headers, &self.coins)?;
+        Blockchain::open_db(db, params).ok_or_else(|| "failed to open the chain".into())
+    }
+}
+
+fn encode_coins(out: &mut Vec<u8>, coins: &[(Vec<u8>, u32, Coin)]) {
+    encode::write_varint(out, coins.len() as u64);
+    for (txid, vout, coin) in coins {
+        encode::write_var_bytes(out, txid);
+        vout.consensus_encode(out);
+        coin.consensus_encode(out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coinselect::CoinSelector;
+    use crate::config::Config;
+    use crate::network::Network;
+    use crate::store::MemoryStore;
+    use crate::wallets::Wallets;
+    use crate::transaction::{self, SigHashType, TXInput, TXOutput, Transaction};
+    use crate::wallet;
+
+    #[test]
+    fn chain_files_and_snapshots_bootstrap_a_node() {
+        let params = Network::Regtest.params();
+        let address = wallet::calc_address(0x6f, &[5; 20]);
+        let mut source = Blockchain::in_memory(&params, &address);
+        source.generate(5, &address, Vec::new());
+
+        let mut file = Vec::new();
+        assert_eq!(export_chain(&source, &mut file).unwrap(), 6);
+
+        // a fresh node takes every block, one that has some skips them
+        let blocks = ChainFileReader::new(file.as_slice()).unwrap();
+        let (imported, summary) =
+            import_chain(BlockchainDb::with_store(MemoryStore::new()), params.clone(), blocks).unwrap();
+        assert_eq!(summary, ImportSummary { connected: 6, skipped: 0 });
+        assert_eq!(imported.tip, source.tip);
+        let blocks = ChainFileReader::new(file.as_slice()).unwrap();
+        let (_, summary) = import_chain(imported.db, params.clone(), blocks).unwrap();
+        assert_eq!(summary, ImportSummary { connected: 0, skipped: 6 });
+
+        // a tampered block is rejected
+        let mut bad = file.clone();
+        let last = bad.len() - 1;
+        bad[last] ^= 1;
+        let blocks = ChainFileReader::new(bad.as_slice()).unwrap();
+        assert!(import_chain(BlockchainDb::with_store(MemoryStore::new()), params.clone(), blocks).is_err());
+
+        let snapshot = UtxoSnapshot::from_chain(&source).unwrap();
+        let mut data = Vec::new();
+        snapshot.write_to(&mut data).unwrap();
+        let loaded = UtxoSnapshot::read_from(data.as_slice()).unwrap();
+        assert_eq!(loaded.commitment(), snapshot.commitment());
+        let mut node = loaded
+            .load_into(BlockchainDb::with_store(MemoryStore::new()), params.clone())
+            .unwrap();
+        assert_eq!(node.tip, source.tip);
+        assert_eq!(node.db.read_coins().unwrap().len(), 6);
+        assert!(node.get_block(&source.tip).is_none());
+        // the node mines on top of the snapshot
+        node.generate(1, &address, Vec::new());
+        assert_eq!(node.get_best_height(), 6);
+
+        let last = data.len() - 40;
+        data[last] ^= 1;
+        assert!(UtxoSnapshot::read_from(data.as_slice()).is_err());
+    }
+
+    #[test]
+    fn blocks_creating_coins_are_rejected() {
+        let dir = utils::TempDir::new("accept-test");
+        let config = Config::builder().data_dir(dir.path()).network(Network::Regtest).build();
+        let mut wallets = Wallets::new(&config);
+        let ours = wallets.create_wallet();
+        let params = config.params().clone();
+        let mut node = Blockchain::in_memory(&params, &ours);
+        wallets.sync(&node);
+        let payee = wallet::calc_address(0x6f, &[5; 20]);
+        let payment = transaction::new_transaction(
+            &[(payee, 4)],
+            &[],
+            &[],
+            None,
+            &CoinSelector::default(),
+            &node,
+            &wallets,
+        )
+        .unwrap();
+        let with_change = |change: i32| {
+            let mut tx = payment.clone();
+            tx.vout[1].value = change;
+            tx.id = tx.hash();
+            wallets.sign_transaction(&mut tx).unwrap();
+            tx
+        };
+        let coinbase = |value: i32| transaction::new_coinbase_tx(&ours, b"reward", value);
+        let mine = |transactions: Vec<Transaction>, tip: &[u8]| {
+            Block::new(transactions, tip.to_vec(), params.target_bits).unwrap()
+        };
+
+        let overspend = mine(vec![with_change(7)], &node.tip);
+        let err = node.accept_block(&overspend).unwrap_err().to_string();
+        assert!(err.contains("inputs are worth 10"), "{}", err);
+        let double_spend = mine(vec![with_change(6), with_change(5)], &node.tip);
+        assert!(node.accept_block(&double_spend).is_err());
+        let greedy = mine(vec![coinbase(params.subsidy + 2), with_change(5)], &node.tip);
+        let err = node.accept_block(&greedy).unwrap_err().to_string();
+        assert!(err.contains("subsidy and fees are 11"), "{}", err);
+
+        // the coinbase may claim the fee of one
+        let block = mine(vec![coinbase(params.subsidy + 1), with_change(5)], &node.tip);
+        node.accept_block(&block).unwrap();
+        assert_eq!(node.tip, block.hash());
+    }
+
+    #[test]
+    fn blocks_may_spend_outputs_created_earlier_in_the_block() {
+        let dir = utils::TempDir::new("accept-chain-test");
+        let config = Config::builder().data_dir(dir.path()).network(Network::Regtest).build();
+        let mut wallets = Wallets::new(&config);
+        let ours = wallets.create_wallet();
+        let params = config.params().clone();
+        let mut node = Blockchain::in_memory(&params, &ours);
+        wallets.sync(&node);
+        let payee = wallet::calc_address(0x6f, &[5; 20]);
+        let payment = transaction::new_transaction(
+            &[(payee.clone(), 4)],
+            &[],
+            &[],
+            None,
+            &CoinSelector::default(),
+            &node,
+            &wallets,
+        )
+        .unwrap();
+
+        // spends the change of `payment`, which only exists once it is connected
+        let wallet = wallets.get_wallet(&ours).unwrap();
+        let mut spend_change = Transaction {
+            id: Vec::new(),
+            version: payment.version,
+            vin: vec![TXInput {
+                txid: payment.id.clone(),
+                vout: 1,
+                signature: Vec::new(),
+                pub_key: wallet.public_key.clone(),
+            }],
+            vout: vec![TXOutput::new(5, &payee)],
+        };
+        spend_change.id = spend_change.hash();
+        spend_change.sign_input(0, &payment.vout[1], &wallet.private_key, SigHashType::ALL);
+
+        let coinbase = transaction::new_coinbase_tx(&ours, b"reward", params.subsidy + 1);
+        let block = Block::new(
+            vec![coinbase.clone(), payment.clone(), spend_change.clone()],
+            node.tip.clone(),
+            params.target_bits,
+        )
+        .unwrap();
+        node.accept_block(&block).unwrap();
+        assert_eq!(node.tip, block.hash());
+        assert!(node.get_coin(&payment.id, 1).is_none());
+        assert_eq!(node.get_coin(&spend_change.id, 0).unwrap().output.value, 5);
+
+        // the spend has to come after the transaction creating the output
+        let mut node = Blockchain::in_memory(&params, &ours);
+        let block = Block::new(vec![coinbase, spend_change, payment], node.tip.clone(), params.target_bits)
+            .unwrap();
+        assert!(node.accept_block(&block).is_err());
+    }
+
+    #[test]
+    fn wallets_spend_their_outputs_in_a_loaded_snapshot() {
+        let dir = utils::TempDir::new("snapshot-wallet-test");
+        let config = Config::builder().data_dir(dir.path()).network(Network::Regtest).build();
+        let mut wallets = Wallets::new(&config);
+        let ours = wallets.create_wallet();
+        let params = config.params().clone();
+        let mut source = Blockchain::in_memory(&params, &ours);
+        source.generate(3, &ours, Vec::new());
+
+        let snapshot = UtxoSnapshot::from_chain(&source).unwrap();
+        let node = snapshot
+            .load_into(BlockchainDb::with_store(MemoryStore::new()), params)
+            .unwrap();
+        wallets.sync(&node);
+        assert_eq!(wallets.synced_tip(), Some(node.tip.as_slice()));
+        assert_eq!(wallets.unspent_coins().len(), 4);
+
+        let recipients = [(wallet::calc_address(0x6f, &[5; 20]), 25)];
+        let tx = transaction::new_transaction(
+            &recipients,
+            &[],
+            &[],
+            None,
+            &CoinSelector::default(),
+            &node,
+            &wallets,
+        )
+        .unwrap();
+        assert_eq!(tx.vin.len(), 3);
+        assert!(tx.verify(&node));
+    }
+}
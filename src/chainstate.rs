@@ -0,0 +1,90 @@
+//! The UTXO set and the undo data that lets blocks be disconnected.
+//!
+//! Connecting a block removes the outputs it spends from the UTXO set and
+//! adds the ones it creates. The spent outputs are kept as the block's
+//! [`BlockUndo`], so disconnecting it can put them back even after the block
+//! files holding the transactions that created them were pruned.
+
+use crate::encode::{self, Decodable, Encodable, Reader};
+use crate::transaction::TXOutput;
+
+/// An unspent output together with where it was created.
+#[derive(Clone, Debug)]
+pub struct Coin {
+    pub output: TXOutput,
+    /// Height of the block holding the transaction that created it.
+    pub height: u64,
+    pub is_coinbase: bool,
+}
+
+/// A coin is its output, the height as a `u64` and a coinbase flag byte.
+impl Encodable for Coin {
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+        self.output.consensus_encode(out);
+        self.height.consensus_encode(out);
+        (self.is_coinbase as u8).consensus_encode(out);
+    }
+}
+
+impl Decodable for Coin {
+    fn consensus_decode(reader: &mut Reader<'_>) -> encode::Result<Self> {
+        let output = TXOutput::consensus_decode(reader)?;
+        let height = u64::consensus_decode(reader)?;
+        let is_coinbase = match u8::consensus_decode(reader)? {
+            0 => false,
+            1 => true,
+            _ => return Err(encode::Error::OutOfRange),
+        };
+        Ok(Coin {
+            output,
+            height,
+            is_coinbase,
+        })
+    }
+}
+
+/// A coin spent by a block.
+#[derive(Clone, Debug)]
+pub struct SpentCoin {
+    pub txid: Vec<u8>,
+    pub vout: u32,
+    pub coin: Coin,
+}
+
+impl Encodable for SpentCoin {
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+        encode::write_var_bytes(out, &self.txid);
+        self.vout.consensus_encode(out);
+        self.coin.consensus_encode(out);
+    }
+}
+
+impl Decodable for SpentCoin {
+    fn consensus_decode(reader: &mut Reader<'_>) -> encode::Result<Self> {
+        Ok(SpentCoin {
+            txid: reader.read_var_bytes()?,
+            vout: u32::consensus_decode(reader)?,
+            coin: Coin::consensus_decode(reader)?,
+        })
+    }
+}
+
+/// The coins a block spent, in the order its inputs spend them.
+#[derive(Clone, Debug, Default)]
+pub struct BlockUndo {
+    pub spent: Vec<SpentCoin>,
+}
+
+impl Encodable for BlockUndo {
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+        self.spent.consensus_encode(out);
+    }
+}
+
+impl Decodable for BlockUndo {
+    fn consensus_decode(reader: &mut Reader<'_>) -> encode::Result<Self> {
+        Ok(BlockUndo {
+            spent: Vec::consensus_decode(reader)?,
+        })
+    }
+}
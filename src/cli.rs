@@ -1,27 +1,79 @@
-use crate::{
+use bitcoin::{
+    bcdb::BlockchainDb,
     blockchain::Blockchain,
+    block::Block,
+    bootstrap::{self, UtxoSnapshot},
+    coinselect::{CoinSelector, Strategy},
+    config::{self, Config},
+    encode, json,
+    network::Network,
+    spv::SpvClient,
     transaction::{self},
     utils,
-    wallets::Wallets, wallet,
+    wallets::Wallets,
+    wallet::{self, Wallet},
 };
 
 use clap::{App, Arg, SubCommand};
+use serde_json::{json, Value};
 use std::env;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-pub struct CLI;
+pub struct Cli {
+    config: Config,
+    /// Use the light client's headers instead of the full chain.
+    light: bool,
+    /// Print one JSON document per command instead of text.
+    json: bool,
+}
 
-impl CLI {
+impl Cli {
     fn print_usage() {
         println!("Usage:");
         println!("  createblockchain -address ADDRESS - Create a blockchain and send genesis block reward to ADDRESS");
-        println!("  createwallet - Generates a new key-pair and saves it into the wallet file");
+        println!("  createwallet [--label LABEL] - Generates a new key-pair and saves it into the wallet file");
+        println!("  createwallet NAME [--label LABEL] - Create the wallet file NAME holding a new key-pair");
+        println!("  listwallets - List the wallet files");
+        println!("  loadwallet NAME - Use wallet NAME when --wallet is not given");
+        println!("  unloadwallet - Use the default wallet file when --wallet is not given");
+        println!("  setlabel ADDRESS LABEL - Label an address of the wallet");
+        println!("  backupwallet PATH - Copy the wallet file to PATH");
         println!("  getbalance -address ADDRESS - Get balance of ADDRESS");
         println!("  listaddresses - Lists all addresses from the wallet file");
         println!("  printchain - Print all the blocks of the blockchain");
         println!("  send -from FROM -to TO -amount AMOUNT - Send AMOUNT of coins from FROM address to TO");
+        println!("  send --to ADDRESS:AMOUNT... [--from ADDRESS...] [--change-address ADDRESS] - Pay several recipients in one transaction");
+        println!("    [--strategy largest-first|smallest-first|branch-and-bound|random] [--dust-threshold AMOUNT]");
+        println!("    [--no-mine] - Sign and keep the transaction until the next generate instead of mining it");
+        println!("    [--inputs TXID:VOUT,...] - Spend exactly these outputs");
+        println!("    [--unsigned] - Print the transaction without signing it, for watch-only addresses");
+        println!("  importaddress ADDRESS|PUBKEY [--no-rescan] - Watch an address without its private key");
+        println!("  dumpprivkey ADDRESS [--pem] - Print the private key of ADDRESS");
+        println!("  importprivkey KEY|--file FILE [--no-rescan] - Add a private key printed by dumpprivkey");
+        println!("  listtransactions [--address ADDRESS] - List the wallet's transactions");
+        println!("  listunspent [--address ADDRESS] - List the wallet's unspent outputs");
+        println!("  lockunspent TXID:VOUT... - Leave outputs out of automatic coin selection");
+        println!("  unlockunspent TXID:VOUT... - Make locked outputs available to coin selection again");
+        println!("  generate N [ADDRESS] - Mine N blocks paying the rewards to ADDRESS or a new wallet address");
+        println!("  spvsync [--node DIR] - Sync the light client's headers and wallet transactions from a full node");
+        println!("  decoderawtransaction HEX - Print a serialized transaction as JSON");
+        println!("  decodeblock HEX|HASH - Print a serialized block, or the block with HASH, as JSON");
+        println!("  invalidateblock HASH - Disconnect the block HASH and the blocks above it");
+        println!("  reconsiderblock HASH - Let an invalidated block back into the chain");
+        println!("  checkdb [--repair] - Check every stored block and index; --repair removes what is damaged");
+        println!("  exportchain FILE - Write every block of the chain to FILE");
+        println!("  importchain FILE - Validate and connect the blocks written by exportchain");
+        println!("  dumptxoutset FILE - Write the UTXO set at the tip to FILE and print its hash");
+        println!("  loadtxoutset FILE --hash HASH - Start an empty chain from a UTXO set with a trusted HASH");
+        println!("  clear [--all-wallets] - Delete the chain and the selected wallet, or every wallet");
+        println!("Options:");
+        println!("  --datadir DIR --conf FILE --network mainnet|testnet|regtest --wallet NAME --mocktime SECONDS --light --json");
     }
 
-    pub fn run(&self) {
+    pub fn run() {
         let args: Vec<String> = env::args().collect();
         if args.len() < 2 {
             Self::print_usage();
@@ -33,6 +85,67 @@ impl CLI {
             .version("1.0")
             .author("Zhenyu Jia <jzhenyu3@gmail.com>")
             .about("Bitcoin implementation in Rust")
+            .arg(
+                Arg::with_name("datadir")
+                    .long("datadir")
+                    .value_name("DIR")
+                    .takes_value(true)
+                    .global(true)
+                    .help("Data directory (default: ~/.bitcoin-rs)"),
+            )
+            .arg(
+                Arg::with_name("conf")
+                    .long("conf")
+                    .value_name("FILE")
+                    .takes_value(true)
+                    .global(true)
+                    .help("Config file (default: <datadir>/bitcoin.toml)"),
+            )
+            .arg(
+                Arg::with_name("network")
+                    .long("network")
+                    .value_name("NAME")
+                    .takes_value(true)
+                    .possible_values(&["mainnet", "testnet", "regtest"])
+                    .global(true)
+                    .help("Network to use (default: mainnet)"),
+            )
+            .arg(
+                Arg::with_name("wallet")
+                    .long("wallet")
+                    .value_name("NAME")
+                    .takes_value(true)
+                    .global(true)
+                    .help("Wallet to use (default: the loaded wallet, or wallets.dat)"),
+            )
+            .arg(
+                Arg::with_name("prune")
+                    .long("prune")
+                    .value_name("MB")
+                    .takes_value(true)
+                    .global(true)
+                    .help("Delete old block files to keep them under MB MiB, at least 550; 0 turns pruning off"),
+            )
+            .arg(
+                Arg::with_name("mocktime")
+                    .long("mocktime")
+                    .value_name("SECONDS")
+                    .takes_value(true)
+                    .global(true)
+                    .help("Use a fixed Unix time for new blocks"),
+            )
+            .arg(
+                Arg::with_name("light")
+                    .long("light")
+                    .global(true)
+                    .help("Run as a light client that only keeps block headers"),
+            )
+            .arg(
+                Arg::with_name("json")
+                    .long("json")
+                    .global(true)
+                    .help("Print the result of the command as a single JSON document"),
+            )
             .subcommand(
                 SubCommand::with_name("getbalance")
                     .about("Get balance of ADDRESS")
@@ -45,7 +158,36 @@ impl CLI {
             )
             .subcommand(
                 SubCommand::with_name("createwallet")
-                    .about("Generates a new key-pair and saves it into the wallet file"),
+                    .about("Generates a new key-pair and saves it into the wallet file, or into a new wallet NAME")
+                    .arg(Arg::with_name("NAME").index(1))
+                    .arg(
+                        Arg::with_name("label")
+                            .long("label")
+                            .value_name("LABEL")
+                            .takes_value(true)
+                            .help("Label of the new address"),
+                    ),
+            )
+            .subcommand(SubCommand::with_name("listwallets").about("List the wallet files"))
+            .subcommand(
+                SubCommand::with_name("loadwallet")
+                    .about("Use wallet NAME when --wallet is not given")
+                    .arg(Arg::with_name("NAME").required(true).index(1)),
+            )
+            .subcommand(
+                SubCommand::with_name("unloadwallet")
+                    .about("Use the default wallet file when --wallet is not given"),
+            )
+            .subcommand(
+                SubCommand::with_name("backupwallet")
+                    .about("Copy the wallet file to PATH, or into PATH if it is a directory")
+                    .arg(Arg::with_name("PATH").required(true).index(1)),
+            )
+            .subcommand(
+                SubCommand::with_name("setlabel")
+                    .about("Label an address of the wallet; an empty LABEL removes the label")
+                    .arg(Arg::with_name("ADDRESS").required(true).index(1))
+                    .arg(Arg::with_name("LABEL").required(true).index(2)),
             )
             .subcommand(
                 SubCommand::with_name("listaddresses")
@@ -56,130 +198,1516 @@ impl CLI {
             )
             .subcommand(
                 SubCommand::with_name("send")
-                    .about("Send AMOUNT of coins from FROM address to TO")
-                    .arg(Arg::with_name("FROM").required(true).index(1))
-                    .arg(Arg::with_name("TO").required(true).index(2))
-                    .arg(Arg::with_name("AMOUNT").required(true).index(3)),
+                    .about("Send AMOUNT of coins from FROM address to TO, or pay several --to recipients at once")
+                    .arg(Arg::with_name("FROM").index(1).requires_all(&["TO", "AMOUNT"]))
+                    .arg(Arg::with_name("TO").index(2))
+                    .arg(Arg::with_name("AMOUNT").index(3))
+                    .arg(
+                        Arg::with_name("to")
+                            .long("to")
+                            .value_name("ADDRESS:AMOUNT")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .conflicts_with("FROM")
+                            .required_unless("FROM")
+                            .help("Recipient and amount; may be repeated"),
+                    )
+                    .arg(
+                        Arg::with_name("from")
+                            .long("from")
+                            .value_name("ADDRESS")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .help("Address to spend from; may be repeated (default: every wallet address)"),
+                    )
+                    .arg(
+                        Arg::with_name("inputs")
+                            .long("inputs")
+                            .value_name("TXID:VOUT")
+                            .takes_value(true)
+                            .multiple(true)
+                            .use_delimiter(true)
+                            .conflicts_with_all(&["from", "strategy"])
+                            .help("Outputs to spend instead of choosing them automatically; may be comma-separated"),
+                    )
+                    .arg(
+                        Arg::with_name("change-address")
+                            .long("change-address")
+                            .value_name("ADDRESS")
+                            .takes_value(true)
+                            .help("Address receiving the change (default: the first address spent from)"),
+                    )
+                    .arg(
+                        Arg::with_name("strategy")
+                            .long("strategy")
+                            .value_name("NAME")
+                            .takes_value(true)
+                            .possible_values(&["largest-first", "smallest-first", "branch-and-bound", "random"])
+                            .help("Coin selection strategy (default: largest-first)"),
+                    )
+                    .arg(
+                        Arg::with_name("dust-threshold")
+                            .long("dust-threshold")
+                            .value_name("AMOUNT")
+                            .takes_value(true)
+                            .help("Smallest change worth an output; less is left as fee (default: 1)"),
+                    )
+                    .arg(
+                        Arg::with_name("no-mine")
+                            .long("no-mine")
+                            .help("Keep the transaction pending until the next generate instead of mining it"),
+                    )
+                    .arg(
+                        Arg::with_name("unsigned")
+                            .long("unsigned")
+                            .conflicts_with("no-mine")
+                            .help("Print the transaction without signing or mining it; may spend watch-only addresses"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("importaddress")
+                    .about("Watch an address, or the address of a public key, without its private key")
+                    .arg(Arg::with_name("ADDRESS").required(true).index(1))
+                    .arg(
+                        Arg::with_name("no-rescan")
+                            .long("no-rescan")
+                            .help("Skip scanning the chain for earlier outputs of the address"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("dumpprivkey")
+                    .about("Print the private key of ADDRESS")
+                    .arg(Arg::with_name("ADDRESS").required(true).index(1))
+                    .arg(
+                        Arg::with_name("pem")
+                            .long("pem")
+                            .help("Print a PKCS#8 PEM document instead of a Base58Check key"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("importprivkey")
+                    .about("Add a private key printed by dumpprivkey, in Base58Check or PEM form")
+                    .arg(Arg::with_name("KEY").index(1).required_unless("file"))
+                    .arg(
+                        Arg::with_name("file")
+                            .long("file")
+                            .value_name("FILE")
+                            .takes_value(true)
+                            .conflicts_with("KEY")
+                            .help("Read the key from FILE"),
+                    )
+                    .arg(
+                        Arg::with_name("no-rescan")
+                            .long("no-rescan")
+                            .help("Skip scanning the chain for earlier outputs of the key"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("listtransactions")
+                    .about("List the wallet's transactions")
+                    .arg(
+                        Arg::with_name("address")
+                            .long("address")
+                            .value_name("ADDRESS")
+                            .takes_value(true)
+                            .help("Only list transactions involving ADDRESS"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("listunspent")
+                    .about("List the wallet's unspent outputs")
+                    .arg(
+                        Arg::with_name("address")
+                            .long("address")
+                            .value_name("ADDRESS")
+                            .takes_value(true)
+                            .help("Only list outputs paying ADDRESS"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("lockunspent")
+                    .about("Leave outputs out of automatic coin selection")
+                    .arg(Arg::with_name("OUTPOINT").required(true).multiple(true).index(1)),
+            )
+            .subcommand(
+                SubCommand::with_name("unlockunspent")
+                    .about("Make locked outputs available to coin selection again")
+                    .arg(Arg::with_name("OUTPOINT").required(true).multiple(true).index(1)),
+            )
+            .subcommand(
+                SubCommand::with_name("generate")
+                    .about("Mine N blocks paying the rewards to ADDRESS or a new wallet address")
+                    .arg(Arg::with_name("N").required(true).index(1))
+                    .arg(Arg::with_name("ADDRESS").index(2)),
+            )
+            .subcommand(
+                SubCommand::with_name("spvsync")
+                    .about("Sync the light client's headers and wallet transactions from a full node")
+                    .arg(
+                        Arg::with_name("node")
+                            .long("node")
+                            .value_name("DIR")
+                            .takes_value(true)
+                            .help("Data directory of the full node (default: --datadir)"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("decoderawtransaction")
+                    .about("Print a serialized transaction as JSON")
+                    .arg(Arg::with_name("HEX").required(true).index(1)),
+            )
+            .subcommand(
+                SubCommand::with_name("decodeblock")
+                    .about("Print a serialized block, or the block with HASH, as JSON")
+                    .arg(Arg::with_name("BLOCK").required(true).index(1)),
+            )
+            .subcommand(
+                SubCommand::with_name("invalidateblock")
+                    .about("Disconnect the block HASH and the blocks above it, and keep them out of the chain")
+                    .arg(Arg::with_name("HASH").required(true).index(1)),
+            )
+            .subcommand(
+                SubCommand::with_name("reconsiderblock")
+                    .about("Let an invalidated block back into the chain, switching to it if its chain is longer")
+                    .arg(Arg::with_name("HASH").required(true).index(1)),
+            )
+            .subcommand(
+                SubCommand::with_name("checkdb")
+                    .about("Check every stored block and the indexes of the chain database")
+                    .arg(
+                        Arg::with_name("repair")
+                            .long("repair")
+                            .help("Delete damaged records, rebuild the indexes and move the tip to the last valid block"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("exportchain")
+                    .about("Write every block of the chain to FILE, genesis first")
+                    .arg(Arg::with_name("FILE").required(true).index(1)),
+            )
+            .subcommand(
+                SubCommand::with_name("importchain")
+                    .about("Validate and connect the blocks written by exportchain")
+                    .arg(Arg::with_name("FILE").required(true).index(1)),
+            )
+            .subcommand(
+                SubCommand::with_name("dumptxoutset")
+                    .about("Write the UTXO set at the tip to FILE and print its hash")
+                    .arg(Arg::with_name("FILE").required(true).index(1)),
+            )
+            .subcommand(
+                SubCommand::with_name("loadtxoutset")
+                    .about("Start an empty chain from a UTXO set written by dumptxoutset")
+                    .arg(Arg::with_name("FILE").required(true).index(1))
+                    .arg(
+                        Arg::with_name("hash")
+                            .long("hash")
+                            .value_name("HASH")
+                            .takes_value(true)
+                            .required(true)
+                            .help("Hash of the UTXO set, as printed by dumptxoutset on a node you trust"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("clear")
+                    .about("Delete the chain and the selected wallet")
+                    .arg(
+                        Arg::with_name("all-wallets")
+                            .long("all-wallets")
+                            .help("Also delete wallets.dat and every named wallet"),
+                    ),
             )
-            .subcommand(SubCommand::with_name("clear"))
             .about("Delete all blocks and walletes")
             .get_matches();
 
+        let json = matches.is_present("json");
+        if json {
+            // library code reports some failures by panicking
+            std::panic::set_hook(Box::new(|info| {
+                let message = info
+                    .payload()
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| info.payload().downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown error".to_string());
+                print_error(true, &message);
+            }));
+            // keep stdout for the result
+            utils::set_log_to_stderr(true);
+        }
+        let config = match Self::load_config(&matches) {
+            Ok(config) => config,
+            Err(e) => {
+                print_error(json, &format!("Invalid configuration: {}", e));
+                return;
+            }
+        };
+        if let Some(mocktime) = matches.value_of("mocktime") {
+            match mocktime.parse::<i64>() {
+                Ok(time) => utils::set_mock_time(Some(time)),
+                Err(_) => {
+                    print_error(json, "Invalid mocktime");
+                    return;
+                }
+            }
+        }
+        let cli = Cli {
+            config,
+            light: matches.is_present("light"),
+            json,
+        };
+        if cli.light {
+            if let (
+                name @ ("createblockchain" | "send" | "printchain" | "generate" | "listunspent"
+                | "lockunspent" | "unlockunspent" | "listtransactions"),
+                _,
+            ) = matches.subcommand()
+            {
+                cli.error(format!("{} is not available in light mode", name));
+                return;
+            }
+        }
+
+        // a mistyped --wallet would otherwise silently start a new wallet
+        if let Some(name) = cli.config.wallet_name() {
+            let exempt = matches!(
+                matches.subcommand_name(),
+                Some("createwallet" | "listwallets" | "loadwallet" | "unloadwallet" | "clear")
+            );
+            if !exempt && !cli.config.wallet_path().exists() {
+                cli.error(format!(
+                    "Wallet {} does not exist; create it with createwallet {}",
+                    name, name
+                ));
+                return;
+            }
+        }
+
         // Match the subcommands and execute the corresponding code
         match matches.subcommand() {
             ("getbalance", Some(sub_m)) => {
                 let address = sub_m.value_of("ADDRESS").unwrap();
-                self.get_balance(address);
+                cli.get_balance(address);
             }
             ("createblockchain", Some(sub_m)) => {
                 let address = sub_m.value_of("ADDRESS").unwrap();
-                self.create_blockchain(address);
+                cli.create_blockchain(address);
+            }
+            ("createwallet", Some(sub_m)) => {
+                cli.create_wallet(sub_m.value_of("NAME"), sub_m.value_of("label").unwrap_or(""));
+            }
+            ("listwallets", Some(_)) => {
+                cli.list_wallets();
+            }
+            ("loadwallet", Some(sub_m)) => {
+                cli.load_wallet(Some(sub_m.value_of("NAME").unwrap()));
+            }
+            ("unloadwallet", Some(_)) => {
+                cli.load_wallet(None);
             }
-            ("createwallet", Some(_)) => {
-                self.create_wallet();
+            ("backupwallet", Some(sub_m)) => {
+                cli.backup_wallet(sub_m.value_of("PATH").unwrap());
+            }
+            ("setlabel", Some(sub_m)) => {
+                let label = sub_m.value_of("LABEL").unwrap();
+                cli.set_label(sub_m.value_of("ADDRESS").unwrap(), label);
             }
             ("listaddresses", Some(_)) => {
-                self.list_addresses();
+                cli.list_addresses();
             }
             ("printchain", Some(_)) => {
-                self.print_chain();
+                cli.print_chain();
             }
             ("send", Some(sub_m)) => {
-                let from = sub_m.value_of("FROM").unwrap();
-                let to = sub_m.value_of("TO").unwrap();
-                let amount = sub_m.value_of("AMOUNT").unwrap().parse::<i32>().unwrap();
-                self.send(from, to, amount);
-            }
-            ("clear", Some(_)) => {
-                let current_dir = std::fs::read_dir(".").expect("Failed to read current directory");
+                let mut from: Vec<String> = sub_m
+                    .values_of("from")
+                    .map(|values| values.map(String::from).collect())
+                    .unwrap_or_default();
+                let mut recipients = Vec::new();
+                if let Some(address) = sub_m.value_of("FROM") {
+                    from = vec![address.to_string()];
+                    let to = sub_m.value_of("TO").unwrap();
+                    let amount = sub_m.value_of("AMOUNT").unwrap();
+                    recipients.push(format!("{}:{}", to, amount));
+                }
+                recipients.extend(sub_m.values_of("to").into_iter().flatten().map(String::from));
 
-                // Delete all .json files and folders named blockchain.db
-                Self::delete_files_and_folders(current_dir, "dat", "blockchain.db");
+                let mut parsed = Vec::new();
+                for recipient in &recipients {
+                    match recipient.rsplit_once(':').map(|(a, n)| (a, n.parse::<i32>())) {
+                        Some((address, Ok(amount))) => parsed.push((address.to_string(), amount)),
+                        _ => {
+                            cli.error(format!("Invalid recipient {}", recipient));
+                            return;
+                        }
+                    }
+                }
+                // clap has already checked the name
+                let strategy: Strategy = sub_m
+                    .value_of("strategy")
+                    .map(|name| name.parse().unwrap())
+                    .unwrap_or_default();
+                let mut selector = CoinSelector::new(strategy);
+                if let Some(dust) = sub_m.value_of("dust-threshold") {
+                    match dust.parse::<i32>() {
+                        Ok(dust) => selector = selector.dust_threshold(dust),
+                        Err(_) => {
+                            cli.error("Invalid dust threshold");
+                            return;
+                        }
+                    }
+                }
+                let mut inputs = Vec::new();
+                for outpoint in sub_m.values_of("inputs").into_iter().flatten() {
+                    match parse_outpoint(outpoint) {
+                        Some(outpoint) => inputs.push(outpoint),
+                        None => {
+                            cli.error(format!("Invalid outpoint {}", outpoint));
+                            return;
+                        }
+                    }
+                }
+                cli.send(
+                    &from,
+                    &inputs,
+                    &parsed,
+                    sub_m.value_of("change-address"),
+                    &selector,
+                    SendMode::from_flags(sub_m.is_present("no-mine"), sub_m.is_present("unsigned")),
+                );
+            }
+            ("importaddress", Some(sub_m)) => {
+                let rescan = !sub_m.is_present("no-rescan");
+                cli.import_address(sub_m.value_of("ADDRESS").unwrap(), rescan);
+            }
+            ("dumpprivkey", Some(sub_m)) => {
+                cli.dump_priv_key(sub_m.value_of("ADDRESS").unwrap(), sub_m.is_present("pem"));
+            }
+            ("importprivkey", Some(sub_m)) => {
+                let key = match sub_m.value_of("file") {
+                    Some(path) => match std::fs::read_to_string(path) {
+                        Ok(key) => key,
+                        Err(e) => {
+                            cli.error(format!("Failed to read {}: {}", path, e));
+                            return;
+                        }
+                    },
+                    None => sub_m.value_of("KEY").unwrap().to_string(),
+                };
+                cli.import_priv_key(&key, !sub_m.is_present("no-rescan"));
+            }
+            ("listtransactions", Some(sub_m)) => {
+                cli.list_transactions(sub_m.value_of("address"));
+            }
+            ("listunspent", Some(sub_m)) => {
+                cli.list_unspent(sub_m.value_of("address"));
+            }
+            (name @ ("lockunspent" | "unlockunspent"), Some(sub_m)) => {
+                let mut outpoints = Vec::new();
+                for outpoint in sub_m.values_of("OUTPOINT").unwrap() {
+                    match parse_outpoint(outpoint) {
+                        Some(outpoint) => outpoints.push(outpoint),
+                        None => {
+                            cli.error(format!("Invalid outpoint {}", outpoint));
+                            return;
+                        }
+                    }
+                }
+                cli.lock_unspent(&outpoints, name == "lockunspent");
+            }
+            ("generate", Some(sub_m)) => {
+                let count = match sub_m.value_of("N").unwrap().parse::<usize>() {
+                    Ok(count) => count,
+                    Err(_) => {
+                        cli.error("Invalid number of blocks");
+                        return;
+                    }
+                };
+                cli.generate(count, sub_m.value_of("ADDRESS"));
+            }
+            ("spvsync", Some(sub_m)) => {
+                cli.spv_sync(sub_m.value_of("node"));
+            }
+            ("decoderawtransaction", Some(sub_m)) => {
+                cli.decode_raw_transaction(sub_m.value_of("HEX").unwrap());
+            }
+            ("decodeblock", Some(sub_m)) => {
+                cli.decode_block(sub_m.value_of("BLOCK").unwrap());
+            }
+            ("invalidateblock", Some(sub_m)) => {
+                let hash = sub_m.value_of("HASH").unwrap();
+                match parse_block_hash(hash) {
+                    Some(hash) => cli.invalidate_block(&hash),
+                    None => cli.error(format!("Invalid block hash {}", hash)),
+                }
+            }
+            ("reconsiderblock", Some(sub_m)) => {
+                let hash = sub_m.value_of("HASH").unwrap();
+                match parse_block_hash(hash) {
+                    Some(hash) => cli.reconsider_block(&hash),
+                    None => cli.error(format!("Invalid block hash {}", hash)),
+                }
+            }
+            ("checkdb", Some(sub_m)) => {
+                cli.check_db(sub_m.is_present("repair"));
+            }
+            ("exportchain", Some(sub_m)) => {
+                cli.export_chain(sub_m.value_of("FILE").unwrap());
+            }
+            ("importchain", Some(sub_m)) => {
+                cli.import_chain(sub_m.value_of("FILE").unwrap());
+            }
+            ("dumptxoutset", Some(sub_m)) => {
+                cli.dump_txout_set(sub_m.value_of("FILE").unwrap());
+            }
+            ("loadtxoutset", Some(sub_m)) => {
+                cli.load_txout_set(sub_m.value_of("FILE").unwrap(), sub_m.value_of("hash").unwrap());
+            }
+            ("clear", Some(sub_m)) => {
+                cli.clear(sub_m.is_present("all-wallets"));
             }
             _ => {
-                eprintln!("Invalid command. Use --help for usage information.");
+                cli.error("Invalid command. Use --help for usage information.");
             }
         }
     }
 
+    fn load_config(matches: &clap::ArgMatches) -> config::Result<Config> {
+        let data_dir = matches
+            .value_of("datadir")
+            .map(PathBuf::from)
+            .unwrap_or_else(config::default_data_dir);
+        let mut builder = Config::builder().data_dir(&data_dir);
+        builder = match matches.value_of("conf") {
+            Some(path) => builder.config_file(&PathBuf::from(path))?,
+            None => builder.config_file_if_exists(&data_dir.join(config::CONFIG_FILE))?,
+        };
+        if let Some(network) = matches.value_of("network") {
+            builder = builder.network(network.parse::<Network>()?);
+        }
+        if let Some(name) = matches.value_of("wallet") {
+            config::validate_wallet_name(name)?;
+            builder = builder.wallet(name);
+        }
+        if let Some(mb) = matches.value_of("prune") {
+            let mb = mb.parse::<u64>().map_err(|_| format!("Invalid prune size `{}`", mb))?;
+            config::validate_prune(mb)?;
+            builder = builder.prune(mb);
+        }
+        let config = builder.build();
+        if !config.backend().is_available() {
+            return Err(format!("This build does not include the {} backend", config.backend()).into());
+        }
+        Ok(config)
+    }
+
     pub fn create_blockchain(&self, address: &str) {
-        if !wallet::validate_address(address) {
-            eprintln!("Invalid address");
+        if !wallet::validate_address(address, self.config.params().address_version) {
+            self.error("Invalid address");
             return;
         }
 
-        Blockchain::new(address);
+        let blockchain = Blockchain::new(&self.config, address);
 
-        println!("Done!");
+        if self.json {
+            self.print_json(json!({ "tip": utils::hex_string(&blockchain.tip) }));
+        } else {
+            println!("Done!");
+        }
     }
 
-    pub fn create_wallet(&self) {
-        let mut wallets = Wallets::new();
+    /// Adds a new address to the selected wallet, or creates the wallet
+    /// file `name` holding one.
+    pub fn create_wallet(&self, name: Option<&str>, label: &str) {
+        let config = match name {
+            Some(name) => {
+                if let Err(e) = config::validate_wallet_name(name) {
+                    self.error(e);
+                    return;
+                }
+                let config = self.config.with_wallet(name);
+                if config.wallet_path().exists() {
+                    self.error(format!("Wallet {} already exists", name));
+                    return;
+                }
+                config
+            }
+            None => self.config.clone(),
+        };
+        let mut wallets = Wallets::new(&config);
         let address = wallets.create_wallet();
+        wallets.set_label(&address, label);
         wallets.save_to_file();
+        if self.json {
+            self.print_json(json!({ "wallet": config.wallet_name(), "address": address }));
+            return;
+        }
+        if let Some(name) = name {
+            println!("Created wallet {}", name);
+        }
         println!("Your new address: {}", address);
     }
 
+    /// Lists the default wallet file, if there is one, and the named wallets.
+    pub fn list_wallets(&self) {
+        let mut names: Vec<Option<String>> = Vec::new();
+        if self.config.network_dir().join(config::WALLET_FILE).exists() {
+            names.push(None);
+        }
+        names.extend(self.config.list_wallets().into_iter().map(Some));
+        let selected = self.config.wallet_name();
+        if self.json {
+            let wallets: Vec<Value> = names
+                .iter()
+                .map(|name| {
+                    json!({
+                        "name": name.as_deref().unwrap_or(""),
+                        "selected": name.as_deref() == selected,
+                    })
+                })
+                .collect();
+            self.print_json(Value::Array(wallets));
+            return;
+        }
+        for name in &names {
+            let marker = if name.as_deref() == selected { "* " } else { "  " };
+            println!("{}{}", marker, name.as_deref().unwrap_or("(default)"));
+        }
+    }
+
+    /// Makes `name` the wallet used when `--wallet` is not given, or the
+    /// default wallet file with `None`.
+    pub fn load_wallet(&self, name: Option<&str>) {
+        if let Some(name) = name {
+            if let Err(e) = config::validate_wallet_name(name) {
+                self.error(e);
+                return;
+            }
+            if !self.config.named_wallet_path(name).exists() {
+                self.error(format!("Wallet {} does not exist", name));
+                return;
+            }
+        }
+        if let Err(e) = self.config.set_default_wallet(name) {
+            self.error(format!("Failed to save the default wallet: {}", e));
+            return;
+        }
+        if self.json {
+            self.print_json(json!({ "wallet": name.unwrap_or("") }));
+        } else {
+            println!("Using wallet {}", name.unwrap_or("(default)"));
+        }
+    }
+
+    pub fn backup_wallet(&self, destination: &str) {
+        if !self.config.wallet_path().exists() {
+            self.error("There is no wallet file to back up");
+            return;
+        }
+        let wallets = Wallets::new(&self.config);
+        match wallets.backup_to(Path::new(destination)) {
+            Ok(path) if self.json => self.print_json(json!({ "path": path })),
+            Ok(path) => println!("Wallet saved to {}", path.display()),
+            Err(e) => self.error(format!("Backup failed: {}", e)),
+        }
+    }
+
+    pub fn set_label(&self, address: &str, label: &str) {
+        let mut wallets = Wallets::new(&self.config);
+        if !wallets.set_label(address, label) {
+            self.error(format!("Wallet not found: {}", address));
+            return;
+        }
+        wallets.save_to_file();
+        if self.json {
+            self.print_json(json!({ "address": address, "label": label }));
+        }
+    }
+
     pub fn get_balance(&self, address: &str) {
-        if !wallet::validate_address(address) {
-            eprintln!("Invalid address");
+        if !wallet::validate_address(address, self.config.params().address_version) {
+            self.error("Invalid address");
             return;
         }
 
-        let blockchain = Blockchain::new(address);
         let payload = utils::base58_decode(address);
         let pub_key_hash = payload[1..payload.len() - wallet::CHECKSUM_LENGTH].to_vec();
+        if self.light {
+            match SpvClient::new(&self.config) {
+                Ok(client) => self.print_balance(address, client.get_balance(&pub_key_hash)),
+                Err(e) => self.error(format!("Failed to open the light client: {}", e)),
+            }
+            return;
+        }
+
+        let blockchain = Blockchain::new(&self.config, address);
         let utxos = blockchain.find_utxo(pub_key_hash);
         let mut balance = 0;
         for utxo in utxos {
             balance += utxo.get_value();
         }
-        println!("Balance of {}: {}", address, balance);
+        self.print_balance(address, balance);
+    }
+
+    fn print_balance(&self, address: &str, balance: i32) {
+        if self.json {
+            self.print_json(json!({ "address": address, "balance": balance }));
+        } else {
+            println!("Balance of {}: {}", address, balance);
+        }
     }
 
     pub fn list_addresses(&self) {
-        let wallets = Wallets::new();
-        let addresses: Vec<String> = wallets.get_addresses();
+        let wallets = Wallets::new(&self.config);
+        let mut addresses: Vec<String> = wallets.get_addresses();
+        addresses.extend(wallets.get_watch_only_addresses());
+        // oldest first; addresses from before creation times were kept come first
+        addresses.sort_by_key(|address| (wallets.get_info(address).created, address.clone()));
+        let entries: Vec<Value> = addresses
+            .iter()
+            .map(|address| {
+                let info = wallets.get_info(address);
+                json!({
+                    "address": address,
+                    "label": info.label,
+                    "created": info.created,
+                    "watch_only": wallets.is_watch_only(address),
+                })
+            })
+            .collect();
+        if self.json {
+            self.print_json(Value::Array(entries));
+            return;
+        }
+        for entry in entries {
+            let mut line = entry["address"].as_str().unwrap().to_string();
+            if entry["watch_only"] == json!(true) {
+                line.push_str(" (watch-only)");
+            }
+            let label = entry["label"].as_str().unwrap();
+            if !label.is_empty() {
+                line.push_str(&format!(" {}", label));
+            }
+            println!("{}", line);
+        }
+    }
+
+    /// Watches `address`, which may also be given as a hex public key.
+    /// With `rescan`, earlier outputs paying it are picked up from the chain.
+    pub fn import_address(&self, address: &str, rescan: bool) {
+        let version = self.config.params().address_version;
+        let (address, public_key) = if wallet::validate_address(address, version) {
+            (address.to_string(), None)
+        } else {
+            let public_key = hex::decode(address)
+                .ok()
+                .filter(|key| p256::PublicKey::from_sec1_bytes(key).is_ok());
+            match public_key {
+                Some(key) => (wallet::calc_address(version, &utils::hash_pub_key(&key)), Some(key)),
+                None => {
+                    self.error("Invalid address or public key");
+                    return;
+                }
+            }
+        };
+
+        let mut wallets = Wallets::new(&self.config);
+        if wallets.get_wallet(&address).is_some() {
+            self.error(format!("The wallet already holds the key of {}", address));
+            return;
+        }
+        wallets.import_watch_only(&address, public_key);
+        if rescan && !self.light {
+            if let Some(blockchain) = Blockchain::open(&self.config) {
+                wallets.rescan(&blockchain);
+            }
+        }
+        wallets.save_to_file();
+        if self.json {
+            self.print_json(json!({ "address": address, "watch_only": true }));
+        } else {
+            println!("Watching {}", address);
+        }
+    }
+
+    /// Prints the private key of `address` in Base58Check form, or as PEM.
+    pub fn dump_priv_key(&self, address: &str, pem: bool) {
+        let wallets = Wallets::new(&self.config);
+        let Some(wallet) = wallets.get_wallet(address) else {
+            if wallets.is_watch_only(address) {
+                self.error(format!("{} is watch-only", address));
+            } else {
+                self.error(format!("Wallet not found: {}", address));
+            }
+            return;
+        };
+        let key = if pem {
+            wallet.to_pem()
+        } else {
+            wallet.to_wif(self.config.params().secret_key_version)
+        };
+        if self.json {
+            self.print_json(json!({ "address": address, "key": key }));
+        } else {
+            print!("{}", key);
+            if !pem {
+                println!();
+            }
+        }
+    }
+
+    /// Adds a private key in Base58Check or PEM form. With `rescan`,
+    /// earlier outputs paying its address are picked up from the chain.
+    pub fn import_priv_key(&self, key: &str, rescan: bool) {
+        let wallet = if key.contains("-----BEGIN") {
+            Wallet::from_pem(key)
+        } else {
+            Wallet::from_wif(key, self.config.params().secret_key_version)
+        };
+        let wallet = match wallet {
+            Ok(wallet) => wallet,
+            Err(e) => {
+                self.error(e);
+                return;
+            }
+        };
+
+        let mut wallets = Wallets::new(&self.config);
+        let address = wallets.import_wallet(wallet);
+        if rescan && !self.light {
+            if let Some(blockchain) = Blockchain::open(&self.config) {
+                wallets.rescan(&blockchain);
+            }
+        }
+        wallets.save_to_file();
+        if self.json {
+            self.print_json(json!({ "address": address }));
+        } else {
+            println!("Imported {}", address);
+        }
+    }
+
+    /// Lists the net amount each wallet transaction paid to or took from
+    /// each of our addresses, or only `address`.
+    pub fn list_transactions(&self, address: Option<&str>) {
+        if let Some(address) = address {
+            if !wallet::validate_address(address, self.config.params().address_version) {
+                self.error("Invalid address");
+                return;
+            }
+        }
+
+        let blockchain = Blockchain::new(&self.config, "");
+        let mut wallets = Wallets::new(&self.config);
+        wallets.sync(&blockchain);
+        wallets.save_to_file();
+        let height = wallets.synced_height().unwrap_or(0);
+        let entries: Vec<Value> = wallets
+            .history()
+            .into_iter()
+            .filter(|entry| address.is_none_or(|address| entry.address == address))
+            .map(|entry| {
+                json!({
+                    "txid": utils::hex_string(&entry.txid),
+                    "height": entry.height,
+                    "confirmations": height - entry.height + 1,
+                    "address": entry.address,
+                    "amount": entry.amount,
+                    "watch_only": wallets.is_watch_only(&entry.address),
+                })
+            })
+            .collect();
+        if self.json {
+            self.print_json(Value::Array(entries));
+            return;
+        }
+        for entry in entries {
+            println!(
+                "{} {} {} {:+}{}",
+                entry["height"],
+                entry["txid"].as_str().unwrap(),
+                entry["address"].as_str().unwrap(),
+                entry["amount"].as_i64().unwrap(),
+                if entry["watch_only"] == json!(true) { " (watch-only)" } else { "" }
+            );
+        }
+    }
+
+    /// Pays every `(address, amount)` of `recipients` in one transaction
+    /// spending `inputs`, or else outputs chosen from the `from` addresses
+    /// or the whole wallet. See [`SendMode`] for what happens to the
+    /// transaction.
+    pub fn send(
+        &self,
+        from: &[String],
+        inputs: &[(Vec<u8>, usize)],
+        recipients: &[(String, i32)],
+        change_address: Option<&str>,
+        selector: &CoinSelector,
+        mode: SendMode,
+    ) {
+        let version = self.config.params().address_version;
+        let addresses = from
+            .iter()
+            .chain(recipients.iter().map(|(address, _)| address))
+            .map(String::as_str)
+            .chain(change_address);
         for address in addresses {
-            println!("{}", address);
+            if !wallet::validate_address(address, version) {
+                self.error(format!("Invalid address {}", address));
+                return;
+            }
+        }
+
+        let mut blockchain = Blockchain::new(&self.config, "");
+        let mut wallets = Wallets::new(&self.config);
+        wallets.sync(&blockchain);
+        let create = if mode == SendMode::Unsigned {
+            transaction::new_unsigned_transaction
+        } else {
+            transaction::new_transaction
+        };
+        let transaction = match create(
+            recipients,
+            from,
+            inputs,
+            change_address,
+            selector,
+            &blockchain,
+            &wallets,
+        ) {
+            Ok(transaction) => transaction,
+            Err(e) => {
+                self.error(format!("ERROR: {}", e));
+                return;
+            }
+        };
+        let txid = transaction.id.clone();
+        // change below the dust threshold, which no block mined here claims
+        let fee = transaction.fee(&blockchain).unwrap_or(0);
+        if mode == SendMode::Unsigned {
+            wallets.save_to_file();
+            let hex = utils::hex_string(&encode::serialize(&transaction));
+            if self.json {
+                self.print_json(json!({ "txid": utils::hex_string(&txid), "hex": hex, "fee": fee }));
+            } else {
+                println!("{}", hex);
+            }
+            return;
+        }
+        wallets.add_pending(transaction);
+        if mode == SendMode::Pending {
+            wallets.save_to_file();
+            if self.json {
+                self.print_json(json!({ "txid": utils::hex_string(&txid), "block_hash": null, "fee": fee }));
+            } else {
+                println!("Pending transaction {}", utils::hex_string(&txid));
+                print_fee(fee);
+            }
+            return;
+        }
+
+        let Some(block_hash) = blockchain.mine_block(wallets.pending_transactions().to_vec()) else {
+            wallets.save_to_file();
+            self.error("Failed to mine the transaction");
+            return;
+        };
+        wallets.sync(&blockchain);
+        wallets.save_to_file();
+        if self.json {
+            self.print_json(json!({
+                "txid": utils::hex_string(&txid),
+                "block_hash": utils::hex_string(&block_hash),
+                "fee": fee,
+            }));
+        } else {
+            println!("Success!");
+            print_fee(fee);
         }
     }
 
-    pub fn send(&self, from: &str, to: &str, amount: i32) {
-        if !wallet::validate_address(from) {
-            eprintln!("Invalid address");
+    /// Lists the wallet's unspent outputs, or those paying `address`.
+    pub fn list_unspent(&self, address: Option<&str>) {
+        if let Some(address) = address {
+            if !wallet::validate_address(address, self.config.params().address_version) {
+                self.error("Invalid address");
+                return;
+            }
+        }
+
+        let blockchain = Blockchain::new(&self.config, "");
+        let mut wallets = Wallets::new(&self.config);
+        wallets.sync(&blockchain);
+        wallets.save_to_file();
+        let height = wallets.synced_height().unwrap_or(0);
+        let coins: Vec<Value> = wallets
+            .unspent_coins()
+            .into_iter()
+            .filter(|coin| address.is_none_or(|address| coin.address == address))
+            .map(|coin| {
+                json!({
+                    "txid": utils::hex_string(&coin.txid),
+                    "vout": coin.vout,
+                    "address": coin.address,
+                    "amount": coin.output.value,
+                    "confirmations": height - coin.height + 1,
+                    "locked": wallets.is_locked(&coin.txid, coin.vout),
+                })
+            })
+            .collect();
+        if self.json {
+            self.print_json(Value::Array(coins));
             return;
         }
-        if !wallet::validate_address(to) {
-            eprintln!("Invalid address");
+        for coin in coins {
+            println!(
+                "{}:{} {} {} confirmations={}{}",
+                coin["txid"].as_str().unwrap(),
+                coin["vout"],
+                coin["address"].as_str().unwrap(),
+                coin["amount"],
+                coin["confirmations"],
+                if coin["locked"] == json!(true) { " locked" } else { "" }
+            );
+        }
+    }
+
+    /// Locks or unlocks `outpoints` for automatic coin selection. Locks are
+    /// kept in the wallet file.
+    pub fn lock_unspent(&self, outpoints: &[(Vec<u8>, usize)], lock: bool) {
+        let blockchain = Blockchain::new(&self.config, "");
+        let mut wallets = Wallets::new(&self.config);
+        wallets.sync(&blockchain);
+        for (txid, vout) in outpoints {
+            let done = if lock {
+                wallets.lock_coin(txid, *vout)
+            } else {
+                wallets.unlock_coin(txid, *vout)
+            };
+            if !done {
+                let reason = if lock { "not an unspent wallet output" } else { "not locked" };
+                self.error(format!("{}:{} is {}", utils::hex_string(txid), vout, reason));
+                return;
+            }
+        }
+        wallets.save_to_file();
+
+        let outpoints: Vec<String> = outpoints
+            .iter()
+            .map(|(txid, vout)| format!("{}:{}", utils::hex_string(txid), vout))
+            .collect();
+        if self.json {
+            let key = if lock { "locked" } else { "unlocked" };
+            self.print_json(json!({ key: outpoints }));
+        } else {
+            println!("{} {} outputs", if lock { "Locked" } else { "Unlocked" }, outpoints.len());
+        }
+    }
+
+    pub fn generate(&self, count: usize, address: Option<&str>) {
+        let address = match address {
+            Some(address) => address.to_string(),
+            None => {
+                let mut wallets = Wallets::new(&self.config);
+                let address = wallets.create_wallet();
+                wallets.save_to_file();
+                address
+            }
+        };
+        if !wallet::validate_address(&address, self.config.params().address_version) {
+            self.error("Invalid address");
             return;
         }
 
-        let mut blockchain = Blockchain::new(from);
-        let transaction = transaction::new_utxo_transaction(from, to, amount, &blockchain);
-        blockchain.mine_block(vec![transaction]);
-        println!("Success!");
+        let mut blockchain = Blockchain::new(&self.config, &address);
+        // the wallet's pending transactions go into the first block
+        let mut wallets = Wallets::new(&self.config);
+        wallets.sync(&blockchain);
+        let pending = wallets.pending_transactions().to_vec();
+        let hashes: Vec<String> = blockchain
+            .generate(count, &address, pending)
+            .iter()
+            .map(|hash| utils::hex_string(hash))
+            .collect();
+        if !wallets.get_addresses().is_empty() {
+            wallets.sync(&blockchain);
+            wallets.save_to_file();
+        }
+        if self.json {
+            self.print_json(json!({ "address": address, "blocks": hashes }));
+            return;
+        }
+        for hash in hashes {
+            println!("{}", hash);
+        }
     }
 
-    pub fn print_chain(&self) {
-        let blockchain = Blockchain::new("");
-        blockchain.print_chain();
-    }
-
-    fn delete_files_and_folders(directory: std::fs::ReadDir, file_ext: &str, folder_name: &str) {
-        for entry in directory {
-            if let Ok(entry) = entry {
-                let path = entry.path();
-                if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some(file_ext) {
-                    // Attempt to delete the file and ignore the result
-                    let _ = std::fs::remove_file(&path);
-                } else if path.is_dir()
-                    && path.file_name().and_then(|s| s.to_str()) == Some(folder_name)
+    /// Downloads headers from the full node at `node_dir` (or our own data
+    /// directory), then scans the blocks whose compact filters match our
+    /// wallet for its transactions.
+    pub fn spv_sync(&self, node_dir: Option<&str>) {
+        let node_config = match node_dir {
+            Some(dir) => {
+                let dir = PathBuf::from(dir);
+                let builder = match Config::builder()
+                    .data_dir(&dir)
+                    .config_file_if_exists(&dir.join(config::CONFIG_FILE))
                 {
-                    // Attempt to delete the folder and ignore the result
-                    let _ = std::fs::remove_dir_all(&path);
+                    Ok(builder) => builder,
+                    Err(e) => {
+                        self.error(format!("Invalid node configuration: {}", e));
+                        return;
+                    }
+                };
+                builder.network(self.config.network()).build()
+            }
+            None => self.config.clone(),
+        };
+        let node = Blockchain::new(&node_config, "");
+
+        let mut client = match SpvClient::new(&self.config) {
+            Ok(client) => client,
+            Err(e) => {
+                self.error(format!("Failed to open the light client: {}", e));
+                return;
+            }
+        };
+        let headers = match client.sync_headers(&node) {
+            Ok(headers) => headers,
+            Err(e) => {
+                self.error(format!("Header sync failed: {}", e));
+                return;
+            }
+        };
+
+        let wallets = Wallets::new(&self.config);
+        let mut addresses = wallets.get_addresses();
+        addresses.extend(wallets.get_watch_only_addresses());
+        let pub_key_hashes: Vec<Vec<u8>> = addresses
+            .iter()
+            .map(|address| {
+                let payload = utils::base58_decode(address);
+                payload[1..payload.len() - wallet::CHECKSUM_LENGTH].to_vec()
+            })
+            .collect();
+        let txs = match client.scan_blocks(&node, &pub_key_hashes) {
+            Ok(txs) => txs,
+            Err(e) => {
+                self.error(format!("Transaction sync failed: {}", e));
+                return;
+            }
+        };
+        let height = match client.get_best_height() {
+            Ok(height) => height,
+            Err(e) => {
+                self.error(format!("Failed to read the header chain: {}", e));
+                return;
+            }
+        };
+        if self.json {
+            self.print_json(json!({
+                "headers": headers,
+                "height": height,
+                "transactions": txs,
+            }));
+        } else {
+            println!(
+                "Synced {} headers, height {}, {} wallet transactions",
+                headers,
+                height.unwrap_or(0),
+                txs
+            );
+        }
+    }
+
+    /// Prints a transaction as JSON. Fees and signature checks need the
+    /// spent outputs, so they are only shown if a local chain has them.
+    pub fn decode_raw_transaction(&self, raw: &str) {
+        let tx = match hex::decode(raw.trim()) {
+            Ok(bytes) => encode::deserialize(&bytes),
+            Err(e) => {
+                self.error(format!("Invalid hex: {}", e));
+                return;
+            }
+        };
+        match tx {
+            Ok(tx) => {
+                let chain = Blockchain::open(&self.config);
+                let value =
+                    json::transaction(&tx, chain.as_ref(), self.config.params().address_version);
+                self.print_json(value);
+            }
+            Err(e) => self.error(format!("Invalid transaction: {}", e)),
+        }
+    }
+
+    /// Prints a block given either serialized or by its hash as JSON.
+    pub fn decode_block(&self, block: &str) {
+        let bytes = match hex::decode(block.trim()) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                self.error(format!("Invalid hex: {}", e));
+                return;
+            }
+        };
+        let chain = Blockchain::open(&self.config);
+        let block = if bytes.len() == 32 {
+            match chain.as_ref().and_then(|chain| chain.get_block(&bytes)) {
+                Some(block) => block,
+                None => {
+                    self.error("Block not found");
+                    return;
+                }
+            }
+        } else {
+            match Block::deserialize(&bytes) {
+                Ok(block) => block,
+                Err(e) => {
+                    self.error(format!("Invalid block: {}", e));
+                    return;
                 }
             }
+        };
+        let value = json::block(&block, chain.as_ref(), self.config.params().address_version);
+        self.print_json(value);
+    }
+
+    pub fn print_chain(&self) {
+        let blockchain = Blockchain::new(&self.config, "");
+        if self.json {
+            let version = self.config.params().address_version;
+            let blocks: Vec<Value> = blockchain
+                .iterator()
+                .map(|block| json::block(&block, Some(&blockchain), version))
+                .collect();
+            self.print_json(Value::Array(blocks));
+        } else {
+            blockchain.print_chain();
         }
     }
+
+    /// Disconnects a block and the blocks above it, keeps it out of the
+    /// chain and brings the wallet up to date.
+    pub fn invalidate_block(&self, hash: &[u8]) {
+        let Some(mut blockchain) = Blockchain::open(&self.config) else {
+            self.error("There is no blockchain");
+            return;
+        };
+        match blockchain.invalidate_block(hash) {
+            Ok(blocks) => {
+                let hashes: Vec<Vec<u8>> = blocks.iter().map(Block::hash).collect();
+                self.report_chain_change(&blockchain, "Disconnected", &hashes);
+            }
+            Err(e) => self.error(format!("Failed to invalidate the block: {}", e)),
+        }
+    }
+
+    /// Lets an invalidated block back into the chain and brings the wallet
+    /// up to date.
+    pub fn reconsider_block(&self, hash: &[u8]) {
+        let Some(mut blockchain) = Blockchain::open(&self.config) else {
+            self.error("There is no blockchain");
+            return;
+        };
+        match blockchain.reconsider_block(hash) {
+            Ok(hashes) => self.report_chain_change(&blockchain, "Connected", &hashes),
+            Err(e) => self.error(format!("Failed to reconsider the block: {}", e)),
+        }
+    }
+
+    /// Syncs the wallet with `blockchain` after the blocks `hashes` were
+    /// connected or disconnected, as `verb` says, and prints them with the
+    /// new tip.
+    fn report_chain_change(&self, blockchain: &Blockchain, verb: &str, hashes: &[Vec<u8>]) {
+        let mut wallets = Wallets::new(&self.config);
+        if !wallets.get_addresses().is_empty() || !wallets.get_watch_only_addresses().is_empty() {
+            wallets.sync(blockchain);
+            wallets.save_to_file();
+        }
+
+        let hashes: Vec<String> = hashes.iter().map(|hash| utils::hex_string(hash)).collect();
+        let tip = utils::hex_string(&blockchain.tip);
+        let height = blockchain.get_best_height();
+        if self.json {
+            self.print_json(json!({ verb.to_lowercase(): hashes, "tip": tip, "height": height }));
+        } else {
+            println!("{} {} blocks, tip {} at height {}", verb, hashes.len(), tip, height);
+        }
+    }
+
+    /// Reports the damaged records and inconsistent indexes of the chain
+    /// database and, with `repair`, removes them.
+    pub fn check_db(&self, repair: bool) {
+        if !self.config.db_path().exists() {
+            self.error("There is no blockchain");
+            return;
+        }
+        let mut db = match BlockchainDb::from_config(&self.config) {
+            Ok(db) => db,
+            Err(e) => {
+                self.error(format!("Failed to open the database: {}", e));
+                return;
+            }
+        };
+        let report = match db.check() {
+            Ok(report) => report,
+            Err(e) => {
+                self.error(format!("Check failed: {}", e));
+                return;
+            }
+        };
+        let repairs = if repair {
+            match db.repair() {
+                Ok(repairs) => repairs,
+                Err(e) => {
+                    self.error(format!("Repair failed: {}", e));
+                    return;
+                }
+            }
+        } else {
+            Vec::new()
+        };
+        let tip = db.read_tip().ok().flatten().map(|tip| utils::hex_string(&tip));
+        let height = db.read_tip_height().ok().flatten();
+
+        if self.json {
+            let faults: Vec<Value> = report
+                .faults
+                .iter()
+                .map(|fault| {
+                    json!({
+                        "kind": fault.kind.to_string(),
+                        "hash": fault.hash.as_ref().map(|hash| utils::hex_string(hash)),
+                        "detail": fault.detail,
+                    })
+                })
+                .collect();
+            let mut value = json!({ "blocks": report.blocks, "faults": faults, "tip": tip, "height": height });
+            if repair {
+                value["repairs"] = json!(repairs);
+            }
+            self.print_json(value);
+            return;
+        }
+        for fault in &report.faults {
+            println!("{}", fault);
+        }
+        println!("Checked {} blocks, found {} faults", report.blocks, report.faults.len());
+        for repair in &repairs {
+            println!("Repaired: {}", repair);
+        }
+        if repair {
+            match (tip, height) {
+                (Some(tip), Some(height)) => println!("Tip {} at height {}", tip, height),
+                _ => println!("No valid block is left"),
+            }
+        }
+    }
+
+    pub fn export_chain(&self, path: &str) {
+        let Some(blockchain) = Blockchain::open(&self.config) else {
+            self.error("There is no blockchain to export");
+            return;
+        };
+        let result = File::create(path)
+            .map_err(Into::into)
+            .and_then(|file| bootstrap::export_chain(&blockchain, BufWriter::new(file)));
+        match result {
+            Ok(blocks) if self.json => self.print_json(json!({ "blocks": blocks, "file": path })),
+            Ok(blocks) => println!("Exported {} blocks to {}", blocks, path),
+            Err(e) => self.error(format!("Export failed: {}", e)),
+        }
+    }
+
+    /// Connects the blocks of a file written by `exportchain`, starting a
+    /// new chain if there is none.
+    pub fn import_chain(&self, path: &str) {
+        let result = File::open(path)
+            .map_err(Into::into)
+            .and_then(|file| bootstrap::ChainFileReader::new(BufReader::new(file)))
+            .and_then(|blocks| {
+                let db = BlockchainDb::from_config(&self.config)?;
+                bootstrap::import_chain(db, self.config.params().clone(), blocks)
+            });
+        match result {
+            Ok((blockchain, summary)) if self.json => self.print_json(json!({
+                "connected": summary.connected,
+                "skipped": summary.skipped,
+                "height": blockchain.get_best_height(),
+                "tip": utils::hex_string(&blockchain.tip),
+            })),
+            Ok((blockchain, summary)) => println!(
+                "Imported {} blocks ({} already known), height {}",
+                summary.connected,
+                summary.skipped,
+                blockchain.get_best_height()
+            ),
+            Err(e) => self.error(format!("Import failed: {}", e)),
+        }
+    }
+
+    pub fn dump_txout_set(&self, path: &str) {
+        let Some(blockchain) = Blockchain::open(&self.config) else {
+            self.error("There is no blockchain to dump");
+            return;
+        };
+        let snapshot = match UtxoSnapshot::from_chain(&blockchain) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                self.error(format!("Failed to read the UTXO set: {}", e));
+                return;
+            }
+        };
+        if let Err(e) = File::create(path).and_then(|file| snapshot.write_to(BufWriter::new(file))) {
+            self.error(format!("Failed to write {}: {}", path, e));
+            return;
+        }
+        self.print_snapshot(&snapshot);
+    }
+
+    /// Starts an empty chain from a UTXO snapshot whose commitment matches
+    /// `trusted_hash`.
+    pub fn load_txout_set(&self, path: &str, trusted_hash: &str) {
+        let snapshot = match File::open(path)
+            .map_err(Into::into)
+            .and_then(|file| UtxoSnapshot::read_from(BufReader::new(file)))
+        {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                self.error(format!("Failed to read {}: {}", path, e));
+                return;
+            }
+        };
+        let hash = utils::hex_string(&snapshot.commitment());
+        if !hash.eq_ignore_ascii_case(trusted_hash.trim()) {
+            self.error(format!("The UTXO set hash is {}, not the trusted {}", hash, trusted_hash));
+            return;
+        }
+        let result = BlockchainDb::from_config(&self.config)
+            .and_then(|db| snapshot.load_into(db, self.config.params().clone()));
+        let blockchain = match result {
+            Ok(blockchain) => blockchain,
+            Err(e) => {
+                self.error(format!("Failed to load the UTXO set: {}", e));
+                return;
+            }
+        };
+        // the wallet starts from its outputs in the snapshot
+        let mut wallets = Wallets::new(&self.config);
+        if !wallets.get_addresses().is_empty() || !wallets.get_watch_only_addresses().is_empty() {
+            wallets.rescan(&blockchain);
+            wallets.save_to_file();
+        }
+        self.print_snapshot(&snapshot);
+    }
+
+    fn print_snapshot(&self, snapshot: &UtxoSnapshot) {
+        let base = utils::hex_string(&snapshot.base_hash());
+        let hash = utils::hex_string(&snapshot.commitment());
+        if self.json {
+            self.print_json(json!({
+                "base_hash": base,
+                "height": snapshot.height(),
+                "coins": snapshot.coins.len(),
+                "hash": hash,
+            }));
+        } else {
+            println!("Block {} at height {}", base, snapshot.height());
+            println!("{} coins, hash {}", snapshot.coins.len(), hash);
+        }
+    }
+
+    /// Deletes the chain database and the selected wallet file, or every
+    /// wallet file with `all_wallets`.
+    fn clear(&self, all_wallets: bool) {
+        // Attempt to delete everything and ignore the result
+        // a LevelDB directory or a redb file
+        let db_path = self.config.db_path();
+        let _ = std::fs::remove_dir_all(db_path).or_else(|_| std::fs::remove_file(db_path));
+        let _ = std::fs::remove_dir_all(self.config.blocks_dir());
+        let _ = std::fs::remove_file(self.config.wallet_path());
+        if all_wallets {
+            let _ = std::fs::remove_file(self.config.network_dir().join(config::WALLET_FILE));
+            let _ = std::fs::remove_dir_all(self.config.wallets_dir());
+        }
+        if all_wallets || self.config.default_wallet().as_deref() == self.config.wallet_name() {
+            let _ = self.config.set_default_wallet(None);
+        }
+        if self.json {
+            self.print_json(json!({ "cleared": true }));
+        }
+    }
+
+    fn print_json(&self, value: Value) {
+        println!("{}", serde_json::to_string_pretty(&value).unwrap());
+    }
+
+    fn error<S: AsRef<str>>(&self, message: S) {
+        print_error(self.json, message.as_ref());
+    }
+}
+
+/// What `send` does with the transaction it creates.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SendMode {
+    /// Sign it and mine a block holding it and other pending transactions.
+    Mine,
+    /// Sign it and keep it pending until the next `generate`.
+    Pending,
+    /// Print it without signing it.
+    Unsigned,
+}
+
+impl SendMode {
+    fn from_flags(no_mine: bool, unsigned: bool) -> Self {
+        if unsigned {
+            SendMode::Unsigned
+        } else if no_mine {
+            SendMode::Pending
+        } else {
+            SendMode::Mine
+        }
+    }
+}
+
+/// Parses the hex hash of a block.
+fn parse_block_hash(hash: &str) -> Option<Vec<u8>> {
+    hex::decode(hash).ok().filter(|hash| hash.len() == 32)
+}
+
+/// Parses an outpoint written as `TXID:VOUT`.
+fn parse_outpoint(outpoint: &str) -> Option<(Vec<u8>, usize)> {
+    let (txid, vout) = outpoint.rsplit_once(':')?;
+    let txid = hex::decode(txid).ok().filter(|txid| txid.len() == 32)?;
+    Some((txid, vout.parse().ok()?))
+}
+
+/// Set once an error has been reported, so that the process exits with a
+/// failure status.
+static FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Returns true if the command reported an error.
+pub fn failed() -> bool {
+    FAILED.load(Ordering::Relaxed)
+}
+
+/// Reports an error on stderr, or as a JSON document on stdout so that
+/// scripts always get one document to parse.
+fn print_error(json: bool, message: &str) {
+    FAILED.store(true, Ordering::Relaxed);
+    if json {
+        println!("{}", json!({ "error": message }));
+    } else {
+        eprintln!("{}", message);
+    }
+}
+
+/// Tells the user about change too small for its own output. Nothing
+/// claims it as a fee, so it is lost.
+fn print_fee(fee: i64) {
+    if fee > 0 {
+        println!("{} left as fee and not claimed by any block", fee);
+    }
 }
@@ -0,0 +1,365 @@
+//! Coin selection: choosing which unspent outputs fund a payment.
+//!
+//! Strategies work on plain output values and return the indices of the
+//! values they pick, so they can be used for any kind of candidate.
+//! Change below the dust threshold is not worth an output; it is left out
+//! of the transaction instead.
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use std::fmt;
+use std::str::FromStr;
+
+/// Smallest change that gets its own output by default.
+pub const DEFAULT_DUST_THRESHOLD: i32 = 1;
+/// Number of branches branch-and-bound explores before giving up.
+const BNB_MAX_TRIES: usize = 100_000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Spend the biggest outputs first, which keeps the number of inputs low.
+    #[default]
+    LargestFirst,
+    /// Spend the smallest outputs first, which consolidates small outputs.
+    SmallestFirst,
+    /// Search for a set of outputs that needs no change, falling back to
+    /// largest-first if there is none.
+    BranchAndBound,
+    /// Spend outputs in random order, so the choice reveals less about the wallet.
+    Random,
+}
+
+impl Strategy {
+    pub const ALL: [Strategy; 4] = [
+        Strategy::LargestFirst,
+        Strategy::SmallestFirst,
+        Strategy::BranchAndBound,
+        Strategy::Random,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Strategy::LargestFirst => "largest-first",
+            Strategy::SmallestFirst => "smallest-first",
+            Strategy::BranchAndBound => "branch-and-bound",
+            Strategy::Random => "random",
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "largest-first" | "largest" => Ok(Strategy::LargestFirst),
+            "smallest-first" | "smallest" => Ok(Strategy::SmallestFirst),
+            "branch-and-bound" | "bnb" => Ok(Strategy::BranchAndBound),
+            "random" => Ok(Strategy::Random),
+            _ => Err(format!("unknown coin selection strategy `{}`", s)),
+        }
+    }
+}
+
+/// The outputs chosen for a payment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Selection {
+    /// Indices of the chosen values.
+    pub selected: Vec<usize>,
+    /// Sum of the chosen values.
+    pub total: i64,
+    /// Change to pay back, zero if none is needed.
+    pub change: i32,
+    /// Excess below the dust threshold that is left out of the outputs.
+    pub dust: i32,
+}
+
+/// Chooses outputs with a [`Strategy`].
+#[derive(Clone, Copy, Debug)]
+pub struct CoinSelector {
+    strategy: Strategy,
+    dust_threshold: i32,
+}
+
+impl Default for CoinSelector {
+    fn default() -> Self {
+        CoinSelector::new(Strategy::default())
+    }
+}
+
+impl CoinSelector {
+    pub fn new(strategy: Strategy) -> Self {
+        CoinSelector {
+            strategy,
+            dust_threshold: DEFAULT_DUST_THRESHOLD,
+        }
+    }
+
+    /// Sets the smallest change that gets its own output.
+    pub fn dust_threshold(mut self, dust_threshold: i32) -> Self {
+        self.dust_threshold = dust_threshold.max(1);
+        self
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Picks values adding up to at least `target`, or `None` if they are
+    /// not enough.
+    pub fn select(&self, values: &[i32], target: i32) -> Option<Selection> {
+        self.select_with_rng(values, target, &mut rand::thread_rng())
+    }
+
+    /// Like [`CoinSelector::select`], drawing random choices from `rng`.
+    pub fn select_with_rng<R: Rng>(
+        &self,
+        values: &[i32],
+        target: i32,
+        rng: &mut R,
+    ) -> Option<Selection> {
+        let mut order: Vec<usize> = (0..values.len()).filter(|&i| values[i] > 0).collect();
+        let selected = match self.strategy {
+            Strategy::LargestFirst => {
+                order.sort_by_key(|&i| std::cmp::Reverse(values[i]));
+                accumulate(values, &order, target)
+            }
+            Strategy::SmallestFirst => {
+                order.sort_by_key(|&i| values[i]);
+                accumulate(values, &order, target)
+            }
+            Strategy::BranchAndBound => {
+                order.sort_by_key(|&i| std::cmp::Reverse(values[i]));
+                branch_and_bound(values, &order, target, self.dust_threshold)
+                    .or_else(|| accumulate(values, &order, target))
+            }
+            Strategy::Random => {
+                order.shuffle(rng);
+                accumulate(values, &order, target)
+            }
+        }?;
+
+        Some(self.finish(values, selected, target))
+    }
+
+    /// Uses every value, for outputs chosen by hand. Returns `None` if
+    /// they are not enough.
+    pub fn select_all(&self, values: &[i32], target: i32) -> Option<Selection> {
+        let total: i64 = values.iter().map(|&value| value as i64).sum();
+        (total >= target as i64).then(|| self.finish(values, (0..values.len()).collect(), target))
+    }
+
+    /// Splits the excess of `selected` over `target` into change or dust.
+    fn finish(&self, values: &[i32], selected: Vec<usize>, target: i32) -> Selection {
+        let total: i64 = selected.iter().map(|&i| values[i] as i64).sum();
+        let excess = (total - target as i64) as i32;
+        let (change, dust) = if excess < self.dust_threshold {
+            (0, excess)
+        } else {
+            (excess, 0)
+        };
+        Selection {
+            selected,
+            total,
+            change,
+            dust,
+        }
+    }
+}
+
+/// Takes values in `order` until they reach `target`.
+fn accumulate(values: &[i32], order: &[usize], target: i32) -> Option<Vec<usize>> {
+    let mut selected = Vec::new();
+    let mut total: i64 = 0;
+    for &i in order {
+        if total >= target as i64 {
+            break;
+        }
+        total += values[i] as i64;
+        selected.push(i);
+    }
+    (total >= target as i64).then_some(selected)
+}
+
+/// Depth-first search for values summing to within `dust_threshold` above
+/// `target`, so that no change output is needed. `order` must be sorted by
+/// decreasing value.
+fn branch_and_bound(
+    values: &[i32],
+    order: &[usize],
+    target: i32,
+    dust_threshold: i32,
+) -> Option<Vec<usize>> {
+    let target = target as i64;
+    let upper = target + dust_threshold as i64;
+    // remaining[k] is the sum of the values from position k on
+    let mut remaining = vec![0i64; order.len() + 1];
+    for k in (0..order.len()).rev() {
+        remaining[k] = remaining[k + 1] + values[order[k]] as i64;
+    }
+
+    let mut tries = 0;
+    let mut chosen = Vec::new();
+    fn search(
+        k: usize,
+        total: i64,
+        ctx: (&[i32], &[usize], &[i64], i64, i64),
+        chosen: &mut Vec<usize>,
+        tries: &mut usize,
+    ) -> bool {
+        let (values, order, remaining, target, upper) = ctx;
+        *tries += 1;
+        if total >= target {
+            return total < upper;
+        }
+        if k == order.len() || total + remaining[k] < target || *tries > BNB_MAX_TRIES {
+            return false;
+        }
+        let value = values[order[k]] as i64;
+        if total + value < upper {
+            chosen.push(order[k]);
+            if search(k + 1, total + value, ctx, chosen, tries) {
+                return true;
+            }
+            chosen.pop();
+        }
+        search(k + 1, total, ctx, chosen, tries)
+    }
+
+    let ctx = (values, order, remaining.as_slice(), target, upper);
+    search(0, 0, ctx, &mut chosen, &mut tries).then_some(chosen)
+}
+
+/// Outcome of running a strategy over a series of payments.
+#[derive(Clone, Debug, Default)]
+pub struct SimulationReport {
+    pub payments: usize,
+    /// Payments the wallet could not fund.
+    pub failures: usize,
+    /// Payments that needed no change output.
+    pub changeless: usize,
+    pub inputs: usize,
+    /// Value lost to change below the dust threshold.
+    pub dust: i64,
+    /// Outputs left in the wallet at the end.
+    pub final_utxos: usize,
+    /// Outputs left that are below the dust threshold.
+    pub final_dust_utxos: usize,
+}
+
+impl SimulationReport {
+    pub fn average_inputs(&self) -> f64 {
+        let funded = self.payments - self.failures;
+        if funded == 0 {
+            0.0
+        } else {
+            self.inputs as f64 / funded as f64
+        }
+    }
+}
+
+/// A wallet holding `utxos` makes each payment in `payments` in turn, adding
+/// its change back to the wallet. The same `seed` gives the same run.
+pub fn simulate(
+    selector: &CoinSelector,
+    utxos: &[i32],
+    payments: &[i32],
+    seed: u64,
+) -> SimulationReport {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut wallet = utxos.to_vec();
+    let mut report = SimulationReport {
+        payments: payments.len(),
+        ..Default::default()
+    };
+
+    for &amount in payments {
+        let Some(selection) = selector.select_with_rng(&wallet, amount, &mut rng) else {
+            report.failures += 1;
+            continue;
+        };
+        report.inputs += selection.selected.len();
+        report.dust += selection.dust as i64;
+        if selection.change == 0 {
+            report.changeless += 1;
+        }
+        let mut selected = selection.selected;
+        selected.sort_unstable_by(|a, b| b.cmp(a));
+        for i in selected {
+            wallet.swap_remove(i);
+        }
+        if selection.change > 0 {
+            wallet.push(selection.change);
+        }
+    }
+
+    report.final_utxos = wallet.len();
+    report.final_dust_utxos = wallet
+        .iter()
+        .filter(|&&value| value < selector.dust_threshold)
+        .count();
+    report
+}
+
+/// Builds a synthetic wallet of `count` outputs with values spread over
+/// several orders of magnitude, the way received payments tend to be.
+pub fn synthetic_utxos(count: usize, seed: u64) -> Vec<i32> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..count)
+        .map(|_| {
+            let magnitude = rng.gen_range(0..5);
+            rng.gen_range(1..=10) * 10i32.pow(magnitude)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strategies_pick_expected_outputs() {
+        let values = [5, 40, 10, 25, 3];
+
+        let largest = CoinSelector::new(Strategy::LargestFirst).select(&values, 45).unwrap();
+        assert_eq!(largest.selected, vec![1, 3]);
+        assert_eq!(largest.change, 20);
+
+        let smallest = CoinSelector::new(Strategy::SmallestFirst).select(&values, 15).unwrap();
+        assert_eq!(smallest.selected, vec![4, 0, 2]);
+        assert_eq!(smallest.change, 3);
+
+        let bnb = CoinSelector::new(Strategy::BranchAndBound).select(&values, 45).unwrap();
+        assert_eq!(bnb.total, 45);
+        assert_eq!(bnb.change, 0);
+
+        let dusty = CoinSelector::new(Strategy::BranchAndBound)
+            .dust_threshold(4)
+            .select(&values, 47)
+            .unwrap();
+        assert_eq!((dusty.total, dusty.change, dusty.dust), (50, 0, 3));
+
+        let random = CoinSelector::new(Strategy::Random).select(&values, 83).unwrap();
+        assert_eq!(random.total, 83);
+        assert!(CoinSelector::new(Strategy::Random).select(&values, 84).is_none());
+    }
+
+    #[test]
+    fn simulation_is_reproducible() {
+        let utxos = synthetic_utxos(100, 1);
+        let payments: Vec<i32> = synthetic_utxos(50, 2).iter().map(|v| v / 2 + 1).collect();
+        for strategy in Strategy::ALL {
+            let selector = CoinSelector::new(strategy).dust_threshold(5);
+            let first = simulate(&selector, &utxos, &payments, 7);
+            let second = simulate(&selector, &utxos, &payments, 7);
+            assert_eq!(first.inputs, second.inputs, "{}", strategy);
+            assert_eq!(first.final_utxos, second.final_utxos, "{}", strategy);
+            assert_eq!(first.payments, 50);
+        }
+    }
+}
@@ -0,0 +1,518 @@
+use crate::network::{ChainParams, Network};
+use crate::store::Backend;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DB_FILE: &str = "blockchain.db";
+pub const WALLET_FILE: &str = "wallets.dat";
+/// Directory of the named wallets, one `<name>.dat` file each.
+pub const WALLETS_DIR: &str = "wallets";
+/// File naming the wallet used when none is given, written by `loadwallet`.
+pub const DEFAULT_WALLET_FILE: &str = "default_wallet";
+pub const SPV_DB_FILE: &str = "spv.db";
+/// Directory of the `blkNNNNN.dat` block files.
+pub const BLOCKS_DIR: &str = "blocks";
+pub const CONFIG_FILE: &str = "bitcoin.toml";
+/// Smallest prune size in MiB; `0` turns pruning off.
+pub const MIN_PRUNE_MB: u64 = 550;
+const DATA_DIR_NAME: &str = ".bitcoin-rs";
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Settings of a node: the network it runs on, its chain parameters and the
+/// locations of its on-disk state.
+#[derive(Clone, Debug)]
+pub struct Config {
+    data_dir: PathBuf,
+    params: ChainParams,
+    db_path: PathBuf,
+    backend: Backend,
+    prune: Option<u64>,
+    wallet_name: Option<String>,
+    wallet_path: PathBuf,
+}
+
+impl Config {
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// Root data directory, shared by all networks.
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// Directory holding the files of the selected network.
+    pub fn network_dir(&self) -> PathBuf {
+        self.data_dir.join(self.params.data_subdir)
+    }
+
+    pub fn network(&self) -> Network {
+        self.params.network
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// Path of the database holding the chain.
+    pub fn db_path(&self) -> &Path {
+        &self.db_path
+    }
+
+    /// Storage engine of the chain databases.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Directory holding the block files.
+    pub fn blocks_dir(&self) -> PathBuf {
+        self.network_dir().join(BLOCKS_DIR)
+    }
+
+    /// Size in MiB the block files are pruned down to, if pruning.
+    pub fn prune(&self) -> Option<u64> {
+        self.prune
+    }
+
+    /// Same as [`Config::prune`], in bytes.
+    pub fn prune_target(&self) -> Option<u64> {
+        self.prune.map(|mb| mb.saturating_mul(1024 * 1024))
+    }
+
+    /// Name of the selected wallet, or `None` for the default `wallets.dat`.
+    pub fn wallet_name(&self) -> Option<&str> {
+        self.wallet_name.as_deref()
+    }
+
+    /// Path of the selected wallet file.
+    pub fn wallet_path(&self) -> &Path {
+        &self.wallet_path
+    }
+
+    /// Directory holding the named wallets.
+    pub fn wallets_dir(&self) -> PathBuf {
+        self.network_dir().join(WALLETS_DIR)
+    }
+
+    /// Path of the wallet file called `name`.
+    pub fn named_wallet_path(&self, name: &str) -> PathBuf {
+        self.wallets_dir().join(format!("{}.dat", name))
+    }
+
+    /// Returns the names of the wallets in [`Config::wallets_dir`], sorted.
+    pub fn list_wallets(&self) -> Vec<String> {
+        let Ok(entries) = fs::read_dir(self.wallets_dir()) else {
+            return Vec::new();
+        };
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != "dat" {
+                    return None;
+                }
+                Some(path.file_stem()?.to_str()?.to_string())
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Returns the same settings with the wallet called `name` selected.
+    pub fn with_wallet(&self, name: &str) -> Config {
+        Config {
+            wallet_name: Some(name.to_string()),
+            wallet_path: self.named_wallet_path(name),
+            ..self.clone()
+        }
+    }
+
+    /// Name of the wallet chosen with `loadwallet`, if any.
+    pub fn default_wallet(&self) -> Option<String> {
+        read_default_wallet(&self.network_dir())
+    }
+
+    /// Makes `name` the wallet used when none is given, or the default
+    /// `wallets.dat` again with `None`.
+    pub fn set_default_wallet(&self, name: Option<&str>) -> Result<()> {
+        let path = self.network_dir().join(DEFAULT_WALLET_FILE);
+        match name {
+            Some(name) => {
+                fs::create_dir_all(self.network_dir())?;
+                fs::write(path, format!("{}\n", name))?;
+            }
+            None if path.exists() => fs::remove_file(path)?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Path of the database holding the headers of a light client.
+    pub fn spv_db_path(&self) -> PathBuf {
+        self.network_dir().join(SPV_DB_FILE)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::builder().build()
+    }
+}
+
+/// Returns `~/.bitcoin-rs`, or the current directory if there is no home directory.
+pub fn default_data_dir() -> PathBuf {
+    match env::var_os("HOME") {
+        Some(home) if !home.is_empty() => PathBuf::from(home).join(DATA_DIR_NAME),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Builds a [`Config`].
+///
+/// Values set on the builder take precedence over the config file, which in
+/// turn overrides the defaults of the selected network. Relative file paths
+/// are resolved against the network's data directory.
+#[derive(Default)]
+pub struct ConfigBuilder {
+    data_dir: Option<PathBuf>,
+    network: Option<Network>,
+    db_path: Option<PathBuf>,
+    backend: Option<Backend>,
+    prune: Option<u64>,
+    wallet: Option<String>,
+    wallet_path: Option<PathBuf>,
+    overrides: Overrides,
+    file: ConfigFile,
+}
+
+/// Chain parameters that can be changed from their network defaults.
+#[derive(Clone, Default)]
+struct Overrides {
+    target_bits: Option<u16>,
+    subsidy: Option<i32>,
+}
+
+impl Overrides {
+    fn apply(&self, params: &mut ChainParams) {
+        if let Some(target_bits) = self.target_bits {
+            params.target_bits = target_bits;
+        }
+        if let Some(subsidy) = self.subsidy {
+            params.subsidy = subsidy;
+        }
+    }
+}
+
+impl ConfigBuilder {
+    pub fn data_dir<P: Into<PathBuf>>(mut self, data_dir: P) -> Self {
+        self.data_dir = Some(data_dir.into());
+        self
+    }
+
+    pub fn network(mut self, network: Network) -> Self {
+        self.network = Some(network);
+        self
+    }
+
+    pub fn db_path<P: Into<PathBuf>>(mut self, db_path: P) -> Self {
+        self.db_path = Some(db_path.into());
+        self
+    }
+
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Keeps only about `mb` MiB of block files, deleting the oldest. `0`
+    /// turns pruning off, and smaller sizes than [`MIN_PRUNE_MB`] are raised
+    /// to it; [`validate_prune`] rejects them instead.
+    pub fn prune(mut self, mb: u64) -> Self {
+        self.prune = Some(mb);
+        self
+    }
+
+    /// Selects the wallet called `name` in the wallets directory.
+    pub fn wallet(mut self, name: &str) -> Self {
+        self.wallet = Some(name.to_string());
+        self
+    }
+
+    pub fn wallet_path<P: Into<PathBuf>>(mut self, wallet_path: P) -> Self {
+        self.wallet_path = Some(wallet_path.into());
+        self
+    }
+
+    /// Sets the number of leading zero bits required of block hashes.
+    pub fn target_bits(mut self, target_bits: u16) -> Self {
+        self.overrides.target_bits = Some(target_bits);
+        self
+    }
+
+    pub fn subsidy(mut self, subsidy: i32) -> Self {
+        self.overrides.subsidy = Some(subsidy);
+        self
+    }
+
+    /// Reads settings from a TOML config file.
+    ///
+    /// Top-level keys apply to every network, keys inside a `[mainnet]`,
+    /// `[testnet]` or `[regtest]` table only to that network:
+    ///
+    /// ```toml
+    /// network = "regtest"
+    /// backend = "leveldb"
+    /// prune = 550
+    /// wallet = "ops"
+    /// difficulty = 12
+    ///
+    /// [regtest]
+    /// subsidy = 50
+    /// ```
+    pub fn config_file(mut self, path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        self.file = ConfigFile::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(self)
+    }
+
+    /// Reads the config file at `path` if it exists.
+    pub fn config_file_if_exists(self, path: &Path) -> Result<Self> {
+        if path.exists() {
+            self.config_file(path)
+        } else {
+            Ok(self)
+        }
+    }
+
+    pub fn build(self) -> Config {
+        let data_dir = self.data_dir.unwrap_or_else(default_data_dir);
+        let network = self.network.or(self.file.network).unwrap_or_default();
+
+        let mut params = network.params();
+        self.file.global.apply(&mut params);
+        if let Some(section) = self.file.sections.get(&network) {
+            section.apply(&mut params);
+        }
+        self.overrides.apply(&mut params);
+
+        let network_dir = data_dir.join(params.data_subdir);
+        let db_path = network_dir.join(self.db_path.unwrap_or_else(|| PathBuf::from(DB_FILE)));
+        let wallet_name = self
+            .wallet
+            .or(self.file.wallet)
+            .or_else(|| read_default_wallet(&network_dir));
+        let wallet_path = match (self.wallet_path, &wallet_name) {
+            (Some(path), _) => network_dir.join(path),
+            (None, Some(name)) => network_dir.join(WALLETS_DIR).join(format!("{}.dat", name)),
+            (None, None) => network_dir.join(WALLET_FILE),
+        };
+        Config {
+            data_dir,
+            params,
+            db_path,
+            backend: self.backend.or(self.file.backend).unwrap_or_default(),
+            prune: self
+                .prune
+                .or(self.file.prune)
+                .filter(|&mb| mb != 0)
+                .map(|mb| mb.max(MIN_PRUNE_MB)),
+            wallet_name,
+            wallet_path,
+        }
+    }
+}
+
+/// Settings read from a config file.
+#[derive(Default)]
+struct ConfigFile {
+    network: Option<Network>,
+    backend: Option<Backend>,
+    prune: Option<u64>,
+    wallet: Option<String>,
+    global: Overrides,
+    sections: HashMap<Network, Overrides>,
+}
+
+impl ConfigFile {
+    fn parse(text: &str) -> std::result::Result<ConfigFile, String> {
+        let mut file = ConfigFile::default();
+        let mut section: Option<Network> = None;
+
+        for (idx, line) in text.lines().enumerate() {
+            let line_no = idx + 1;
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let network = name
+                    .trim()
+                    .parse::<Network>()
+                    .map_err(|e| format!("line {}: {}", line_no, e))?;
+                section = Some(network);
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected `key = value`", line_no))?;
+            let key = key.trim();
+            let value = parse_value(value.trim()).map_err(|e| format!("line {}: {}", line_no, e))?;
+
+            let overrides = match section {
+                Some(network) => file.sections.entry(network).or_default(),
+                None => &mut file.global,
+            };
+            match (key, value) {
+                ("network", Value::String(name)) if section.is_none() => {
+                    file.network = Some(
+                        name.parse()
+                            .map_err(|e| format!("line {}: {}", line_no, e))?,
+                    );
+                }
+                ("backend", Value::String(name)) if section.is_none() => {
+                    file.backend = Some(
+                        name.parse()
+                            .map_err(|e| format!("line {}: {}", line_no, e))?,
+                    );
+                }
+                ("prune", Value::Integer(n)) if section.is_none() => {
+                    let mb = to_int(n, line_no, key)?;
+                    validate_prune(mb).map_err(|e| format!("line {}: {}", line_no, e))?;
+                    file.prune = Some(mb);
+                }
+                ("wallet", Value::String(name)) if section.is_none() => {
+                    validate_wallet_name(&name).map_err(|e| format!("line {}: {}", line_no, e))?;
+                    file.wallet = Some(name);
+                }
+                ("difficulty", Value::Integer(n)) => {
+                    overrides.target_bits = Some(to_int(n, line_no, key)?);
+                }
+                ("subsidy", Value::Integer(n)) => {
+                    overrides.subsidy = Some(to_int(n, line_no, key)?);
+                }
+                _ => return Err(format!("line {}: unsupported setting `{}`", line_no, key)),
+            }
+        }
+
+        Ok(file)
+    }
+}
+
+/// Checks that `mb` is `0`, which turns pruning off, or at least
+/// [`MIN_PRUNE_MB`].
+pub fn validate_prune(mb: u64) -> std::result::Result<(), String> {
+    if mb == 0 || mb >= MIN_PRUNE_MB {
+        Ok(())
+    } else {
+        Err(format!(
+            "prune size {} MiB is below the minimum of {} MiB; use 0 to turn pruning off",
+            mb, MIN_PRUNE_MB
+        ))
+    }
+}
+
+/// Checks that `name` can be used as a wallet file name.
+pub fn validate_wallet_name(name: &str) -> std::result::Result<(), String> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "invalid wallet name `{}`: use letters, digits, `-` and `_`",
+            name
+        ))
+    }
+}
+
+/// Reads the name `loadwallet` stored in `network_dir`.
+fn read_default_wallet(network_dir: &Path) -> Option<String> {
+    let name = fs::read_to_string(network_dir.join(DEFAULT_WALLET_FILE)).ok()?;
+    Some(name.trim().to_string()).filter(|name| !name.is_empty())
+}
+
+/// Value of a `key = value` line. Only the TOML scalars used by the config
+/// file are supported.
+enum Value {
+    String(String),
+    Integer(i64),
+}
+
+fn parse_value(raw: &str) -> std::result::Result<Value, String> {
+    if let Some(inner) = raw.strip_prefix('"').and_then(|r| r.strip_suffix('"')) {
+        return Ok(Value::String(inner.to_string()));
+    }
+    raw.replace('_', "")
+        .parse::<i64>()
+        .map(Value::Integer)
+        .map_err(|_| format!("invalid value `{}`", raw))
+}
+
+fn to_int<T: TryFrom<i64>>(n: i64, line_no: usize, key: &str) -> std::result::Result<T, String> {
+    T::try_from(n).map_err(|_| format!("line {}: `{}` is out of range", line_no, key))
+}
+
+/// Removes a trailing `#` comment that is not inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..idx],
+            _ => {}
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_file_overrides_network_defaults() {
+        let file = ConfigFile::parse(
+            "# node settings\nnetwork = \"regtest\"\nbackend = \"redb\"\nprune = 550\ndifficulty = 4\n\n[regtest]\nsubsidy = 50 # per block\n[testnet]\nsubsidy = 1\n",
+        )
+        .unwrap();
+        let builder = ConfigBuilder {
+            file,
+            ..Default::default()
+        };
+        let config = builder.data_dir("/tmp/node").build();
+
+        assert_eq!(config.network(), Network::Regtest);
+        assert_eq!(config.params().target_bits, 4);
+        assert_eq!(config.params().subsidy, 50);
+        assert_eq!(config.db_path(), Path::new("/tmp/node/regtest/blockchain.db"));
+        assert_eq!(config.backend(), Backend::Redb);
+        assert_eq!(config.prune_target(), Some(550 * 1024 * 1024));
+        assert_eq!(config.wallet_name(), None);
+
+        let unpruned = Config::builder().data_dir("/tmp/node").prune(0).build();
+        assert_eq!(unpruned.prune(), None);
+        assert_eq!(Config::builder().prune(1).build().prune(), Some(MIN_PRUNE_MB));
+
+        let named = Config::builder().data_dir("/tmp/node").wallet("ops").build();
+        assert_eq!(named.wallet_path(), Path::new("/tmp/node/wallets/ops.dat"));
+    }
+
+    #[test]
+    fn config_file_rejects_unknown_settings() {
+        assert!(ConfigFile::parse("colour = \"blue\"").is_err());
+        assert!(ConfigFile::parse("[moonnet]").is_err());
+        assert!(ConfigFile::parse("subsidy = 99999999999").is_err());
+        assert!(ConfigFile::parse("wallet = \"../keys\"").is_err());
+        assert!(ConfigFile::parse("prune = 1").is_err());
+        assert!(ConfigFile::parse("prune = 0").is_ok());
+        assert!(ConfigFile::parse("backend = \"rocksdb\"").is_err());
+    }
+}
@@ -0,0 +1,248 @@
+//! Canonical consensus serialization.
+//!
+//! Every structure that is hashed, stored or sent to another node is encoded
+//! with the rules below, specified in `docs/serialization.md` together with
+//! test vectors:
+//!
+//! - fixed-width integers are little-endian
+//! - counts and byte string lengths are varints (Bitcoin's CompactSize) in
+//!   their shortest form
+//! - transactions and blocks start with an explicit version
+//!
+//! Decoding rejects truncated input, trailing bytes, non-minimal varints and
+//! unknown versions, so every value has exactly one encoding.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The input ended in the middle of a value.
+    UnexpectedEnd,
+    /// Bytes were left after the value.
+    TrailingBytes,
+    /// A varint was not in its shortest form.
+    NonMinimalVarInt,
+    /// A value does not fit the type it is decoded into.
+    OutOfRange,
+    /// A structure carries a version this implementation does not know.
+    UnsupportedVersion(i32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnexpectedEnd => write!(f, "unexpected end of data"),
+            Error::TrailingBytes => write!(f, "trailing bytes after value"),
+            Error::NonMinimalVarInt => write!(f, "non-minimal varint"),
+            Error::OutOfRange => write!(f, "value out of range"),
+            Error::UnsupportedVersion(version) => write!(f, "unsupported version {}", version),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub trait Encodable {
+    /// Appends the canonical encoding of `self` to `out`.
+    fn consensus_encode(&self, out: &mut Vec<u8>);
+}
+
+pub trait Decodable: Sized {
+    fn consensus_decode(reader: &mut Reader<'_>) -> Result<Self>;
+}
+
+/// Encodes a value into a new buffer.
+pub fn serialize<T: Encodable + ?Sized>(value: &T) -> Vec<u8> {
+    let mut out = Vec::new();
+    value.consensus_encode(&mut out);
+    out
+}
+
+/// Decodes a value that must span all of `data`.
+pub fn deserialize<T: Decodable>(data: &[u8]) -> Result<T> {
+    let mut reader = Reader::new(data);
+    let value = T::consensus_decode(&mut reader)?;
+    if !reader.is_empty() {
+        return Err(Error::TrailingBytes);
+    }
+    Ok(value)
+}
+
+/// Writes `n` as a CompactSize: one byte below 0xfd, otherwise a marker
+/// byte 0xfd, 0xfe or 0xff followed by 2, 4 or 8 little-endian bytes.
+pub fn write_varint(out: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xfc => out.push(n as u8),
+        0xfd..=0xffff => {
+            out.push(0xfd);
+            out.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(0xfe);
+            out.extend_from_slice(&(n as u32).to_le_bytes());
+        }
+        _ => {
+            out.push(0xff);
+            out.extend_from_slice(&n.to_le_bytes());
+        }
+    }
+}
+
+/// Writes a byte string prefixed by its varint length.
+pub fn write_var_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Reads values from a byte slice.
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Returns the bytes not read yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(Error::UnexpectedEnd);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.read_bytes(N)?.try_into().unwrap())
+    }
+
+    pub fn read_varint(&mut self) -> Result<u64> {
+        let (n, min) = match self.read_array::<1>()?[0] {
+            0xfd => (u16::from_le_bytes(self.read_array()?) as u64, 0xfd),
+            0xfe => (u32::from_le_bytes(self.read_array()?) as u64, 0x1_0000),
+            0xff => (u64::from_le_bytes(self.read_array()?), 0x1_0000_0000),
+            n => return Ok(n as u64),
+        };
+        if n < min {
+            return Err(Error::NonMinimalVarInt);
+        }
+        Ok(n)
+    }
+
+    /// Reads a varint that counts items or bytes still to come.
+    pub fn read_len(&mut self) -> Result<usize> {
+        let len = self.read_varint()?;
+        // every item takes at least a byte, so longer lengths cannot be valid
+        if len > self.data.len() as u64 {
+            return Err(Error::UnexpectedEnd);
+        }
+        Ok(len as usize)
+    }
+
+    pub fn read_var_bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.read_len()?;
+        Ok(self.read_bytes(len)?.to_vec())
+    }
+}
+
+macro_rules! impl_int {
+    ($($ty:ty),*) => {$(
+        impl Encodable for $ty {
+            fn consensus_encode(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+        }
+
+        impl Decodable for $ty {
+            fn consensus_decode(reader: &mut Reader<'_>) -> Result<Self> {
+                Ok(<$ty>::from_le_bytes(reader.read_array()?))
+            }
+        }
+    )*};
+}
+
+impl_int!(u8, u16, u32, u64, i32, i64);
+
+impl<const N: usize> Encodable for [u8; N] {
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+}
+
+impl<const N: usize> Decodable for [u8; N] {
+    fn consensus_decode(reader: &mut Reader<'_>) -> Result<Self> {
+        reader.read_array()
+    }
+}
+
+/// Sequences are a varint count followed by the items. For `Vec<u8>` this
+/// is a length-prefixed byte string.
+impl<T: Encodable> Encodable for Vec<T> {
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.len() as u64);
+        for item in self {
+            item.consensus_encode(out);
+        }
+    }
+}
+
+impl<T: Decodable> Decodable for Vec<T> {
+    fn consensus_decode(reader: &mut Reader<'_>) -> Result<Self> {
+        let len = reader.read_len()?;
+        let mut items = Vec::with_capacity(len);
+        for _ in 0..len {
+            items.push(T::consensus_decode(reader)?);
+        }
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varints_round_trip_in_shortest_form() {
+        let cases: [(u64, &str); 7] = [
+            (0, "00"),
+            (0xfc, "fc"),
+            (0xfd, "fdfd00"),
+            (0xffff, "fdffff"),
+            (0x1_0000, "fe00000100"),
+            (0xffff_ffff, "feffffffff"),
+            (0x1_0000_0000, "ff0000000001000000"),
+        ];
+        for (n, encoded) in cases {
+            let mut out = Vec::new();
+            write_varint(&mut out, n);
+            assert_eq!(hex::encode(&out), encoded);
+            assert_eq!(Reader::new(&out).read_varint(), Ok(n));
+        }
+
+        assert_eq!(Reader::new(&[0xfd, 0xfc, 0x00]).read_varint(), Err(Error::NonMinimalVarInt));
+        assert_eq!(Reader::new(&[0xfe, 0x01]).read_varint(), Err(Error::UnexpectedEnd));
+    }
+
+    #[test]
+    fn deserialize_rejects_truncated_and_trailing_data() {
+        let bytes = serialize(&vec![vec![1u8, 2, 3], vec![]]);
+        assert_eq!(hex::encode(&bytes), "020301020300");
+        assert_eq!(deserialize::<Vec<Vec<u8>>>(&bytes), Ok(vec![vec![1, 2, 3], vec![]]));
+        assert_eq!(deserialize::<Vec<Vec<u8>>>(&bytes[..4]), Err(Error::UnexpectedEnd));
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(deserialize::<Vec<Vec<u8>>>(&longer), Err(Error::TrailingBytes));
+    }
+}
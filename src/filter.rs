@@ -0,0 +1,302 @@
+//! Compact block filters in the style of BIP158.
+//!
+//! A filter is a Golomb-coded set of the items a block touches: the
+//! `pub_key_hash` of every output, and for every input the outpoint it
+//! spends together with the `pub_key_hash` of the spending key. A wallet
+//! tests its own items against the filter and only fetches the block on a
+//! match. False positives happen at a rate of about `1 / M`; false negatives
+//! never do.
+
+use crate::{
+    block::Block,
+    encode::{self, Reader},
+    utils,
+};
+
+/// Number of low bits written verbatim for each Golomb-Rice coded value.
+pub const FILTER_P: u8 = 19;
+/// Inverse of the false positive rate.
+pub const FILTER_M: u64 = 784_931;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockFilter {
+    /// Number of items in the set.
+    n: u64,
+    /// Golomb-Rice coded deltas of the sorted hashed items.
+    data: Vec<u8>,
+}
+
+impl BlockFilter {
+    /// Builds the filter of a block.
+    pub fn build(block: &Block) -> BlockFilter {
+        let mut items: Vec<Vec<u8>> = Vec::new();
+        for tx in &block.transactions {
+            for output in &tx.vout {
+                items.push(output.pub_key_hash.clone());
+            }
+            if tx.is_coinbase() {
+                continue;
+            }
+            for input in &tx.vin {
+                items.push(outpoint_item(&input.txid, input.vout));
+                items.push(utils::hash_pub_key(&input.pub_key));
+            }
+        }
+        items.sort();
+        items.dedup();
+        items.retain(|item| !item.is_empty());
+
+        let key = siphash_key(&block.hash());
+        let n = items.len() as u64;
+        let mut values: Vec<u64> = items
+            .iter()
+            .map(|item| hash_to_range(key, item, n * FILTER_M))
+            .collect();
+        values.sort_unstable();
+
+        let mut writer = BitWriter::default();
+        let mut last = 0;
+        for value in values {
+            golomb_encode(&mut writer, value - last);
+            last = value;
+        }
+        BlockFilter {
+            n,
+            data: writer.finish(),
+        }
+    }
+
+    /// Returns true if any of `items` may be in the block with hash
+    /// `block_hash`. Items are raw `pub_key_hash`es or [`outpoint_item`]s.
+    pub fn match_any(&self, block_hash: &[u8], items: &[Vec<u8>]) -> bool {
+        if self.n == 0 || items.is_empty() {
+            return false;
+        }
+        let key = siphash_key(block_hash);
+        let mut queries: Vec<u64> = items
+            .iter()
+            .map(|item| hash_to_range(key, item, self.n * FILTER_M))
+            .collect();
+        queries.sort_unstable();
+
+        let mut reader = BitReader::new(&self.data);
+        let mut value = 0;
+        let mut query = queries.iter().peekable();
+        for _ in 0..self.n {
+            match golomb_decode(&mut reader) {
+                Some(delta) => value += delta,
+                None => return true,
+            }
+            while let Some(&&q) = query.peek() {
+                if q == value {
+                    return true;
+                }
+                if q > value {
+                    break;
+                }
+                query.next();
+            }
+            if query.peek().is_none() {
+                return false;
+            }
+        }
+        false
+    }
+
+    /// Serializes the filter as the item count followed by the coded set.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.data.len() + 9);
+        encode::write_varint(&mut bytes, self.n);
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Option<BlockFilter> {
+        let mut reader = Reader::new(bytes);
+        let n = reader.read_varint().ok()?;
+        Some(BlockFilter {
+            n,
+            data: reader.remaining().to_vec(),
+        })
+    }
+}
+
+/// Encodes an outpoint as a filter item: the transaction id followed by the
+/// output index as a little-endian `u32`.
+pub fn outpoint_item(txid: &[u8], vout: usize) -> Vec<u8> {
+    let mut item = txid.to_vec();
+    item.extend_from_slice(&(vout as u32).to_le_bytes());
+    item
+}
+
+/// The SipHash key is taken from the first 16 bytes of the block hash.
+fn siphash_key(block_hash: &[u8]) -> (u64, u64) {
+    let mut key = [0u8; 16];
+    let len = block_hash.len().min(16);
+    key[..len].copy_from_slice(&block_hash[..len]);
+    (
+        u64::from_le_bytes(key[..8].try_into().unwrap()),
+        u64::from_le_bytes(key[8..].try_into().unwrap()),
+    )
+}
+
+/// Maps an item uniformly onto `[0, f)`.
+fn hash_to_range(key: (u64, u64), item: &[u8], f: u64) -> u64 {
+    ((siphash24(key.0, key.1, item) as u128 * f as u128) >> 64) as u64
+}
+
+fn golomb_encode(writer: &mut BitWriter, value: u64) {
+    for _ in 0..(value >> FILTER_P) {
+        writer.write_bit(true);
+    }
+    writer.write_bit(false);
+    writer.write_bits(value, FILTER_P);
+}
+
+fn golomb_decode(reader: &mut BitReader) -> Option<u64> {
+    let mut quotient = 0;
+    while reader.read_bit()? {
+        quotient += 1;
+    }
+    let remainder = reader.read_bits(FILTER_P)?;
+    Some((quotient << FILTER_P) + remainder)
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u8,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+        }
+        self.used = (self.used + 1) % 8;
+    }
+
+    /// Writes the low `count` bits of `value`, most significant first.
+    fn write_bits(&mut self, value: u64, count: u8) {
+        for shift in (0..count).rev() {
+            self.write_bit((value >> shift) & 1 == 1);
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.position / 8)?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Some(bit)
+    }
+
+    fn read_bits(&mut self, count: u8) -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Some(value)
+    }
+}
+
+/// SipHash-2-4 of `data` with the key `(k0, k1)`.
+fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let m = u64::from_le_bytes(chunk.try_into().unwrap());
+        v[3] ^= m;
+        round(&mut v);
+        round(&mut v);
+        v[0] ^= m;
+    }
+
+    let mut last = [0u8; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = data.len() as u8;
+    let m = u64::from_le_bytes(last);
+    v[3] ^= m;
+    round(&mut v);
+    round(&mut v);
+    v[0] ^= m;
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{transaction, wallet};
+
+    #[test]
+    fn siphash_matches_reference_vector() {
+        // first vector of the SipHash-2-4 reference implementation
+        let k0 = u64::from_le_bytes([0, 1, 2, 3, 4, 5, 6, 7]);
+        let k1 = u64::from_le_bytes([8, 9, 10, 11, 12, 13, 14, 15]);
+        assert_eq!(siphash24(k0, k1, &[]), 0x726f_db47_dd0e_0e31);
+        let data: Vec<u8> = (0..15).collect();
+        assert_eq!(siphash24(k0, k1, &data), 0xa129_ca61_49be_45e5);
+    }
+
+    #[test]
+    fn filter_matches_block_items_only() {
+        let mut transactions = Vec::new();
+        for i in 0..50u8 {
+            let address = wallet::calc_address(0x00, &[i; 20]);
+            transactions.push(transaction::new_coinbase_tx(&address, &[i], 10));
+        }
+        let block = Block::new_with_time(1_700_000_000, transactions, vec![], 1).unwrap();
+        let filter = BlockFilter::build(&block);
+        let decoded = BlockFilter::deserialize(&filter.serialize()).unwrap();
+        assert_eq!(decoded, filter);
+
+        let hash = block.hash();
+        for i in 0..50u8 {
+            assert!(decoded.match_any(&hash, &[vec![i; 20]]));
+        }
+        let strangers: Vec<Vec<u8>> = (100..200u8).map(|i| vec![i; 20]).collect();
+        assert!(!decoded.match_any(&hash, &strangers));
+        assert!(!decoded.match_any(&hash, &[]));
+    }
+}
@@ -0,0 +1,139 @@
+//! JSON views of transactions and blocks for inspection and debugging.
+
+use crate::{
+    block::Block,
+    blockchain::Blockchain,
+    encode,
+    store::ChainStore,
+    transaction::{SigHashType, TXOutput, Transaction},
+    utils, wallet,
+};
+
+use serde_json::{json, Value};
+
+/// Describes a transaction. With `chain`, the outputs spent by the inputs
+/// are looked up, which adds their value and address, whether the
+/// signature verifies, and the fee.
+pub fn transaction<S: ChainStore>(tx: &Transaction, chain: Option<&Blockchain<S>>, address_version: u8) -> Value {
+    let raw = encode::serialize(tx);
+    let coinbase = tx.is_coinbase();
+
+    let mut input_total = Some(0i64);
+    let vin: Vec<Value> = tx
+        .vin
+        .iter()
+        .enumerate()
+        .map(|(idx, input)| {
+            if coinbase {
+                return json!({ "coinbase": utils::hex_string(&input.pub_key) });
+            }
+            let mut value = json!({
+                "txid": utils::hex_string(&input.txid),
+                "vout": input.vout,
+                "signature": utils::hex_string(&input.signature),
+                "sighash": input
+                    .signature
+                    .last()
+                    .and_then(|byte| SigHashType::from_byte(*byte))
+                    .map(|t| t.to_string()),
+                "pubkey": utils::hex_string(&input.pub_key),
+                "address": wallet::calc_address(
+                    address_version,
+                    &utils::hash_pub_key(&input.pub_key)
+                ),
+            });
+            let prev_output = chain.and_then(|chain| prev_output(chain, &input.txid, input.vout));
+            match prev_output {
+                Some(prev_output) => {
+                    value["prev_value"] = json!(prev_output.value);
+                    value["prev_address"] =
+                        json!(wallet::calc_address(address_version, &prev_output.pub_key_hash));
+                    value["signature_valid"] = json!(tx.verify_input(idx, &prev_output));
+                    input_total = input_total.map(|total| total + prev_output.value as i64);
+                }
+                None => input_total = None,
+            }
+            value
+        })
+        .collect();
+
+    let vout: Vec<Value> = tx
+        .vout
+        .iter()
+        .enumerate()
+        .map(|(n, output)| {
+            json!({
+                "n": n,
+                "value": output.value,
+                "pub_key_hash": utils::hex_string(&output.pub_key_hash),
+                "address": wallet::calc_address(address_version, &output.pub_key_hash),
+            })
+        })
+        .collect();
+
+    let output_total: i64 = tx.vout.iter().map(|out| out.value as i64).sum();
+    let fee = match input_total {
+        Some(input_total) if !coinbase => Some(input_total - output_total),
+        _ => None,
+    };
+
+    json!({
+        "txid": utils::hex_string(&tx.id),
+        "version": tx.version,
+        "size": raw.len(),
+        "coinbase": coinbase,
+        "vin": vin,
+        "vout": vout,
+        "fee": fee,
+        "hex": utils::hex_string(&raw),
+    })
+}
+
+/// Describes a block and each of its transactions.
+pub fn block<S: ChainStore>(block: &Block, chain: Option<&Blockchain<S>>, address_version: u8) -> Value {
+    let header = &block.header;
+    let transactions: Vec<Value> = block
+        .transactions
+        .iter()
+        .map(|tx| transaction(tx, chain, address_version))
+        .collect();
+    json!({
+        "hash": block.get_hash(),
+        "version": header.version,
+        "prev_block_hash": utils::hex_string(&header.prev_block_hash),
+        "merkle_root": utils::hex_string(&header.merkle_root),
+        "merkle_root_valid": Block::compute_merkle_root(&block.transactions) == header.merkle_root,
+        "time": header.time_stamp,
+        "bits": header.bits,
+        "nonce": header.nonce,
+        "pow_valid": header.validate_pow(),
+        "size": block.serialize().len(),
+        "tx": transactions,
+    })
+}
+
+fn prev_output<S: ChainStore>(chain: &Blockchain<S>, txid: &[u8], vout: usize) -> Option<TXOutput> {
+    chain.find_transaction(txid)?.vout.get(vout).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction;
+
+    #[test]
+    fn coinbase_has_no_fee_and_shows_addresses() {
+        let address = wallet::calc_address(0x6f, &[7; 20]);
+        let coinbase = transaction::new_coinbase_tx(&address, b"test", 10);
+        let value = transaction(&coinbase, None::<&Blockchain>, 0x6f);
+
+        assert_eq!(value["coinbase"], json!(true));
+        assert_eq!(value["fee"], Value::Null);
+        assert_eq!(value["vin"][0]["coinbase"], json!("74657374"));
+        assert_eq!(value["vout"][0]["address"], json!(address));
+        assert_eq!(value["size"], json!(46));
+        let decoded: Transaction =
+            encode::deserialize(&hex::decode(value["hex"].as_str().unwrap()).unwrap()).unwrap();
+        assert_eq!(decoded.id, coinbase.id);
+    }
+}
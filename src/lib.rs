@@ -0,0 +1,37 @@
+//! A small Bitcoin-style blockchain: blocks with proof of work, UTXO
+//! transactions signed with ECDSA P-256, and wallets stored on disk.
+//!
+//! The binary in `main.rs` is a thin command line front end over this crate;
+//! other programs can depend on the same types directly.
+
+pub mod bcdb;
+pub mod block;
+pub mod blockchain;
+pub mod blockfiles;
+pub mod bootstrap;
+pub mod chainstate;
+pub mod coinselect;
+pub mod config;
+pub mod encode;
+pub mod filter;
+pub mod json;
+pub mod merkle;
+pub mod network;
+pub mod proofofwork;
+pub mod spv;
+pub mod store;
+pub mod transaction;
+pub mod utils;
+pub mod wallet;
+pub mod wallets;
+
+pub use bcdb::BlockchainDb;
+pub use block::{Block, BlockHeader};
+pub use blockchain::{Blockchain, BlockchainIterator, HeaderIterator};
+pub use config::{Config, ConfigBuilder};
+pub use network::{ChainParams, Network};
+pub use spv::{NodeSource, SpvClient};
+pub use store::{Backend, ChainStore, DiskStore, MemoryStore};
+pub use transaction::{SigHashBase, SigHashType, TXInput, TXOutput, Transaction};
+pub use wallet::Wallet;
+pub use wallets::Wallets;
@@ -1,16 +1,13 @@
-mod utils;
-mod block;
-mod blockchain;
-mod proofofwork;
-mod bcdb;
 mod cli;
-mod transaction;
-mod wallet;
-mod wallets;
 
-use cli::CLI;
+use cli::Cli;
+use std::process::ExitCode;
 
-fn main() {
-    let cli = CLI;
-    cli.run();
+fn main() -> ExitCode {
+    Cli::run();
+    if cli::failed() {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
 }
@@ -0,0 +1,125 @@
+use crate::{
+    encode::{self, Decodable, Encodable, Reader},
+    utils,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Proof that a leaf is part of a Merkle tree: the sibling of each node on
+/// the path from the leaf up to the root.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    /// Position of the leaf; its bits tell on which side each sibling goes.
+    pub index: u32,
+    pub siblings: Vec<Vec<u8>>,
+}
+
+/// Computes the Merkle root of a list of 32-byte hashes.
+///
+/// Pairs of nodes are concatenated and hashed with double SHA-256. A level
+/// with an odd number of nodes pairs its last node with itself, and an empty
+/// list has an all-zero root.
+pub fn merkle_root(hashes: &[Vec<u8>]) -> [u8; 32] {
+    if hashes.is_empty() {
+        return [0u8; 32];
+    }
+
+    let mut level: Vec<Vec<u8>> = hashes.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+
+    let mut root = [0u8; 32];
+    root.copy_from_slice(&level[0]);
+    root
+}
+
+/// Builds the proof for the leaf at `index`, or `None` if it is out of range.
+pub fn merkle_proof(hashes: &[Vec<u8>], index: usize) -> Option<MerkleProof> {
+    if index >= hashes.len() {
+        return None;
+    }
+
+    let mut siblings = Vec::new();
+    let mut level: Vec<Vec<u8>> = hashes.to_vec();
+    let mut position = index;
+    while level.len() > 1 {
+        let sibling = level.get(position ^ 1).unwrap_or(&level[position]);
+        siblings.push(sibling.clone());
+        level = next_level(&level);
+        position /= 2;
+    }
+
+    Some(MerkleProof {
+        index: index as u32,
+        siblings,
+    })
+}
+
+impl MerkleProof {
+    /// Computes the root of the tree containing `leaf` according to the proof.
+    pub fn root(&self, leaf: &[u8]) -> Vec<u8> {
+        let mut node = leaf.to_vec();
+        let mut position = self.index;
+        for sibling in &self.siblings {
+            node = if position & 1 == 0 {
+                hash_pair(&node, sibling)
+            } else {
+                hash_pair(sibling, &node)
+            };
+            position >>= 1;
+        }
+        node
+    }
+
+    pub fn verify(&self, leaf: &[u8], root: &[u8]) -> bool {
+        self.root(leaf) == root
+    }
+}
+
+/// A proof is the leaf index as a `u32` followed by the siblings.
+impl Encodable for MerkleProof {
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+        self.index.consensus_encode(out);
+        self.siblings.consensus_encode(out);
+    }
+}
+
+impl Decodable for MerkleProof {
+    fn consensus_decode(reader: &mut Reader<'_>) -> encode::Result<Self> {
+        Ok(MerkleProof {
+            index: u32::consensus_decode(reader)?,
+            siblings: Vec::consensus_decode(reader)?,
+        })
+    }
+}
+
+fn next_level(level: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    level
+        .chunks(2)
+        .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+fn hash_pair(left: &[u8], right: &[u8]) -> Vec<u8> {
+    utils::sha256d(&[left, right].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proofs_verify_against_the_root() {
+        for count in 1..=7 {
+            let leaves: Vec<Vec<u8>> = (0..count).map(|i| utils::compute_sha256(&[i])).collect();
+            let root = merkle_root(&leaves);
+            for (idx, leaf) in leaves.iter().enumerate() {
+                let proof = merkle_proof(&leaves, idx).unwrap();
+                assert!(proof.verify(leaf, &root), "leaf {} of {}", idx, count);
+                assert!(!proof.verify(&leaves[(idx + 1) % count as usize], &root) || count == 1);
+            }
+        }
+        assert!(merkle_proof(&[], 0).is_none());
+    }
+}
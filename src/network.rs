@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// The chains a node can run on. Each one keeps its state in its own data
+/// subdirectory and uses its own address version byte, so coins cannot be
+/// sent across networks by mistake.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+/// Consensus and node parameters of a network.
+#[derive(Clone, Debug)]
+pub struct ChainParams {
+    pub network: Network,
+    /// Version byte prefixed to the public key hash in addresses.
+    pub address_version: u8,
+    /// Version byte prefixed to private keys exported by `dumpprivkey`.
+    pub secret_key_version: u8,
+    /// Subdirectory of the data directory holding this network's files.
+    pub data_subdir: &'static str,
+    /// Number of leading zero bits a block hash must have.
+    pub target_bits: u16,
+    /// Mining reward paid by each coinbase transaction.
+    pub subsidy: i32,
+    /// Timestamp of the genesis block.
+    pub genesis_time_stamp: i64,
+    /// Data embedded in the coinbase input of the genesis block.
+    pub genesis_message: &'static str,
+}
+
+impl Network {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Regtest => "regtest",
+        }
+    }
+
+    /// Returns the default parameters of the network.
+    pub fn params(&self) -> ChainParams {
+        match self {
+            Network::Mainnet => ChainParams {
+                network: *self,
+                address_version: 0x00,
+                secret_key_version: 0x80,
+                data_subdir: "",
+                target_bits: 16,
+                subsidy: 10,
+                genesis_time_stamp: 1_700_000_000,
+                genesis_message: "bitcoin-rs mainnet genesis",
+            },
+            Network::Testnet => ChainParams {
+                network: *self,
+                address_version: 0x6f,
+                secret_key_version: 0xef,
+                data_subdir: "testnet",
+                target_bits: 12,
+                subsidy: 10,
+                genesis_time_stamp: 1_700_000_000,
+                genesis_message: "bitcoin-rs testnet genesis",
+            },
+            Network::Regtest => ChainParams {
+                network: *self,
+                address_version: 0x6f,
+                secret_key_version: 0xef,
+                data_subdir: "regtest",
+                target_bits: 1,
+                subsidy: 10,
+                genesis_time_stamp: 1_700_000_000,
+                genesis_message: "bitcoin-rs regtest genesis",
+            },
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mainnet" | "main" => Ok(Network::Mainnet),
+            "testnet" | "test" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format!("unknown network: {}", s)),
+        }
+    }
+}
@@ -1,67 +1,223 @@
-use crate::block::Block;
-use num_bigint::BigUint;
+use crate::block::{BlockHeader, HEADER_SIZE};
 use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-const TARGET_BITS: u16 = 16;
-const MAT_NONCE: u32 = u32::MAX;
+const MAX_NONCE: u64 = u32::MAX as u64;
+/// Nonces each thread tries per round before the results are compared.
+const BATCH_SIZE: u64 = 1 << 16;
+/// How often a thread checks for cancellation.
+const CANCEL_CHECK_INTERVAL: u64 = 1 << 12;
+
+/// Lowest solution found in a round, as nonce and hash, and the hashes computed.
+type RoundResult = (Option<(u32, Vec<u8>)>, u64);
+
+/// Size of the header data preceding the nonce.
+const PREFIX_SIZE: usize = HEADER_SIZE - 4;
 
 pub struct ProofOfWork<'a> {
-    block: &'a Block,
-    target: BigUint,
+    header: &'a BlockHeader,
+}
+
+/// A nonce and timestamp giving a block hash below the target.
+pub struct Solution {
+    pub nonce: u32,
+    pub time_stamp: u32,
+    pub hash: Vec<u8>,
+}
+
+/// Work done by a call to [`Miner::mine`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MiningStats {
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningStats {
+    pub fn hashes_per_second(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.hashes as f64 / secs
+        } else {
+            0.0
+        }
+    }
 }
 
 impl<'a> ProofOfWork<'a> {
-    pub fn new(block: &'a Block) -> Self {
-        let mut target = BigUint::from(1u32);
-        target = target << (256 - TARGET_BITS);
-        ProofOfWork { block, target }
+    pub fn new(header: &'a BlockHeader) -> Self {
+        ProofOfWork { header }
     }
 
-    fn prepare_data(&self, nonce: u32) -> Vec<u8> {
-        let data = vec![
-            &self.block.time_stamp.to_le_bytes()[..],
-            &self.block.hash_transaction(),
-            &self.block.prev_block_hash[..],
-            &TARGET_BITS.to_le_bytes(),
-            &nonce.to_be_bytes(),
-        ]
-        .concat();
-        data
+    /// Returns the serialized header up to the nonce, which is the last field
+    /// and the only one changing while searching a timestamp.
+    fn prepare_prefix(&self, time_stamp: u32) -> Vec<u8> {
+        let header = BlockHeader {
+            time_stamp,
+            ..self.header.clone()
+        };
+        header.serialize()[..PREFIX_SIZE].to_vec()
     }
 
-    /// Calculates the proof of work, returning the correct nonce and hash.
-    pub fn run(&self) -> (u32, Vec<u8>) {
-        let mut hasher = Sha256::new();
+    /// Calculates the proof of work on all available cores, returning the
+    /// solution and the work it took.
+    pub fn run(&self) -> (Solution, MiningStats) {
+        let (solution, stats) = Miner::default().mine(self);
+        (solution.expect("mining cannot be cancelled without a handle"), stats)
+    }
 
-        for nonce in 0..MAT_NONCE {
-            let data = self.prepare_data(nonce);
-            hasher.update(&data);
-            let hash = hasher.finalize_reset().to_vec();
+    pub fn validate(&self) -> bool {
+        meets_target(&self.header.hash(), self.header.bits)
+    }
+}
 
-            let hash_int = BigUint::from_bytes_be(&hash);
+/// Returns true if `hash` has at least `target_bits` leading zero bits, that
+/// is, if it is below `2^(256 - target_bits)`.
+pub fn meets_target(hash: &[u8], target_bits: u32) -> bool {
+    let mut remaining = target_bits;
+    for byte in hash {
+        if remaining == 0 {
+            return true;
+        }
+        let zeros = byte.leading_zeros().min(remaining);
+        if zeros < 8 && zeros < remaining {
+            return false;
+        }
+        remaining -= zeros;
+    }
+    remaining == 0
+}
 
-            if hash_int < self.target {
-                return (nonce, hash);
-            }
+/// Searches for proof of work on several threads.
+///
+/// Each round gives every thread its own slice of the nonce space, and the
+/// lowest valid nonce wins, so the result is the same as a sequential scan
+/// whatever the thread count. When all nonces fail, the timestamp is rolled
+/// forward by one second and the search starts over.
+pub struct Miner {
+    threads: usize,
+    cancel: Arc<AtomicBool>,
+}
+
+impl Default for Miner {
+    fn default() -> Self {
+        Miner::new(thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Self {
+        Miner {
+            threads: threads.max(1),
+            cancel: Arc::new(AtomicBool::new(false)),
         }
+    }
 
-        (0, Vec::new()) // Return a default value if no solution is found within MAT_NONCE
+    /// Returns a flag that stops the current search when set, for example
+    /// when another node extends the chain first. Set between searches, it
+    /// stops the next one. Each search clears it when it ends.
+    pub fn cancel_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.cancel)
     }
 
-    #[allow(dead_code)]
-    pub fn validate(&self) -> bool {
-        let data: Vec<u8> = self.prepare_data(self.block.nonce);
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        let hash = hasher.finalize().to_vec();
-        let hash_int = BigUint::from_bytes_be(&hash);
+    /// Searches for a solution for the header of `pow`, starting from its
+    /// timestamp. Returns `None` if the search was cancelled.
+    pub fn mine(&self, pow: &ProofOfWork) -> (Option<Solution>, MiningStats) {
+        let start = Instant::now();
+        let mut hashes = 0;
+        let mut time_stamp = pow.header.time_stamp;
+
+        let solution = 'search: loop {
+            let mut midstate = Sha256::new();
+            midstate.update(pow.prepare_prefix(time_stamp));
+
+            let mut base = 0;
+            while base <= MAX_NONCE {
+                if self.cancel.load(Ordering::Relaxed) {
+                    break 'search None;
+                }
+                let (found, done) = self.search_round(&midstate, pow.header.bits, base);
+                hashes += done;
+                if let Some((nonce, hash)) = found {
+                    break 'search Some(Solution {
+                        nonce,
+                        time_stamp,
+                        hash,
+                    });
+                }
+                base += BATCH_SIZE * self.threads as u64;
+            }
+            time_stamp = time_stamp.wrapping_add(1);
+        };
+        self.cancel.store(false, Ordering::Relaxed);
 
-        hash_int < self.target
+        let stats = MiningStats {
+            hashes,
+            elapsed: start.elapsed(),
+        };
+        (solution, stats)
+    }
+
+    /// Lets every thread try `BATCH_SIZE` nonces from `base` on and returns
+    /// the lowest solution found together with the number of hashes computed.
+    fn search_round(
+        &self,
+        midstate: &Sha256,
+        target_bits: u32,
+        base: u64,
+    ) -> RoundResult {
+        let best = AtomicU64::new(u64::MAX);
+        let cancel = &self.cancel;
+
+        let results: Vec<RoundResult> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..self.threads as u64)
+                .map(|idx| {
+                    let best = &best;
+                    scope.spawn(move || {
+                        let from = base + idx * BATCH_SIZE;
+                        let to = (from + BATCH_SIZE).min(MAX_NONCE + 1);
+                        let mut hashes = 0;
+                        for nonce in from..to {
+                            // a lower nonce has already been found by another thread
+                            if nonce > best.load(Ordering::Relaxed) {
+                                break;
+                            }
+                            if hashes % CANCEL_CHECK_INTERVAL == 0 && cancel.load(Ordering::Relaxed) {
+                                break;
+                            }
+                            let first = midstate
+                                .clone()
+                                .chain_update((nonce as u32).to_le_bytes())
+                                .finalize();
+                            let hash = Sha256::digest(first);
+                            hashes += 1;
+                            if meets_target(&hash, target_bits) {
+                                best.fetch_min(nonce, Ordering::Relaxed);
+                                return (Some((nonce as u32, hash.to_vec())), hashes);
+                            }
+                        }
+                        (None, hashes)
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        let hashes = results.iter().map(|(_, n)| n).sum();
+        let found = results
+            .into_iter()
+            .filter_map(|(found, _)| found)
+            .min_by_key(|(nonce, _)| *nonce);
+        (found, hashes)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn test() {
         let bytes: &[u8] = &[0x78, 0x56, 0x12, 0x44];
@@ -71,4 +227,64 @@ mod tests {
         let last_four = &bytes[bytes.len() - 4..];
         println!("{:?}", last_four);
     }
+
+    #[test]
+    fn meets_target_counts_leading_zero_bits() {
+        assert!(meets_target(&[0xff; 32], 0));
+        assert!(meets_target(&[0x00, 0x7f, 0xff], 9));
+        assert!(!meets_target(&[0x00, 0x7f, 0xff], 10));
+        assert!(meets_target(&[0x00, 0x00], 16));
+        assert!(!meets_target(&[0x00, 0x00], 17));
+    }
+
+    #[test]
+    fn thread_count_does_not_change_the_solution() {
+        let header = BlockHeader {
+            version: 1,
+            prev_block_hash: [1; 32],
+            merkle_root: [0; 32],
+            time_stamp: 1_700_000_000,
+            bits: 10,
+            nonce: 0,
+        };
+        let pow = ProofOfWork::new(&header);
+        let (single, _) = Miner::new(1).mine(&pow);
+        let (multi, stats) = Miner::new(4).mine(&pow);
+        let (single, multi) = (single.unwrap(), multi.unwrap());
+
+        assert_eq!(single.nonce, multi.nonce);
+        assert_eq!(single.hash, multi.hash);
+        assert!(stats.hashes > single.nonce as u64);
+
+        let solved = BlockHeader {
+            nonce: single.nonce,
+            ..header.clone()
+        };
+        assert_eq!(solved.hash(), single.hash);
+        assert!(ProofOfWork::new(&solved).validate());
+    }
+
+    #[test]
+    fn cancelled_search_returns_nothing() {
+        let header = BlockHeader {
+            version: 1,
+            prev_block_hash: [0; 32],
+            merkle_root: [0; 32],
+            time_stamp: 0,
+            bits: 200,
+            nonce: 0,
+        };
+        let miner = Miner::new(2);
+        let cancel = miner.cancel_handle();
+        // a cancel issued before the search is not lost
+        cancel.store(true, Ordering::Relaxed);
+        let (solution, stats) = miner.mine(&ProofOfWork::new(&header));
+        assert!(solution.is_none());
+        assert_eq!(stats.hashes, 0);
+
+        // and only stops that search
+        assert!(!cancel.load(Ordering::Relaxed));
+        let header = BlockHeader { bits: 8, ..header };
+        assert!(miner.mine(&ProofOfWork::new(&header)).0.is_some());
+    }
 }
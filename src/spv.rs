@@ -0,0 +1,441 @@
+use crate::{
+    bcdb::{BlockchainDb, Result},
+    block::{Block, BlockHeader},
+    blockchain::Blockchain,
+    config::Config,
+    encode::{self, Decodable, Encodable, Reader},
+    filter::BlockFilter,
+    merkle::{self, MerkleProof},
+    network::ChainParams,
+    store::ChainStore,
+    transaction::Transaction,
+};
+
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Most headers a node returns for one request.
+pub const MAX_HEADERS: usize = 2000;
+/// Key of the verified wallet transactions of a light client.
+const TX_PROOFS_KEY: &[u8] = b"w";
+
+/// A transaction together with the proof that it is in a block.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TxProof {
+    pub block_hash: Vec<u8>,
+    pub tx: Transaction,
+    pub proof: MerkleProof,
+}
+
+impl Encodable for TxProof {
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+        encode::write_var_bytes(out, &self.block_hash);
+        self.tx.consensus_encode(out);
+        self.proof.consensus_encode(out);
+    }
+}
+
+impl Decodable for TxProof {
+    fn consensus_decode(reader: &mut Reader<'_>) -> encode::Result<Self> {
+        Ok(TxProof {
+            block_hash: reader.read_var_bytes()?,
+            tx: Transaction::consensus_decode(reader)?,
+            proof: MerkleProof::consensus_decode(reader)?,
+        })
+    }
+}
+
+/// The requests a light client makes to a full node.
+pub trait NodeSource {
+    /// Returns up to `max` headers of the node's best chain following the
+    /// first hash in `locator` the node knows, or starting at the genesis
+    /// block if it knows none of them.
+    fn get_headers(&self, locator: &[Vec<u8>], max: usize) -> Vec<BlockHeader>;
+
+    /// Returns every transaction paying to or spending from one of
+    /// `pub_key_hashes`, with its Merkle proof.
+    fn get_tx_proofs(&self, pub_key_hashes: &[Vec<u8>]) -> Vec<TxProof>;
+
+    /// Returns the compact filter of a block, if the node has one.
+    fn get_block_filter(&self, block_hash: &[u8]) -> Option<BlockFilter>;
+
+    /// Returns a full block.
+    fn get_block(&self, block_hash: &[u8]) -> Option<Block>;
+}
+
+/// A local full node answering light client requests in-process.
+impl<S: ChainStore> NodeSource for Blockchain<S> {
+    fn get_headers(&self, locator: &[Vec<u8>], max: usize) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = self.header_iterator().collect();
+        headers.reverse();
+
+        let positions: HashMap<Vec<u8>, usize> = headers
+            .iter()
+            .enumerate()
+            .map(|(height, header)| (header.hash(), height))
+            .collect();
+        let start = locator
+            .iter()
+            .find_map(|hash| positions.get(hash))
+            .map_or(0, |height| height + 1);
+
+        headers.into_iter().skip(start).take(max).collect()
+    }
+
+    fn get_tx_proofs(&self, pub_key_hashes: &[Vec<u8>]) -> Vec<TxProof> {
+        let mut proofs = Vec::new();
+        for block in self.iterator() {
+            let ids: Vec<Vec<u8>> = block.transactions.iter().map(|tx| tx.id.clone()).collect();
+            for (idx, tx) in block.transactions.iter().enumerate() {
+                let pays = tx
+                    .vout
+                    .iter()
+                    .any(|out| pub_key_hashes.iter().any(|pkh| out.is_locked_with_key(pkh)));
+                let spends = !tx.is_coinbase()
+                    && tx
+                        .vin
+                        .iter()
+                        .any(|input| pub_key_hashes.iter().any(|pkh| input.uses_key(pkh)));
+                if pays || spends {
+                    proofs.push(TxProof {
+                        block_hash: block.hash(),
+                        tx: tx.clone(),
+                        proof: merkle::merkle_proof(&ids, idx).unwrap(),
+                    });
+                }
+            }
+        }
+        proofs
+    }
+
+    fn get_block_filter(&self, block_hash: &[u8]) -> Option<BlockFilter> {
+        Blockchain::get_block_filter(self, block_hash)
+    }
+
+    fn get_block(&self, block_hash: &[u8]) -> Option<Block> {
+        Blockchain::get_block(self, block_hash)
+    }
+}
+
+/// A light client: it keeps only block headers, checks their proof of work
+/// and tracks the wallet's coins through Merkle proofs.
+pub struct SpvClient {
+    db: BlockchainDb,
+    params: ChainParams,
+    tip: Option<Vec<u8>>,
+    tx_proofs: Vec<TxProof>,
+}
+
+impl SpvClient {
+    pub fn new(config: &Config) -> Result<Self> {
+        let path = config.spv_db_path();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let db = BlockchainDb::open(config.backend(), &path)?;
+        let tip = db.read_tip()?;
+        let tx_proofs = match db.read(TX_PROOFS_KEY)? {
+            Some(data) => encode::deserialize(data.as_slice())?,
+            None => Vec::new(),
+        };
+        Ok(SpvClient {
+            db,
+            params: config.params().clone(),
+            tip,
+            tx_proofs,
+        })
+    }
+
+    pub fn tip(&self) -> Option<&Vec<u8>> {
+        self.tip.as_ref()
+    }
+
+    /// Returns the height of the best header, or `None` before the first sync.
+    pub fn get_best_height(&self) -> Result<Option<u64>> {
+        self.tip.as_ref().map(|tip| self.height_of(tip)).transpose()
+    }
+
+    /// Downloads headers from `node` until it has no more, validating each
+    /// batch before storing it. Returns the number of headers added.
+    pub fn sync_headers(&mut self, node: &impl NodeSource) -> Result<usize> {
+        let mut added = 0;
+        loop {
+            let headers = node.get_headers(&self.locator()?, MAX_HEADERS);
+            if headers.is_empty() {
+                return Ok(added);
+            }
+            added += self.connect_headers(&headers)?;
+        }
+    }
+
+    /// Replaces the tracked transactions with proofs from `node` for
+    /// `pub_key_hashes`, keeping only those that verify against the best
+    /// header chain. Returns the number of transactions kept.
+    pub fn sync_transactions(
+        &mut self,
+        node: &impl NodeSource,
+        pub_key_hashes: &[Vec<u8>],
+    ) -> Result<usize> {
+        let main_chain = self.main_chain()?;
+        let mut verified = Vec::new();
+        for tx_proof in node.get_tx_proofs(pub_key_hashes) {
+            if !main_chain.contains(&tx_proof.block_hash) {
+                continue;
+            }
+            let Some(header) = self.db.read_header(&tx_proof.block_hash)? else {
+                continue;
+            };
+            if tx_proof.tx.hash() != tx_proof.tx.id
+                || !tx_proof.proof.verify(&tx_proof.tx.id, &header.merkle_root)
+            {
+                return Err(format!(
+                    "invalid Merkle proof for transaction {}",
+                    HEXLOWER.encode(&tx_proof.tx.id)
+                )
+                .into());
+            }
+            verified.push(tx_proof);
+        }
+
+        self.db.write(TX_PROOFS_KEY, &encode::serialize(&verified))?;
+        self.tx_proofs = verified;
+        Ok(self.tx_proofs.len())
+    }
+
+    /// Like [`SpvClient::sync_transactions`], but without telling `node`
+    /// which keys the wallet owns: the client downloads the compact filter
+    /// of every block of the best chain, fetches only the blocks matching
+    /// `pub_key_hashes` and builds the Merkle proofs itself. Returns the
+    /// number of transactions kept.
+    pub fn scan_blocks(
+        &mut self,
+        node: &impl NodeSource,
+        pub_key_hashes: &[Vec<u8>],
+    ) -> Result<usize> {
+        let mut verified = Vec::new();
+        for block_hash in self.main_chain_hashes()?.into_iter().rev() {
+            let may_match = node
+                .get_block_filter(&block_hash)
+                .is_none_or(|filter| filter.match_any(&block_hash, pub_key_hashes));
+            if !may_match {
+                continue;
+            }
+            let block = node.get_block(&block_hash).ok_or_else(|| {
+                format!("node does not have block {}", HEXLOWER.encode(&block_hash))
+            })?;
+            let header = self
+                .db
+                .read_header(&block_hash)?
+                .ok_or("a header of the best chain is missing")?;
+            if block.header != header
+                || Block::compute_merkle_root(&block.transactions) != header.merkle_root
+            {
+                return Err(format!(
+                    "block {} does not match its header",
+                    HEXLOWER.encode(&block_hash)
+                )
+                .into());
+            }
+
+            let ids: Vec<Vec<u8>> = block.transactions.iter().map(|tx| tx.id.clone()).collect();
+            for (idx, tx) in block.transactions.iter().enumerate() {
+                let pays = tx
+                    .vout
+                    .iter()
+                    .any(|out| pub_key_hashes.iter().any(|pkh| out.is_locked_with_key(pkh)));
+                let spends = !tx.is_coinbase()
+                    && tx
+                        .vin
+                        .iter()
+                        .any(|input| pub_key_hashes.iter().any(|pkh| input.uses_key(pkh)));
+                if pays || spends {
+                    verified.push(TxProof {
+                        block_hash: block_hash.clone(),
+                        tx: tx.clone(),
+                        proof: merkle::merkle_proof(&ids, idx).unwrap(),
+                    });
+                }
+            }
+        }
+
+        self.db.write(TX_PROOFS_KEY, &encode::serialize(&verified))?;
+        self.tx_proofs = verified;
+        Ok(self.tx_proofs.len())
+    }
+
+    /// Returns the value of the verified outputs locked to `pub_key_hash`
+    /// that no verified transaction spends.
+    pub fn get_balance(&self, pub_key_hash: &[u8]) -> i32 {
+        let spent: HashSet<(Vec<u8>, usize)> = self
+            .tx_proofs
+            .iter()
+            .filter(|p| !p.tx.is_coinbase())
+            .flat_map(|p| p.tx.vin.iter().map(|input| (input.txid.clone(), input.vout)))
+            .collect();
+
+        self.tx_proofs
+            .iter()
+            .flat_map(|p| p.tx.vout.iter().enumerate().map(move |(idx, out)| (p, idx, out)))
+            .filter(|(p, idx, out)| {
+                out.is_locked_with_key(pub_key_hash) && !spent.contains(&(p.tx.id.clone(), *idx))
+            })
+            .map(|(_, _, out)| out.value)
+            .sum()
+    }
+
+    /// Validates a batch of consecutive headers and makes the last one the
+    /// tip if its chain is longer than the current one.
+    fn connect_headers(&mut self, headers: &[BlockHeader]) -> Result<usize> {
+        let first = &headers[0];
+        let mut height = if first.is_genesis() {
+            0
+        } else if self.db.read_header(&first.prev_block_hash)?.is_some() {
+            self.height_of(&first.prev_block_hash)? + 1
+        } else {
+            return Err("headers do not connect to the known chain".into());
+        };
+
+        let mut prev_hash = first.prev_block_hash.to_vec();
+        for header in headers {
+            if header.prev_block_hash[..] != prev_hash[..] {
+                return Err("headers are not consecutive".into());
+            }
+            if header.bits != self.params.target_bits as u32 || !header.validate_pow() {
+                return Err(format!("invalid proof of work at height {}", height).into());
+            }
+            self.db.write_header(header)?;
+            prev_hash = header.hash();
+            height += 1;
+        }
+
+        let new_height = height - 1;
+        if self.get_best_height()?.is_none_or(|best| new_height > best) {
+            self.db.write_tip(&prev_hash)?;
+            self.tip = Some(prev_hash);
+        }
+        Ok(headers.len())
+    }
+
+    /// Returns hashes of the best chain from the tip back, dense at first and
+    /// then exponentially sparser, ending with the genesis block.
+    fn locator(&self) -> Result<Vec<Vec<u8>>> {
+        let chain: Vec<Vec<u8>> = self.main_chain_hashes()?;
+        let mut locator = Vec::new();
+        let mut step = 1;
+        let mut idx = 0;
+        while idx < chain.len() {
+            locator.push(chain[idx].clone());
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            idx += step;
+        }
+        if let Some(genesis) = chain.last() {
+            if locator.last() != Some(genesis) {
+                locator.push(genesis.clone());
+            }
+        }
+        Ok(locator)
+    }
+
+    /// Returns the hashes of the best chain from the tip back to the genesis block.
+    fn main_chain_hashes(&self) -> Result<Vec<Vec<u8>>> {
+        let mut hashes = Vec::new();
+        let mut next = self.tip.clone();
+        while let Some(hash) = next {
+            let header = self.db.read_header(&hash)?;
+            hashes.push(hash);
+            next = header
+                .filter(|h| !h.is_genesis())
+                .map(|h| h.prev_block_hash.to_vec());
+        }
+        Ok(hashes)
+    }
+
+    fn main_chain(&self) -> Result<HashSet<Vec<u8>>> {
+        Ok(self.main_chain_hashes()?.into_iter().collect())
+    }
+
+    fn height_of(&self, hash: &[u8]) -> Result<u64> {
+        let mut height = 0;
+        let mut header = self.db.read_header(hash)?;
+        while let Some(h) = header.filter(|h| !h.is_genesis()) {
+            height += 1;
+            header = self.db.read_header(&h.prev_block_hash)?;
+        }
+        Ok(height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{block::Block, network::Network, transaction, utils, wallet};
+
+    /// A node serving a fixed list of blocks.
+    struct FakeNode {
+        blocks: Vec<Block>,
+    }
+
+    impl NodeSource for FakeNode {
+        fn get_headers(&self, locator: &[Vec<u8>], max: usize) -> Vec<BlockHeader> {
+            let start = locator
+                .iter()
+                .find_map(|hash| self.blocks.iter().position(|b| &b.hash() == hash))
+                .map_or(0, |idx| idx + 1);
+            self.blocks.iter().skip(start).take(max).map(|b| b.header.clone()).collect()
+        }
+
+        fn get_tx_proofs(&self, _pub_key_hashes: &[Vec<u8>]) -> Vec<TxProof> {
+            self.blocks
+                .iter()
+                .map(|block| TxProof {
+                    block_hash: block.hash(),
+                    tx: block.transactions[0].clone(),
+                    proof: merkle::merkle_proof(&[block.transactions[0].id.clone()], 0).unwrap(),
+                })
+                .collect()
+        }
+
+        fn get_block_filter(&self, block_hash: &[u8]) -> Option<BlockFilter> {
+            self.get_block(block_hash).map(|b| BlockFilter::build(&b))
+        }
+
+        fn get_block(&self, block_hash: &[u8]) -> Option<Block> {
+            self.blocks.iter().find(|b| b.hash() == block_hash).cloned()
+        }
+    }
+
+    #[test]
+    fn light_client_tracks_balance_from_headers_and_proofs() {
+        let dir = utils::TempDir::new("spv-test");
+        let config = Config::builder().data_dir(dir.path()).network(Network::Regtest).build();
+        let pub_key_hash = vec![3u8; 20];
+        let address = wallet::calc_address(0x6f, &pub_key_hash);
+
+        let mut blocks: Vec<Block> = Vec::new();
+        for height in 0u64..3 {
+            let coinbase = transaction::new_coinbase_tx(&address, &height.to_le_bytes(), 10);
+            let prev = blocks.last().map(|b| b.hash()).unwrap_or_default();
+            utils::set_mock_time(Some(1_700_000_000 + height as i64));
+            blocks.push(Block::new(vec![coinbase], prev, 1).unwrap());
+        }
+        utils::set_mock_time(None);
+        let mut node = FakeNode { blocks };
+
+        let mut client = SpvClient::new(&config).unwrap();
+        assert_eq!(client.sync_headers(&node).unwrap(), 3);
+        assert_eq!(client.get_best_height().unwrap(), Some(2));
+        let pub_key_hashes = vec![pub_key_hash.clone()];
+        assert_eq!(client.sync_transactions(&node, &pub_key_hashes).unwrap(), 3);
+        assert_eq!(client.get_balance(&pub_key_hash), 30);
+        assert_eq!(client.scan_blocks(&node, &pub_key_hashes).unwrap(), 3);
+        assert_eq!(client.get_balance(&pub_key_hash), 30);
+        assert_eq!(client.scan_blocks(&node, &[vec![4u8; 20]]).unwrap(), 0);
+
+        node.blocks[1].transactions[0].vout[0].value = 1000;
+        node.blocks[1].transactions[0].id = node.blocks[1].transactions[0].hash();
+        assert!(client.sync_transactions(&node, &pub_key_hashes).is_err());
+    }
+}
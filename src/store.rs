@@ -0,0 +1,457 @@
+//! Key-value stores the chain database can be kept in.
+//!
+//! [`BlockchainDb`](crate::bcdb::BlockchainDb) only needs ordered byte keys,
+//! atomic batches and prefix scans, which [`ChainStore`] describes. LevelDB
+//! is the default on disk; redb is a pure Rust alternative behind the `redb`
+//! feature, and [`MemoryStore`] keeps everything in memory for tests.
+
+use crate::bcdb::Result;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+#[cfg(not(any(feature = "leveldb", feature = "redb")))]
+compile_error!("enable at least one storage backend: the `leveldb` or `redb` feature");
+
+/// An entry yielded by [`ChainStore::scan_prefix`].
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// Writes applied together by [`ChainStore::write_batch`]: after a crash
+/// either all of them are in the store or none is.
+#[derive(Default)]
+pub struct WriteBatch {
+    /// Keys with their new value, or `None` to delete them.
+    ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], val: &[u8]) {
+        self.ops.push((key.to_vec(), Some(val.to_vec())));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.ops.push((key.to_vec(), None));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// An ordered map from byte strings to byte strings.
+pub trait ChainStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()>;
+
+    fn delete(&mut self, key: &[u8]) -> Result<()>;
+
+    /// Applies every write of `batch` atomically and durably.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()>;
+
+    /// Returns the entries whose key starts with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: &[u8]) -> Box<dyn Iterator<Item = Result<Entry>> + '_>;
+
+    /// Returns the keys starting with `prefix`, in order. Stores that can
+    /// skip reading the values should.
+    fn keys_with_prefix(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.scan_prefix(prefix).map(|entry| entry.map(|(key, _)| key)).collect()
+    }
+}
+
+/// A store that lives in memory and is lost when dropped.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore {
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+impl ChainStore for MemoryStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.entries.get(key).cloned())
+    }
+
+    fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.entries.insert(key.to_vec(), val.to_vec());
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.entries.remove(key);
+        Ok(())
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        for (key, val) in batch.ops {
+            match val {
+                Some(val) => self.entries.insert(key, val),
+                None => self.entries.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Box<dyn Iterator<Item = Result<Entry>> + '_> {
+        let prefix = prefix.to_vec();
+        Box::new(
+            self.entries
+                .range(prefix.clone()..)
+                .take_while(move |(key, _)| key.starts_with(&prefix))
+                .map(|(key, val)| Ok((key.clone(), val.clone()))),
+        )
+    }
+}
+
+/// The on-disk storage engines.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    LevelDb,
+    Redb,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        if cfg!(feature = "leveldb") {
+            Backend::LevelDb
+        } else {
+            Backend::Redb
+        }
+    }
+}
+
+impl Backend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::LevelDb => "leveldb",
+            Backend::Redb => "redb",
+        }
+    }
+
+    /// Whether this build includes the backend.
+    pub fn is_available(&self) -> bool {
+        match self {
+            Backend::LevelDb => cfg!(feature = "leveldb"),
+            Backend::Redb => cfg!(feature = "redb"),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "leveldb" => Ok(Backend::LevelDb),
+            "redb" => Ok(Backend::Redb),
+            _ => Err(format!("unknown storage backend `{}`", s)),
+        }
+    }
+}
+
+/// A store on disk, in whichever backend the configuration picks.
+#[cfg(any(feature = "leveldb", feature = "redb"))]
+pub enum DiskStore {
+    #[cfg(feature = "leveldb")]
+    LevelDb(leveldb_store::LevelDbStore),
+    #[cfg(feature = "redb")]
+    Redb(redb_store::RedbStore),
+}
+
+#[cfg(any(feature = "leveldb", feature = "redb"))]
+impl DiskStore {
+    /// Opens the store at `path`, creating it if needed. Fails if the
+    /// backend was not compiled in.
+    pub fn open(backend: Backend, path: &Path) -> Result<Self> {
+        match backend {
+            #[cfg(feature = "leveldb")]
+            Backend::LevelDb => Ok(DiskStore::LevelDb(leveldb_store::LevelDbStore::open(path)?)),
+            #[cfg(feature = "redb")]
+            Backend::Redb => Ok(DiskStore::Redb(redb_store::RedbStore::open(path)?)),
+            #[allow(unreachable_patterns)]
+            _ => Err(format!("this build does not include the {} backend", backend).into()),
+        }
+    }
+
+    fn inner(&self) -> &dyn ChainStore {
+        match self {
+            #[cfg(feature = "leveldb")]
+            DiskStore::LevelDb(store) => store,
+            #[cfg(feature = "redb")]
+            DiskStore::Redb(store) => store,
+        }
+    }
+
+    fn inner_mut(&mut self) -> &mut dyn ChainStore {
+        match self {
+            #[cfg(feature = "leveldb")]
+            DiskStore::LevelDb(store) => store,
+            #[cfg(feature = "redb")]
+            DiskStore::Redb(store) => store,
+        }
+    }
+}
+
+#[cfg(any(feature = "leveldb", feature = "redb"))]
+impl ChainStore for DiskStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner().get(key)
+    }
+
+    fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.inner_mut().put(key, val)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.inner_mut().delete(key)
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.inner_mut().write_batch(batch)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Box<dyn Iterator<Item = Result<Entry>> + '_> {
+        self.inner().scan_prefix(prefix)
+    }
+
+    fn keys_with_prefix(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.inner().keys_with_prefix(prefix)
+    }
+}
+
+#[cfg(feature = "leveldb")]
+mod leveldb_store {
+    use super::{ChainStore, Entry, WriteBatch};
+    use crate::bcdb::Result;
+
+    use db_key::Key;
+    use leveldb::batch::{Batch, Writebatch};
+    use leveldb::database::Database;
+    use leveldb::iterator::{Iterable, LevelDBIterator};
+    use leveldb::kv::KV;
+    use leveldb::options::{Options, ReadOptions, WriteOptions};
+    use std::path::Path;
+
+    /// A raw byte string used as a LevelDB key.
+    pub struct BytesKey(Vec<u8>);
+
+    impl Key for BytesKey {
+        fn from_u8(key: &[u8]) -> Self {
+            BytesKey(key.to_vec())
+        }
+
+        fn as_slice<T, F: Fn(&[u8]) -> T>(&self, f: F) -> T {
+            f(&self.0)
+        }
+    }
+
+    pub struct LevelDbStore {
+        database: Database<BytesKey>,
+    }
+
+    impl LevelDbStore {
+        pub fn open(path: &Path) -> Result<Self> {
+            let mut opts = Options::new();
+            opts.create_if_missing = true;
+
+            let database = Database::open(path, opts)?;
+
+            Ok(LevelDbStore { database })
+        }
+    }
+
+    impl ChainStore for LevelDbStore {
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            let read_options = ReadOptions::new();
+            self.database.get(read_options, BytesKey::from_u8(key)).map_err(Into::into)
+        }
+
+        fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+            let write_opts = WriteOptions::new();
+            self.database.put(write_opts, BytesKey::from_u8(key), val).map_err(Into::into)
+        }
+
+        fn delete(&mut self, key: &[u8]) -> Result<()> {
+            let write_opts = WriteOptions::new();
+            self.database.delete(write_opts, BytesKey::from_u8(key)).map_err(Into::into)
+        }
+
+        fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+            let mut writebatch = Writebatch::new();
+            for (key, val) in batch.ops {
+                match val {
+                    Some(val) => writebatch.put(BytesKey(key), &val),
+                    None => writebatch.delete(BytesKey(key)),
+                }
+            }
+            let mut write_opts = WriteOptions::new();
+            write_opts.sync = true;
+            Batch::write(&self.database, write_opts, &writebatch).map_err(Into::into)
+        }
+
+        fn scan_prefix(&self, prefix: &[u8]) -> Box<dyn Iterator<Item = Result<Entry>> + '_> {
+            // `from` would have to borrow the key for as long as the
+            // iterator lives, so seek before the first step instead
+            let iter = self.database.iter(ReadOptions::new());
+            iter.seek(&BytesKey::from_u8(prefix));
+            let prefix = prefix.to_vec();
+            Box::new(
+                iter.map(|(key, val)| (key.0, val))
+                    .take_while(move |(key, _)| key.starts_with(&prefix))
+                    .map(Ok),
+            )
+        }
+
+        fn keys_with_prefix(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>> {
+            let iter = self.database.keys_iter(ReadOptions::new());
+            iter.seek(&BytesKey::from_u8(prefix));
+            Ok(iter
+                .map(|key| key.0)
+                .take_while(|key| key.starts_with(prefix))
+                .collect())
+        }
+    }
+}
+
+#[cfg(feature = "redb")]
+mod redb_store {
+    use super::{ChainStore, Entry, WriteBatch};
+    use crate::bcdb::Result;
+
+    use redb::{Database, Range, TableDefinition};
+    use std::path::Path;
+
+    const TABLE: TableDefinition<&[u8], &[u8]> = TableDefinition::new("chain");
+
+    pub struct RedbStore {
+        database: Database,
+    }
+
+    impl RedbStore {
+        pub fn open(path: &Path) -> Result<Self> {
+            let database = Database::create(path)?;
+            // reads fail on a table that was never written to
+            let txn = database.begin_write()?;
+            txn.open_table(TABLE)?;
+            txn.commit()?;
+            Ok(RedbStore { database })
+        }
+    }
+
+    impl ChainStore for RedbStore {
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            let table = self.database.begin_read()?.open_table(TABLE)?;
+            Ok(table.get(key)?.map(|val| val.value().to_vec()))
+        }
+
+        fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+            let mut batch = WriteBatch::new();
+            batch.put(key, val);
+            self.write_batch(batch)
+        }
+
+        fn delete(&mut self, key: &[u8]) -> Result<()> {
+            let mut batch = WriteBatch::new();
+            batch.delete(key);
+            self.write_batch(batch)
+        }
+
+        fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+            let txn = self.database.begin_write()?;
+            {
+                let mut table = txn.open_table(TABLE)?;
+                for (key, val) in batch.ops {
+                    match val {
+                        Some(val) => table.insert(key.as_slice(), val.as_slice())?,
+                        None => table.remove(key.as_slice())?,
+                    };
+                }
+            }
+            txn.commit()?;
+            Ok(())
+        }
+
+        fn scan_prefix(&self, prefix: &[u8]) -> Box<dyn Iterator<Item = Result<Entry>> + '_> {
+            let range = match self.range_from(prefix) {
+                Ok(range) => range,
+                Err(err) => return Box::new(std::iter::once(Err(err))),
+            };
+            let prefix = prefix.to_vec();
+            Box::new(
+                range
+                    .map(|entry| {
+                        let (key, val) = entry?;
+                        Ok((key.value().to_vec(), val.value().to_vec()))
+                    })
+                    .take_while(move |entry: &Result<Entry>| {
+                        entry.as_ref().map_or(true, |(key, _)| key.starts_with(&prefix))
+                    }),
+            )
+        }
+    }
+
+    impl RedbStore {
+        /// Returns the entries from `start` on, in a read transaction of their own.
+        fn range_from(&self, start: &[u8]) -> Result<Range<'static, &'static [u8], &'static [u8]>> {
+            let table = self.database.begin_read()?.open_table(TABLE)?;
+            Ok(table.range(start..)?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils;
+
+    /// Runs the same operations against any store.
+    fn exercise(store: &mut dyn ChainStore) {
+        store.put(b"a1", b"x").unwrap();
+        store.put(b"b1", b"y").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"a2", b"z");
+        batch.put(b"a0", b"w");
+        batch.delete(b"a1");
+        batch.put(b"c", b"v");
+        store.write_batch(batch).unwrap();
+        store.delete(b"c").unwrap();
+
+        assert_eq!(store.get(b"a1").unwrap(), None);
+        assert_eq!(store.get(b"b1").unwrap(), Some(b"y".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), None);
+        let entries: Vec<Entry> = store.scan_prefix(b"a").map(Result::unwrap).collect();
+        assert_eq!(entries, vec![(b"a0".to_vec(), b"w".to_vec()), (b"a2".to_vec(), b"z".to_vec())]);
+        assert_eq!(store.keys_with_prefix(b"b").unwrap(), vec![b"b1".to_vec()]);
+        assert!(store.keys_with_prefix(b"d").unwrap().is_empty());
+    }
+
+    #[test]
+    fn stores_agree_on_reads_writes_and_scans() {
+        exercise(&mut MemoryStore::new());
+
+        let dir = utils::TempDir::new("store-test");
+        for backend in [Backend::LevelDb, Backend::Redb] {
+            let path = dir.path().join(backend.name());
+            match DiskStore::open(backend, &path) {
+                Ok(mut store) => exercise(&mut store),
+                Err(err) => assert!(!backend.is_available(), "{}: {}", backend, err),
+            }
+        }
+    }
+}
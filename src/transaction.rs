@@ -1,11 +1,19 @@
-use crate::{utils, wallets::Wallets, wallet, blockchain::Blockchain};
+use crate::{
+    blockchain::Blockchain,
+    coinselect::CoinSelector,
+    encode::{self, Decodable, Encodable, Reader},
+    store::ChainStore,
+    utils, wallet,
+    wallets::Wallets,
+};
 use serde::{Deserialize, Serialize};
-use data_encoding::HEXLOWER;
+use std::collections::HashSet;
+use std::fmt;
 
-// mining reward
-pub const SUBSIDY: i32 = 10;
+/// Version written at the start of every serialized transaction.
+pub const TX_VERSION: i32 = 1;
 
-#[derive(Default, Serialize, Deserialize, Clone)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct TXInput {
     pub txid: Vec<u8>,
     pub vout: usize,
@@ -13,15 +21,16 @@ pub struct TXInput {
     pub pub_key: Vec<u8>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TXOutput {
     pub value: i32,     // amount of coins
     pub pub_key_hash: Vec<u8>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Transaction {
-    pub id: Vec<u8>, 
+    pub id: Vec<u8>,
+    pub version: i32,
     pub vin: Vec<TXInput>,
     pub vout: Vec<TXOutput>,
 }
@@ -76,19 +85,31 @@ impl TXOutput {
 }
 
 impl Transaction {
-    fn hash(&mut self) -> Vec<u8>{
-        let tx_copy = Transaction {
-            id: vec![],
-            vin: self.vin.clone(),
-            vout: self.vout.clone(),
-        };
-        let data = bincode::serialize(&tx_copy).unwrap();
-        utils::compute_sha256(&data)
+    /// Computes the transaction id: the double SHA-256 of the serialized
+    /// transaction with every signature left empty. Signatures are left out,
+    /// so the id is known before signing and stays the same after.
+    pub fn hash(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.encode_with(&mut data, false);
+        utils::sha256d(&data)
+    }
+
+    /// Encodes the transaction, leaving signatures empty unless `with_signatures`.
+    fn encode_with(&self, out: &mut Vec<u8>, with_signatures: bool) {
+        self.version.consensus_encode(out);
+        encode::write_varint(out, self.vin.len() as u64);
+        for input in &self.vin {
+            write_outpoint(out, input);
+            let signature: &[u8] = if with_signatures { &input.signature } else { &[] };
+            encode::write_var_bytes(out, signature);
+            encode::write_var_bytes(out, &input.pub_key);
+        }
+        self.vout.consensus_encode(out);
     }
 
     /// Determines if the transaction is a coinbase transaction.
     pub fn is_coinbase(&self) -> bool {
-        self.vin.len() == 1 && self.vin[0].txid.len() == 0 && self.vin[0].vout == 0
+        self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == 0
     }
 
     pub fn get_id(&self) -> Vec<u8> {
@@ -103,94 +124,276 @@ impl Transaction {
         self.vout.clone()
     }
 
-    /// Creates a trimmed copy of the transaction to be used in signing.
-    fn trimmed_copy(&self) -> Transaction {
-        let mut inputs: Vec<TXInput> = Vec::new();
-        let mut outputs: Vec<TXOutput> = Vec::new();
-        for input in &self.vin {
-            inputs.push(TXInput {
-                txid: input.get_txid(),
-                vout: input.get_vout(),
-                signature: Vec::new(),
-                pub_key: Vec::new(),
-            });
+    /// Computes the digest an input's signature commits to, in the style of
+    /// BIP143. The preimage is, in the encoding of [`crate::encode`]:
+    ///
+    /// 1. `hash_prevouts`: sha256d of every input's outpoint, `txid` as a
+    ///    byte string then `vout` as a `u32`, or 32 zero bytes with
+    ///    ANYONECANPAY
+    /// 2. the outpoint of the input being signed
+    /// 3. the `pub_key_hash` of the output it spends
+    /// 4. the value of that output as an `i64`
+    /// 5. `hash_outputs`: sha256d of every encoded output with ALL, of the
+    ///    output with the same index only
+    ///    with SINGLE, or 32 zero bytes with NONE or a SINGLE input without
+    ///    a matching output
+    /// 6. the sighash type as a `u32`
+    ///
+    /// The digest is the sha256d of the preimage.
+    pub fn signature_hash(
+        &self,
+        input_index: usize,
+        prev_output: &TXOutput,
+        sighash_type: SigHashType,
+    ) -> Vec<u8> {
+        let zero = vec![0u8; 32];
+        let hash_prevouts = if sighash_type.anyone_can_pay {
+            zero.clone()
+        } else {
+            let mut data = Vec::new();
+            for input in &self.vin {
+                write_outpoint(&mut data, input);
+            }
+            utils::sha256d(&data)
+        };
+        let hash_outputs = match sighash_type.base {
+            SigHashBase::All => {
+                let mut data = Vec::new();
+                for output in &self.vout {
+                    output.consensus_encode(&mut data);
+                }
+                utils::sha256d(&data)
+            }
+            SigHashBase::Single if input_index < self.vout.len() => {
+                let mut data = Vec::new();
+                self.vout[input_index].consensus_encode(&mut data);
+                utils::sha256d(&data)
+            }
+            _ => zero,
+        };
+
+        let mut preimage = Vec::new();
+        preimage.extend_from_slice(&hash_prevouts);
+        write_outpoint(&mut preimage, &self.vin[input_index]);
+        encode::write_var_bytes(&mut preimage, &prev_output.pub_key_hash);
+        (prev_output.value as i64).consensus_encode(&mut preimage);
+        preimage.extend_from_slice(&hash_outputs);
+        (sighash_type.to_byte() as u32).consensus_encode(&mut preimage);
+        utils::sha256d(&preimage)
+    }
+
+    /// Signs one input spending `prev_output`. The signature is the 64-byte
+    /// `r || s` of the signature hash followed by the sighash type byte.
+    pub fn sign_input(
+        &mut self,
+        input_index: usize,
+        prev_output: &TXOutput,
+        private_key: &[u8],
+        sighash_type: SigHashType,
+    ) {
+        let sighash = self.signature_hash(input_index, prev_output, sighash_type);
+        let mut signature = utils::ecdsa_p256_sha256_sign(private_key, &sighash);
+        signature.push(sighash_type.to_byte());
+        self.vin[input_index].signature = signature;
+    }
+
+    /// Verifies the signature of one input spending `prev_output`.
+    pub fn verify_input(&self, input_index: usize, prev_output: &TXOutput) -> bool {
+        let input = &self.vin[input_index];
+        let Some((&type_byte, signature)) = input.signature.split_last() else {
+            return false;
+        };
+        let Some(sighash_type) = SigHashType::from_byte(type_byte) else {
+            return false;
+        };
+        if !input.uses_key(&prev_output.pub_key_hash) {
+            return false;
         }
-        for output in &self.vout {
-            outputs.push(output.clone());
+        let sighash = self.signature_hash(input_index, prev_output, sighash_type);
+        utils::ecdsa_p256_sha256_sign_verify(&input.pub_key, signature, &sighash)
+    }
+
+    /// Verifies that each input of a transaction spends an unspent output
+    /// and carries a valid signature for it.
+    pub fn verify<S: ChainStore>(&self, blockchain: &Blockchain<S>) -> bool {
+        if self.is_coinbase() {
+            return true;
         }
-        Transaction {
-            id: self.id.clone(),
-            vin: inputs,
-            vout: outputs,
+
+        (0..self.vin.len()).all(|idx| {
+            self.prev_output(blockchain, idx)
+                .is_some_and(|prev_output| self.verify_input(idx, &prev_output))
+        })
+    }
+
+    /// Returns what the inputs are worth beyond the outputs, or `None` if
+    /// one of the spent outputs is not in the UTXO set.
+    pub fn fee<S: ChainStore>(&self, blockchain: &Blockchain<S>) -> Option<i64> {
+        let mut fee = 0i64;
+        for idx in 0..self.vin.len() {
+            fee += self.prev_output(blockchain, idx)?.value as i64;
         }
+        Some(fee - self.vout.iter().map(|output| output.value as i64).sum::<i64>())
     }
 
-    /// Signs each input of a transaction.
-    fn sign(&mut self, blockchain: &Blockchain, private_key: Vec<u8>) {
-        let mut tx_copy = self.trimmed_copy();
+    /// Finds the unspent output spent by an input.
+    fn prev_output<S: ChainStore>(
+        &self,
+        blockchain: &Blockchain<S>,
+        input_index: usize,
+    ) -> Option<TXOutput> {
+        let input = &self.vin[input_index];
+        blockchain.get_coin(&input.txid, input.vout).map(|coin| coin.output)
+    }
+}
 
-        for (id, vin) in self.vin.iter_mut().enumerate() {
-            // find the previous transaction
-            let prev_tx_option = blockchain.find_transaction(&vin.get_txid());
-            if prev_tx_option.is_none() {
-                panic!("ERROR: Previous transaction is not correct");
-            }
+/// Which outputs a signature commits to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SigHashBase {
+    /// Every output.
+    All,
+    /// No output, so anyone may change where the coins go.
+    None,
+    /// Only the output with the same index as the input.
+    Single,
+}
+
+/// The sighash type of a signature: which outputs it commits to and whether
+/// it commits to the other inputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SigHashType {
+    pub base: SigHashBase,
+    /// If set, the signature commits to its own input only, so others may
+    /// add inputs.
+    pub anyone_can_pay: bool,
+}
 
-            let prev_tx = prev_tx_option.unwrap();
-            tx_copy.vin[id].signature = Vec::new();
-            tx_copy.vin[id].pub_key = prev_tx.vout[vin.vout].pub_key_hash.clone();
-            tx_copy.id = tx_copy.hash();
-            tx_copy.vin[id].pub_key = Vec::new();
+impl SigHashType {
+    pub const ALL: SigHashType = SigHashType::new(SigHashBase::All, false);
+    pub const NONE: SigHashType = SigHashType::new(SigHashBase::None, false);
+    pub const SINGLE: SigHashType = SigHashType::new(SigHashBase::Single, false);
+    const ANYONECANPAY: u8 = 0x80;
 
-            // Sign the transaction using the private key
-            let tx_bytes = bincode::serialize(&tx_copy).expect("ERROR: Failed to serialize transaction");
-            let signature = utils::ecdsa_p256_sha256_sign(&private_key, &tx_bytes);
-            vin.signature = signature
+    pub const fn new(base: SigHashBase, anyone_can_pay: bool) -> Self {
+        SigHashType {
+            base,
+            anyone_can_pay,
         }
     }
 
-    /// Verifies the signatures of each input of a transaction.
-    pub fn verify(&self, blockchain: &Blockchain) -> bool {
-        if self.is_coinbase() {
-            return true;
+    /// Encodes the type as in Bitcoin: 1, 2 or 3 for ALL, NONE and SINGLE,
+    /// with 0x80 added for ANYONECANPAY.
+    pub fn to_byte(self) -> u8 {
+        let base = match self.base {
+            SigHashBase::All => 0x01,
+            SigHashBase::None => 0x02,
+            SigHashBase::Single => 0x03,
+        };
+        if self.anyone_can_pay {
+            base | Self::ANYONECANPAY
+        } else {
+            base
         }
+    }
 
-        let mut tx_copy = self.trimmed_copy();
-        
-        for (idx, vin) in  self.vin.iter().enumerate() {
-            let prev_tx_option = blockchain.find_transaction(&vin.get_txid());
-            if prev_tx_option.is_none() {
-                panic!("ERROR: Previous transaction is not correct");
-            }
-            let prev_tx = prev_tx_option.unwrap();
-            tx_copy.vin[idx].signature = Vec::new();
-            tx_copy.vin[idx].pub_key = prev_tx.vout[vin.vout].pub_key_hash.clone();
-            tx_copy.id = tx_copy.hash();
-            tx_copy.vin[idx].pub_key = Vec::new();
-
-            // Verify the transaction using the public key
-            let tx_bytes = bincode::serialize(&tx_copy).expect("ERROR: Failed to serialize transaction");
-            let verify = utils::ecdsa_p256_sha256_sign_verify(
-                &vin.pub_key, 
-                &vin.signature,
-                &tx_bytes
-            );
-            if !verify {
-                return false;
-            }
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        let base = match byte & !Self::ANYONECANPAY {
+            0x01 => SigHashBase::All,
+            0x02 => SigHashBase::None,
+            0x03 => SigHashBase::Single,
+            _ => return None,
+        };
+        Some(SigHashType::new(base, byte & Self::ANYONECANPAY != 0))
+    }
+}
+
+/// Formats the type as in Bitcoin Core, e.g. `ALL` or `SINGLE|ANYONECANPAY`.
+impl fmt::Display for SigHashType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let base = match self.base {
+            SigHashBase::All => "ALL",
+            SigHashBase::None => "NONE",
+            SigHashBase::Single => "SINGLE",
+        };
+        if self.anyone_can_pay {
+            write!(f, "{}|ANYONECANPAY", base)
+        } else {
+            write!(f, "{}", base)
         }
+    }
+}
+
+fn write_outpoint(out: &mut Vec<u8>, input: &TXInput) {
+    encode::write_var_bytes(out, &input.txid);
+    (input.vout as u32).consensus_encode(out);
+}
 
-        true
+/// An output is its value as an `i64` followed by the `pub_key_hash`.
+impl Encodable for TXOutput {
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+        (self.value as i64).consensus_encode(out);
+        encode::write_var_bytes(out, &self.pub_key_hash);
     }
+}
 
+impl Decodable for TXOutput {
+    fn consensus_decode(reader: &mut Reader<'_>) -> encode::Result<Self> {
+        let value = i32::try_from(i64::consensus_decode(reader)?)
+            .map_err(|_| encode::Error::OutOfRange)?;
+        Ok(TXOutput {
+            value,
+            pub_key_hash: reader.read_var_bytes()?,
+        })
+    }
 }
 
-/// Creates a new coinbase transaction. It has no inputs, produce one output.
-pub fn new_coinbase_tx(to: &str) -> Transaction {
-    let txin = TXInput::default();
-    let txout = TXOutput::new(SUBSIDY, to);
+/// A transaction is its version, the inputs (`txid`, `vout` as a `u32`,
+/// signature and public key) and the outputs. The id is not encoded; it is
+/// recomputed when decoding.
+impl Encodable for Transaction {
+    fn consensus_encode(&self, out: &mut Vec<u8>) {
+        self.encode_with(out, true);
+    }
+}
+
+impl Decodable for Transaction {
+    fn consensus_decode(reader: &mut Reader<'_>) -> encode::Result<Self> {
+        let version = i32::consensus_decode(reader)?;
+        if version != TX_VERSION {
+            return Err(encode::Error::UnsupportedVersion(version));
+        }
+        let count = reader.read_len()?;
+        let mut vin = Vec::with_capacity(count);
+        for _ in 0..count {
+            vin.push(TXInput {
+                txid: reader.read_var_bytes()?,
+                vout: u32::consensus_decode(reader)? as usize,
+                signature: reader.read_var_bytes()?,
+                pub_key: reader.read_var_bytes()?,
+            });
+        }
+        let mut tx = Transaction {
+            id: Vec::new(),
+            version,
+            vin,
+            vout: Vec::consensus_decode(reader)?,
+        };
+        tx.id = tx.hash();
+        Ok(tx)
+    }
+}
+
+/// Creates a new coinbase transaction paying `subsidy` to `to`. Its single
+/// input spends nothing and carries arbitrary `data`.
+pub fn new_coinbase_tx(to: &str, data: &[u8], subsidy: i32) -> Transaction {
+    let txin = TXInput {
+        pub_key: data.to_vec(),
+        ..Default::default()
+    };
+    let txout = TXOutput::new(subsidy, to);
     let mut tx = Transaction {
         id: vec![],
+        version: TX_VERSION,
         vin: vec![txin],
         vout: vec![txout],
     };
@@ -198,54 +401,342 @@ pub fn new_coinbase_tx(to: &str) -> Transaction {
     tx
 }
 
-pub fn new_utxo_transaction(
+/// Pays `amount` from `from` to `to`, returning the change to `from`. The
+/// wallet is first brought up to date with `blockchain`, since
+/// [`new_transaction`] refuses to spend from a wallet that is behind.
+pub fn new_utxo_transaction<S: ChainStore>(
     from: &str,
     to: &str,
     amount: i32,
-    blockchain: &Blockchain,
-) -> Transaction {
-    // 1. find wallet
-    let binding = Wallets::new();
-    let wallet = binding
-        .get_wallet(from)
-        .expect("ERROR: Wallet not found");
-    let public_key_hash = utils::hash_pub_key(&wallet.get_public_key());
-
-    //2. find unspent outputs
-    let (accumlated, valid_outputs) =
-        blockchain.find_spendable_outputs(&public_key_hash, amount);
-    if accumlated < amount {
-        panic!("ERROR: Not enough funds");
-    }
-
-    let mut inputs = Vec::new();
-    for (txid_hex, outs) in valid_outputs {
-        let txid = HEXLOWER.decode(txid_hex.as_bytes()).unwrap();
-        for out in outs {
-            let input = TXInput {
-                txid: txid.clone(), // last transaction ID
-                vout: out,
-                signature: Vec::new(),
-                pub_key: wallet.get_public_key(),
-            };
-            inputs.push(input);
-        }
+    blockchain: &Blockchain<S>,
+    wallets: &mut Wallets,
+) -> Result<Transaction, String> {
+    wallets.sync(blockchain);
+    let recipients = [(to.to_string(), amount)];
+    let selector = CoinSelector::default();
+    let from = [from.to_string()];
+    new_transaction(&recipients, &from, &[], Some(&from[0]), &selector, blockchain, wallets)
+}
+
+/// Creates and signs a transaction like [`new_unsigned_transaction`],
+/// except that only addresses we hold the key of are spent from by default.
+pub fn new_transaction<S: ChainStore>(
+    recipients: &[(String, i32)],
+    from: &[String],
+    inputs: &[(Vec<u8>, usize)],
+    change_address: Option<&str>,
+    selector: &CoinSelector,
+    blockchain: &Blockchain<S>,
+    wallets: &Wallets,
+) -> Result<Transaction, String> {
+    let mut addresses = wallets.get_addresses();
+    addresses.sort();
+    let from = if from.is_empty() { &addresses } else { from };
+    let mut tx = new_unsigned_transaction(
+        recipients,
+        from,
+        inputs,
+        change_address,
+        selector,
+        blockchain,
+        wallets,
+    )?;
+    wallets.sign_transaction(&mut tx)?;
+    Ok(tx)
+}
+
+/// Creates a transaction paying each `(address, amount)` of `recipients`,
+/// without signing it. It spends exactly the `inputs` outpoints if any are
+/// given. Otherwise `selector` chooses among the unlocked outputs of the
+/// `from` addresses, or of every address in `wallets`, watch-only ones
+/// included, if `from` is empty. Change goes to `change_address`, or back
+/// to the address of the first input spent. It spends the coins `wallets`
+/// tracks, so the wallet must be synced to the tip of `blockchain`.
+pub fn new_unsigned_transaction<S: ChainStore>(
+    recipients: &[(String, i32)],
+    from: &[String],
+    inputs: &[(Vec<u8>, usize)],
+    change_address: Option<&str>,
+    selector: &CoinSelector,
+    blockchain: &Blockchain<S>,
+    wallets: &Wallets,
+) -> Result<Transaction, String> {
+    if recipients.is_empty() {
+        return Err("No recipients".to_string());
     }
+    if recipients.iter().any(|(_, amount)| *amount <= 0) {
+        return Err("Amounts must be positive".to_string());
+    }
+    let total = recipients
+        .iter()
+        .try_fold(0i32, |total, (_, amount)| total.checked_add(*amount))
+        .ok_or("Total amount is too large")?;
 
-    let mut outputs = vec![TXOutput::new(amount, to)];
-    if accumlated > amount {
-        outputs.push(TXOutput::new(accumlated - amount, from));
+    if wallets.synced_tip() != Some(blockchain.tip.as_slice()) {
+        return Err("Wallet is not synced with the chain".to_string());
     }
 
+    let coins = wallets.unspent_coins();
+    let mut candidates = Vec::new();
+    if inputs.is_empty() {
+        let sources: Vec<String> = if from.is_empty() {
+            let mut addresses = wallets.get_addresses();
+            addresses.extend(wallets.get_watch_only_addresses());
+            addresses.sort();
+            addresses
+        } else {
+            let mut seen = HashSet::new();
+            from.iter().filter(|a| seen.insert(*a)).cloned().collect()
+        };
+        for address in &sources {
+            if !wallets.is_mine(address) {
+                return Err(format!("Wallet not found: {}", address));
+            }
+            for coin in coins.iter().filter(|coin| &coin.address == address) {
+                if !wallets.is_locked(&coin.txid, coin.vout) {
+                    candidates.push(*coin);
+                }
+            }
+        }
+    } else {
+        let mut seen = HashSet::new();
+        for (txid, vout) in inputs.iter().filter(|outpoint| seen.insert(*outpoint)) {
+            let coin = coins
+                .iter()
+                .find(|coin| &coin.txid == txid && coin.vout == *vout)
+                .ok_or_else(|| {
+                    format!("{}:{} is not an unspent wallet output", utils::hex_string(txid), vout)
+                })?;
+            candidates.push(*coin);
+        }
+    }
+    let values: Vec<i32> = candidates.iter().map(|coin| coin.output.value).collect();
+    let selection = if inputs.is_empty() {
+        selector.select(&values, total)
+    } else {
+        selector.select_all(&values, total)
+    }
+    .ok_or("Not enough funds")?;
+    let selected: Vec<_> = selection.selected.iter().map(|&i| &candidates[i]).collect();
+
+    let mut outputs: Vec<TXOutput> = recipients
+        .iter()
+        .map(|(address, amount)| TXOutput::new(*amount, address))
+        .collect();
+    if selection.change > 0 {
+        let change = match change_address {
+            Some(address) => address.to_string(),
+            None => selected[0].address.clone(),
+        };
+        outputs.push(TXOutput::new(selection.change, &change));
+    }
+
+    let inputs = selected
+        .iter()
+        .map(|coin| TXInput {
+            txid: coin.txid.clone(),
+            vout: coin.vout,
+            signature: Vec::new(),
+            // left empty for watch-only addresses imported without a key
+            pub_key: wallets.get_public_key(&coin.address).unwrap_or_default(),
+        })
+        .collect();
     let mut tx = Transaction {
         id: Vec::new(),
+        version: TX_VERSION,
         vin: inputs,
         vout: outputs,
     };
-
     tx.id = tx.hash();
+    Ok(tx)
+}
 
-    tx.sign(blockchain, wallet.get_private_key());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::{self, Wallet};
 
-    tx
-}
\ No newline at end of file
+    fn spend(wallet: &Wallet) -> (Transaction, TXOutput) {
+        let prev_output = TXOutput {
+            value: 50,
+            pub_key_hash: utils::hash_pub_key(&wallet.public_key),
+        };
+        let input = TXInput {
+            txid: vec![1; 32],
+            vout: 0,
+            signature: Vec::new(),
+            pub_key: wallet.get_public_key(),
+        };
+        let mut tx = Transaction {
+            id: Vec::new(),
+            version: TX_VERSION,
+            vin: vec![input],
+            vout: vec![
+                TXOutput::new(30, &wallet::calc_address(0x00, &[2; 20])),
+                TXOutput::new(20, &wallet::calc_address(0x00, &[3; 20])),
+            ],
+        };
+        tx.id = tx.hash();
+        (tx, prev_output)
+    }
+
+    #[test]
+    fn transaction_encoding_round_trips() {
+        // vector from docs/serialization.md
+        let coinbase = new_coinbase_tx(&wallet::calc_address(0x6f, &[7; 20]), b"test", 10);
+        let bytes = encode::serialize(&coinbase);
+        assert_eq!(
+            hex::encode(&bytes),
+            "01000000010000000000000474657374010a00000000000000140707070707070707070707070707070707070707"
+        );
+        assert_eq!(
+            hex::encode(&coinbase.id),
+            "10005e3af3b298b82a402ee410d38ec4e9068f3156a479d6ace8cd53a8bd71b7"
+        );
+
+        let wallet = Wallet::new();
+        let (mut tx, prev_output) = spend(&wallet);
+        tx.sign_input(0, &prev_output, &wallet.private_key, SigHashType::ALL);
+        let bytes = encode::serialize(&tx);
+        let decoded: Transaction = encode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded.id, tx.id);
+        assert_eq!(encode::serialize(&decoded), bytes);
+        assert!(decoded.verify_input(0, &prev_output));
+
+        let mut unknown_version = bytes.clone();
+        unknown_version[0] = 2;
+        assert_eq!(
+            encode::deserialize::<Transaction>(&unknown_version).err(),
+            Some(encode::Error::UnsupportedVersion(2))
+        );
+    }
+
+    #[test]
+    fn new_transaction_pays_several_recipients_from_several_addresses() {
+        let dir = utils::TempDir::new("send-test");
+        let config = crate::config::Config::builder()
+            .data_dir(dir.path())
+            .network(crate::network::Network::Regtest)
+            .build();
+        let mut wallets = Wallets::new(&config);
+        let first = wallets.create_wallet();
+        let second = wallets.create_wallet();
+        let payee = wallet::calc_address(0x6f, &[5; 20]);
+        let change = wallet::calc_address(0x6f, &[6; 20]);
+
+        let mut blockchain = Blockchain::in_memory(config.params(), &first);
+        blockchain.generate(1, &second, Vec::new());
+        wallets.sync(&blockchain);
+        let recipients = vec![(payee.clone(), 12), (payee, 5)];
+        let selector = CoinSelector::default();
+        let tx = new_transaction(
+            &recipients,
+            &[],
+            &[],
+            Some(&change),
+            &selector,
+            &blockchain,
+            &wallets,
+        )
+        .unwrap();
+
+        assert_eq!(tx.vin.len(), 2);
+        let values: Vec<i32> = tx.vout.iter().map(|out| out.value).collect();
+        assert_eq!(values, vec![12, 5, 3]);
+        assert!(tx.vout[2].is_locked_with_key(&[6; 20]));
+        assert!(tx.verify(&blockchain));
+
+        let from = [first.clone()];
+        assert!(new_transaction(&recipients, &from, &[], None, &selector, &blockchain, &wallets)
+            .is_err());
+
+        // a locked output is only spent when asked for by hand
+        let coin = wallets.unspent_coins()[0].clone();
+        assert!(wallets.lock_coin(&coin.txid, coin.vout));
+        assert!(new_transaction(&recipients, &[], &[], None, &selector, &blockchain, &wallets)
+            .is_err());
+        let inputs = [(coin.txid.clone(), coin.vout)];
+        let pay = [(change, 7)];
+        let tx = new_transaction(&pay, &[], &inputs, None, &selector, &blockchain, &wallets)
+            .unwrap();
+        assert_eq!(tx.vin.len(), 1);
+        assert_eq!(tx.vin[0].txid, coin.txid);
+        assert_eq!(tx.vout[1].value, 3);
+
+        // new_utxo_transaction catches the wallet up with the chain first
+        blockchain.generate(1, &first, Vec::new());
+        let payee = wallet::calc_address(0x6f, &[5; 20]);
+        let tx = new_utxo_transaction(&first, &payee, 4, &blockchain, &mut wallets).unwrap();
+        assert_eq!(wallets.synced_height(), Some(2));
+        assert!(tx.verify(&blockchain));
+    }
+
+    #[test]
+    fn signing_is_deterministic() {
+        let wallet = Wallet::new();
+        let (mut tx, prev_output) = spend(&wallet);
+        tx.sign_input(0, &prev_output, &wallet.private_key, SigHashType::ALL);
+        let first = tx.vin[0].signature.clone();
+        tx.sign_input(0, &prev_output, &wallet.private_key, SigHashType::ALL);
+        assert_eq!(tx.vin[0].signature, first);
+        assert_eq!(first.len(), 65);
+        assert_eq!(first[64], 0x01);
+        assert!(tx.verify_input(0, &prev_output));
+    }
+
+    #[test]
+    fn signature_commits_to_amount_and_sighash_type() {
+        let wallet = Wallet::new();
+        let (mut tx, prev_output) = spend(&wallet);
+        tx.sign_input(0, &prev_output, &wallet.private_key, SigHashType::ALL);
+
+        let other_amount = TXOutput {
+            value: 51,
+            ..prev_output.clone()
+        };
+        assert!(!tx.verify_input(0, &other_amount));
+
+        let mut changed = tx.clone();
+        changed.vout[1].value = 19;
+        assert!(!changed.verify_input(0, &prev_output));
+
+        let mut relabeled = tx.clone();
+        *relabeled.vin[0].signature.last_mut().unwrap() = SigHashType::NONE.to_byte();
+        assert!(!relabeled.verify_input(0, &prev_output));
+    }
+
+    #[test]
+    fn sighash_types_limit_what_is_signed() {
+        let wallet = Wallet::new();
+        let (mut tx, prev_output) = spend(&wallet);
+
+        tx.sign_input(0, &prev_output, &wallet.private_key, SigHashType::NONE);
+        let mut changed = tx.clone();
+        changed.vout[0].value = 1;
+        assert!(changed.verify_input(0, &prev_output));
+
+        tx.sign_input(0, &prev_output, &wallet.private_key, SigHashType::SINGLE);
+        let mut changed = tx.clone();
+        changed.vout[1].value = 1;
+        assert!(changed.verify_input(0, &prev_output));
+        changed.vout[0].value = 1;
+        assert!(!changed.verify_input(0, &prev_output));
+
+        let all_anyone = SigHashType::new(SigHashBase::All, true);
+        assert_eq!(SigHashType::from_byte(0x81), Some(all_anyone));
+        tx.sign_input(0, &prev_output, &wallet.private_key, all_anyone);
+        let mut extended = tx.clone();
+        extended.vin.push(TXInput {
+            txid: vec![9; 32],
+            ..Default::default()
+        });
+        assert!(extended.verify_input(0, &prev_output));
+        tx.sign_input(0, &prev_output, &wallet.private_key, SigHashType::ALL);
+        let mut extended = tx.clone();
+        extended.vin.push(TXInput {
+            txid: vec![9; 32],
+            ..Default::default()
+        });
+        assert!(!extended.verify_input(0, &prev_output));
+    }
+}
@@ -2,8 +2,51 @@ extern crate bs58;
 
 use sha2::{Digest as SHA256Digest, Sha256};
 use ripemd::Ripemd160;
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use p256::pkcs8::DecodePrivateKey;
 use ring::rand::SystemRandom;
 use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Whether [`log`] writes to stderr instead of stdout.
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static MOCK_TIME: Cell<Option<i64>> = const { Cell::new(None) };
+}
+
+/// Sets a fixed time returned by [`get_time`] on the current thread, or
+/// restores the system clock with `None`. Used to build deterministic chains.
+pub fn set_mock_time(time: Option<i64>) {
+    MOCK_TIME.with(|mock| mock.set(time));
+}
+
+/// Returns the current Unix time in seconds, or the mock time if one is set.
+pub fn get_time() -> i64 {
+    MOCK_TIME.with(|mock| mock.get()).unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    })
+}
+
+/// Sends progress messages to stderr, leaving stdout to the result of a
+/// command, or back to stdout with `false`.
+pub fn set_log_to_stderr(enabled: bool) {
+    LOG_TO_STDERR.store(enabled, Ordering::Relaxed);
+}
+
+/// Prints a progress message.
+pub fn log(message: &str) {
+    if LOG_TO_STDERR.load(Ordering::Relaxed) {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
 
 /// Encodes a byte slice into its hexadecimal representation.
 pub fn hex_string(bytes: &[u8]) -> String {
@@ -15,6 +58,10 @@ pub fn compute_sha256(data: &[u8]) -> Vec<u8> {
     Sha256::digest(data).as_slice().to_vec()
 }
 
+/// Computes SHA-256 twice, as used for block and Merkle tree hashes.
+pub fn sha256d(data: &[u8]) -> Vec<u8> {
+    compute_sha256(&compute_sha256(data))
+}
 
 /// Computes the RIPEMD-160 hash of the given data.
 pub fn compute_ripemd160(data: &[u8]) -> Vec<u8> {
@@ -44,12 +91,14 @@ pub fn generate_key_pair() -> Vec<u8> {
     pkcs8.as_ref().to_vec()
 }
 
-/// Sign the given message using ECDSA P256 SHA256
+/// Sign the given message using ECDSA P256 SHA256. The nonce is derived
+/// from the key and the message as in RFC 6979, so signing the same message
+/// twice gives the same 64-byte `r || s` signature.
 pub fn ecdsa_p256_sha256_sign(pkcs8: &[u8], message: &[u8]) -> Vec<u8> {
-    let rng = ring::rand::SystemRandom::new();
-    let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8, &rng).unwrap();
-    let rng = ring::rand::SystemRandom::new();
-    key_pair.sign(&rng, message).unwrap().as_ref().to_vec()
+    let secret_key = p256::SecretKey::from_pkcs8_der(pkcs8).expect("ERROR: Invalid private key");
+    let signing_key = SigningKey::from(secret_key);
+    let signature: Signature = signing_key.sign(message);
+    signature.to_bytes().to_vec()
 }
 
 /// Verify the given signature using ECDSA P256 SHA256
@@ -63,4 +112,34 @@ pub fn ecdsa_p256_sha256_sign_verify(public_key: &[u8], signature: &[u8], messag
 pub fn hash_pub_key(pub_key: &[u8]) -> Vec<u8> {
     let pub_key_sha256 = compute_sha256(pub_key);
     compute_ripemd160(&pub_key_sha256)
-}
\ No newline at end of file
+}
+
+/// A directory under the system temp dir that is removed when dropped, so a
+/// failing test does not leave files behind.
+#[cfg(test)]
+pub(crate) struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// Creates an empty directory whose name starts with `name` and is unique
+    /// to this process and call.
+    pub(crate) fn new(name: &str) -> TempDir {
+        static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("bitcoin-{}-{}-{}", name, std::process::id(), n));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -1,20 +1,115 @@
-use crate::wallet::Wallet;
+use crate::{
+    block::Block,
+    blockchain::Blockchain,
+    config::Config,
+    store::ChainStore,
+    transaction::{SigHashType, TXOutput, Transaction},
+    utils,
+    wallet::{self, Wallet},
+};
 
-use std::collections::HashMap;
-use std::env::current_dir;
-use std::fs::{File, OpenOptions};
-use std::io::{BufWriter, Read, Write};
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 
-pub const WALLET_FILE: &str = "wallets.dat";
+/// First bytes of a wallet file, followed by the format version.
+const WALLET_MAGIC: [u8; 4] = *b"BRSW";
+/// Version of the wallet file format written by [`Wallets::save_to_file`].
+pub const WALLET_FORMAT_VERSION: u32 = 1;
+/// Number of numbered backups kept next to the wallet file.
+pub const WALLET_BACKUPS: usize = 3;
+
+/// An output paying one of the wallet's addresses.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WalletCoin {
+    pub txid: Vec<u8>,
+    pub vout: usize,
+    pub output: TXOutput,
+    pub address: String,
+    /// Block that created the output and its height.
+    pub block_hash: Vec<u8>,
+    pub height: u64,
+    /// Where the output was spent, if it was.
+    pub spent: Option<CoinSpend>,
+}
+
+/// The transaction and block spending a [`WalletCoin`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CoinSpend {
+    pub txid: Vec<u8>,
+    pub block_hash: Vec<u8>,
+    pub height: u64,
+}
+
+/// An address we watch without holding its private key.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WatchOnly {
+    /// Known if a public key rather than an address was imported; unsigned
+    /// transactions need it in their inputs.
+    pub public_key: Option<Vec<u8>>,
+}
+
+/// What we know about one of our addresses besides its keys.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AddressInfo {
+    pub label: String,
+    /// Unix time the address was created or imported, unknown for
+    /// addresses older than this record.
+    pub created: Option<i64>,
+}
+
+/// The net effect of a transaction on one of our addresses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WalletTransaction {
+    pub txid: Vec<u8>,
+    pub height: u64,
+    pub address: String,
+    /// Received minus spent.
+    pub amount: i64,
+}
+
+/// Everything kept in the wallet file.
+#[derive(Default, Serialize, Deserialize)]
+struct WalletFile {
+    keys: HashMap<String, Wallet>,
+    watch_only: HashMap<String, WatchOnly>,
+    metadata: HashMap<String, AddressInfo>,
+    coins: BTreeMap<(Vec<u8>, usize), WalletCoin>,
+    /// Transactions we signed that are not in a block yet.
+    pending: Vec<Transaction>,
+    /// Hashes of the blocks connected so far, from the genesis block up.
+    blocks: Vec<Vec<u8>>,
+    /// Outpoints left out of automatic coin selection.
+    locked: BTreeSet<(Vec<u8>, usize)>,
+}
 
 pub struct Wallets {
     wallets: HashMap<String, Wallet>,
+    watch_only: HashMap<String, WatchOnly>,
+    metadata: HashMap<String, AddressInfo>,
+    coins: BTreeMap<(Vec<u8>, usize), WalletCoin>,
+    pending: Vec<Transaction>,
+    blocks: Vec<Vec<u8>>,
+    locked: BTreeSet<(Vec<u8>, usize)>,
+    path: PathBuf,
+    address_version: u8,
 }
 
 impl Wallets{
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
         let mut wallets = Wallets {
             wallets: HashMap::new(),
+            watch_only: HashMap::new(),
+            metadata: HashMap::new(),
+            coins: BTreeMap::new(),
+            pending: Vec::new(),
+            blocks: Vec::new(),
+            locked: BTreeSet::new(),
+            path: config.wallet_path().to_path_buf(),
+            address_version: config.params().address_version,
         };
         wallets.load_from_file();
         wallets
@@ -22,46 +117,856 @@ impl Wallets{
 
     pub fn create_wallet(&mut self) -> String {
         let wallet = Wallet::new();
-        let address = wallet.address();
+        let address = wallet.address(self.address_version);
         self.wallets.insert(address.clone(), wallet);
+        self.record_creation(&address);
         address
     }
 
-    pub fn get_addresses(&self) -> Vec<String> {
-        let mut addresses = Vec::new();
-        for (address, _) in &self.wallets {
-            addresses.push(address.clone());
+    /// Adds a wallet holding an existing key and returns its address. An
+    /// address watched until now becomes spendable.
+    pub fn import_wallet(&mut self, wallet: Wallet) -> String {
+        let address = wallet.address(self.address_version);
+        self.watch_only.remove(&address);
+        self.wallets.insert(address.clone(), wallet);
+        self.record_creation(&address);
+        address
+    }
+
+    /// Watches `address` without being able to spend from it. The
+    /// `public_key`, if known, must belong to the address.
+    pub fn import_watch_only(&mut self, address: &str, public_key: Option<Vec<u8>>) {
+        if !self.wallets.contains_key(address) {
+            self.watch_only.insert(address.to_string(), WatchOnly { public_key });
+            self.record_creation(address);
+        }
+    }
+
+    fn record_creation(&mut self, address: &str) {
+        let info = self.metadata.entry(address.to_string()).or_default();
+        info.created.get_or_insert(utils::get_time());
+    }
+
+    /// Returns the label and creation time of one of our addresses.
+    pub fn get_info(&self, address: &str) -> AddressInfo {
+        self.metadata.get(address).cloned().unwrap_or_default()
+    }
+
+    /// Labels one of our addresses; an empty label removes it. Returns
+    /// false if the address is not ours.
+    pub fn set_label(&mut self, address: &str, label: &str) -> bool {
+        if !self.is_mine(address) {
+            return false;
         }
-        addresses
+        self.metadata.entry(address.to_string()).or_default().label = label.to_string();
+        true
+    }
+
+    /// Returns the addresses we hold the private key of.
+    pub fn get_addresses(&self) -> Vec<String> {
+        self.wallets.keys().cloned().collect()
+    }
+
+    pub fn get_watch_only_addresses(&self) -> Vec<String> {
+        self.watch_only.keys().cloned().collect()
     }
 
     pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
         self.wallets.get(address)
     }
 
+    /// Returns true for addresses we hold the key of and watch-only ones.
+    pub fn is_mine(&self, address: &str) -> bool {
+        self.wallets.contains_key(address) || self.watch_only.contains_key(address)
+    }
+
+    pub fn is_watch_only(&self, address: &str) -> bool {
+        self.watch_only.contains_key(address)
+    }
+
+    /// Returns the public key of one of our addresses if we know it.
+    pub fn get_public_key(&self, address: &str) -> Option<Vec<u8>> {
+        match self.wallets.get(address) {
+            Some(wallet) => Some(wallet.get_public_key()),
+            None => self.watch_only.get(address)?.public_key.clone(),
+        }
+    }
+
+    /// Signs every input of `tx`, which must spend outputs of ours. Fails
+    /// without signing anything if one of them is watch-only.
+    pub fn sign_transaction(&self, tx: &mut Transaction) -> Result<(), String> {
+        let mut signers = Vec::with_capacity(tx.vin.len());
+        for input in &tx.vin {
+            let coin = self
+                .coins
+                .get(&(input.txid.clone(), input.vout))
+                .ok_or_else(|| format!("Unknown output {}:{}", utils::hex_string(&input.txid), input.vout))?;
+            let wallet = self.wallets.get(&coin.address).ok_or_else(|| {
+                format!("Cannot sign for watch-only address {}", coin.address)
+            })?;
+            signers.push((coin.output.clone(), wallet));
+        }
+        for (idx, (prev_output, wallet)) in signers.iter().enumerate() {
+            tx.sign_input(idx, prev_output, &wallet.private_key, SigHashType::ALL);
+        }
+        Ok(())
+    }
+
+    /// Returns the net amount each transaction paid to or took from each of
+    /// our addresses, oldest first.
+    pub fn history(&self) -> Vec<WalletTransaction> {
+        let mut amounts: BTreeMap<(u64, Vec<u8>, String), i64> = BTreeMap::new();
+        for coin in self.coins.values() {
+            let value = coin.output.value as i64;
+            *amounts
+                .entry((coin.height, coin.txid.clone(), coin.address.clone()))
+                .or_default() += value;
+            if let Some(spend) = &coin.spent {
+                *amounts
+                    .entry((spend.height, spend.txid.clone(), coin.address.clone()))
+                    .or_default() -= value;
+            }
+        }
+        amounts
+            .into_iter()
+            .map(|((height, txid, address), amount)| WalletTransaction {
+                txid,
+                height,
+                address,
+                amount,
+            })
+            .collect()
+    }
+
+    /// Returns the outputs we own that are neither spent in a block nor by
+    /// a pending transaction, oldest first.
+    pub fn unspent_coins(&self) -> Vec<&WalletCoin> {
+        let pending_spends: HashSet<(&[u8], usize)> = self
+            .pending
+            .iter()
+            .flat_map(|tx| tx.vin.iter().map(|input| (input.txid.as_slice(), input.vout)))
+            .collect();
+        let mut coins: Vec<&WalletCoin> = self
+            .coins
+            .values()
+            .filter(|coin| coin.spent.is_none())
+            .filter(|coin| !pending_spends.contains(&(coin.txid.as_slice(), coin.vout)))
+            .collect();
+        coins.sort_by_key(|coin| coin.height);
+        coins
+    }
+
+    /// Leaves an unspent output of ours out of automatic coin selection.
+    /// Returns false if there is no such output.
+    pub fn lock_coin(&mut self, txid: &[u8], vout: usize) -> bool {
+        let known = self.unspent_coins().iter().any(|coin| coin.txid == txid && coin.vout == vout);
+        if known {
+            self.locked.insert((txid.to_vec(), vout));
+        }
+        known
+    }
+
+    /// Makes a locked output available to coin selection again. Returns
+    /// false if it was not locked.
+    pub fn unlock_coin(&mut self, txid: &[u8], vout: usize) -> bool {
+        self.locked.remove(&(txid.to_vec(), vout))
+    }
+
+    pub fn is_locked(&self, txid: &[u8], vout: usize) -> bool {
+        self.locked.contains(&(txid.to_vec(), vout))
+    }
+
+    /// Returns the transactions we signed that are not in a block yet.
+    pub fn pending_transactions(&self) -> &[Transaction] {
+        &self.pending
+    }
+
+    /// Records a transaction we signed, so that its inputs are not spent
+    /// again before it is mined.
+    pub fn add_pending(&mut self, tx: Transaction) {
+        if !self.pending.iter().any(|pending| pending.id == tx.id) {
+            self.pending.push(tx);
+        }
+    }
+
+    /// Returns the height of the last block the wallet has seen, if any.
+    pub fn synced_height(&self) -> Option<u64> {
+        (self.blocks.len() as u64).checked_sub(1)
+    }
+
+    /// Returns the hash of the last block the wallet has seen, if any.
+    pub fn synced_tip(&self) -> Option<&[u8]> {
+        self.blocks.last().map(Vec::as_slice)
+    }
+
+    /// Brings the coins up to date with the chain, disconnecting blocks
+    /// that are no longer part of it and connecting the new ones.
+    pub fn sync<S: ChainStore>(&mut self, blockchain: &Blockchain<S>) {
+        let mut hashes: Vec<Vec<u8>> = blockchain
+            .header_iterator()
+            .map(|header| header.hash())
+            .collect();
+        hashes.reverse();
+
+        let common = self
+            .blocks
+            .iter()
+            .zip(&hashes)
+            .take_while(|(ours, theirs)| ours == theirs)
+            .count();
+        while self.blocks.len() > common {
+            let hash = self.blocks.last().unwrap().clone();
+            match blockchain.get_block(&hash) {
+                Some(block) => self.disconnect_block(&block),
+                None => {
+                    // without the block we cannot undo it, so start over
+                    return self.rescan(blockchain);
+                }
+            }
+        }
+        for hash in &hashes[common..] {
+            match blockchain.get_block(hash) {
+                Some(block) => self.connect_block(&block),
+                None => {
+                    // pruned or loaded from a snapshot: take the unspent
+                    // outputs from the chain's UTXO set instead
+                    return self.load_utxo_set(blockchain, hashes);
+                }
+            }
+        }
+    }
+
+    /// Replaces the coins with our outputs in the UTXO set of `blockchain`,
+    /// whose active chain is `hashes`. Spent outputs and the history of
+    /// the skipped blocks are not known.
+    fn load_utxo_set<S: ChainStore>(&mut self, blockchain: &Blockchain<S>, hashes: Vec<Vec<u8>>) {
+        let coins = blockchain
+            .db
+            .read_coins()
+            .unwrap_or_else(|e| {
+                panic!(
+                    "Failed to read the UTXO set: {}. Run `checkdb --repair` to rebuild it.",
+                    e
+                )
+            });
+        self.coins.clear();
+        for (txid, vout, coin) in coins {
+            let address = wallet::calc_address(self.address_version, &coin.output.pub_key_hash);
+            if !self.is_mine(&address) {
+                continue;
+            }
+            let vout = vout as usize;
+            let coin = WalletCoin {
+                txid: txid.clone(),
+                vout,
+                output: coin.output,
+                address,
+                block_hash: hashes[coin.height as usize].clone(),
+                height: coin.height,
+                spent: None,
+            };
+            self.coins.insert((txid, vout), coin);
+        }
+        let coins = &self.coins;
+        self.locked.retain(|outpoint| coins.contains_key(outpoint));
+        self.pending.retain(|tx| {
+            tx.vin
+                .iter()
+                .all(|input| coins.contains_key(&(input.txid.clone(), input.vout)))
+        });
+        self.blocks = hashes;
+    }
+
+    /// Forgets the coins and scans the whole chain again, which picks up
+    /// the earlier outputs of imported addresses. On a pruned chain the
+    /// coins come from the UTXO set.
+    pub fn rescan<S: ChainStore>(&mut self, blockchain: &Blockchain<S>) {
+        self.coins.clear();
+        self.blocks.clear();
+        self.sync(blockchain);
+    }
+
+    /// Adds the outputs of `block` paying us and marks the ones it spends.
+    pub fn connect_block(&mut self, block: &Block) {
+        let hash = block.hash();
+        let height = self.blocks.len() as u64;
+        let mut spends = HashSet::new();
+        for tx in &block.transactions {
+            if !tx.is_coinbase() {
+                for input in &tx.vin {
+                    spends.insert((input.txid.clone(), input.vout));
+                    self.locked.remove(&(input.txid.clone(), input.vout));
+                    if let Some(coin) = self.coins.get_mut(&(input.txid.clone(), input.vout)) {
+                        coin.spent = Some(CoinSpend {
+                            txid: tx.id.clone(),
+                            block_hash: hash.clone(),
+                            height,
+                        });
+                    }
+                }
+            }
+            for (vout, output) in tx.vout.iter().enumerate() {
+                let address = wallet::calc_address(self.address_version, &output.pub_key_hash);
+                if !self.is_mine(&address) {
+                    continue;
+                }
+                let coin = WalletCoin {
+                    txid: tx.id.clone(),
+                    vout,
+                    output: output.clone(),
+                    address,
+                    block_hash: hash.clone(),
+                    height,
+                    spent: None,
+                };
+                self.coins.insert((tx.id.clone(), vout), coin);
+            }
+        }
+        // drop pending transactions that were mined or can no longer be
+        let mined: HashSet<&[u8]> = block.transactions.iter().map(|tx| tx.id.as_slice()).collect();
+        self.pending.retain(|tx| {
+            !mined.contains(tx.id.as_slice())
+                && !tx.vin.iter().any(|input| spends.contains(&(input.txid.clone(), input.vout)))
+        });
+        self.blocks.push(hash);
+    }
+
+    /// Undoes [`Wallets::connect_block`] for the last connected block. Our
+    /// transactions in it become pending again.
+    pub fn disconnect_block(&mut self, block: &Block) {
+        let hash = block.hash();
+        if self.blocks.last() != Some(&hash) {
+            return;
+        }
+        for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            let ours = tx
+                .vin
+                .iter()
+                .any(|input| self.coins.contains_key(&(input.txid.clone(), input.vout)));
+            if ours {
+                self.add_pending(tx.clone());
+            }
+        }
+        self.coins.retain(|_, coin| coin.block_hash != hash);
+        for coin in self.coins.values_mut() {
+            if coin.spent.as_ref().is_some_and(|spend| spend.block_hash == hash) {
+                coin.spent = None;
+            }
+        }
+        let coins = &self.coins;
+        self.pending.retain(|tx| {
+            tx.vin
+                .iter()
+                .all(|input| coins.contains_key(&(input.txid.clone(), input.vout)))
+        });
+        self.blocks.pop();
+    }
+
     pub fn load_from_file(&mut self) {
-        let path = current_dir().unwrap().join(WALLET_FILE);
-        if !path.exists() {
+        if !self.path.exists() {
             return;
         }
-        let mut file = File::open(path).unwrap();
+        let mut file = File::open(&self.path).unwrap();
         let metadata = file.metadata().expect("Unable to read metadata");
         let mut buffer = vec![0; metadata.len() as usize];
-        let _ = file.read(&mut buffer).expect("buffer overflow");
-        let wallets = bincode::deserialize(&buffer[..]).expect("Unable to deserialize file data");
-        self.wallets = wallets;
+        file.read_exact(&mut buffer).expect("Unable to read the wallet file");
+        let file = WalletFile::decode(&buffer).unwrap_or_else(|e| {
+            panic!("Unable to load {}: {}", self.path.display(), e)
+        });
+        self.wallets = file.keys;
+        self.watch_only = file.watch_only;
+        self.metadata = file.metadata;
+        self.coins = file.coins;
+        self.pending = file.pending;
+        self.blocks = file.blocks;
+        self.locked = file.locked;
     }
 
+    /// Saves the wallet without ever leaving a partly written file: the
+    /// data goes to a temporary file that is synced to disk and renamed over
+    /// the old one. The old file is first kept as backup `.1`, and older
+    /// backups move up by one, keeping [`WALLET_BACKUPS`] of them.
     pub fn save_to_file(&self) {
-        let path = current_dir().unwrap().join(WALLET_FILE);
-        let file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(&path)
-            .expect("Unable to open wallet.data");
-        let mut writer = BufWriter::new(&file);
-        let wallets_bytes = bincode::serialize(&self.wallets).expect("Unable to serialize wallets");
-        writer.write(&wallets_bytes).expect("Unable to write wallets to file");
-        let _ = writer.flush();
-    }
-}
\ No newline at end of file
+        let bytes = self.encode();
+        if fs::read(&self.path).is_ok_and(|old| old == bytes) {
+            return;
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).expect("Unable to create wallet directory");
+        }
+        if self.path.exists() {
+            rotate_backups(&self.path).expect("Unable to back up the wallet file");
+        }
+        write_atomically(&self.path, &bytes).expect("Unable to write wallets to file");
+    }
+
+    /// Writes a copy of the wallet to `destination`, or into it under the
+    /// wallet's file name if it is a directory.
+    pub fn backup_to(&self, destination: &Path) -> io::Result<PathBuf> {
+        let path = if destination.is_dir() {
+            destination.join(self.path.file_name().unwrap_or_default())
+        } else {
+            destination.to_path_buf()
+        };
+        if path == self.path {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the backup would replace the wallet file",
+            ));
+        }
+        write_atomically(&path, &self.encode())?;
+        Ok(path)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let file = WalletFile {
+            keys: self.wallets.clone(),
+            watch_only: self.watch_only.clone(),
+            metadata: self.metadata.clone(),
+            coins: self.coins.clone(),
+            pending: self.pending.clone(),
+            blocks: self.blocks.clone(),
+            locked: self.locked.clone(),
+        };
+        let mut bytes = WALLET_MAGIC.to_vec();
+        bytes.extend(WALLET_FORMAT_VERSION.to_le_bytes());
+        bytes.extend(bincode::serialize(&file).expect("Unable to serialize wallets"));
+        bytes
+    }
+}
+
+impl WalletFile {
+    /// Reads a wallet file of the current or any earlier format.
+    fn decode(bytes: &[u8]) -> Result<WalletFile, String> {
+        let Some(rest) = bytes.strip_prefix(WALLET_MAGIC.as_slice()) else {
+            return WalletFile::decode_unversioned(bytes);
+        };
+        if rest.len() < 4 {
+            return Err("truncated header".to_string());
+        }
+        let (version, body) = rest.split_at(4);
+        let version = u32::from_le_bytes(version.try_into().unwrap());
+        // each new format adds an arm converting from the previous one
+        match version {
+            1 => strict().deserialize(body).map_err(|e| e.to_string()),
+            _ => Err(format!(
+                "format version {} is newer than this program supports ({})",
+                version, WALLET_FORMAT_VERSION
+            )),
+        }
+    }
+
+    /// Files written before the format was versioned. Their layouts differ
+    /// only in the fields present, so each is tried, newest first, and must
+    /// use every byte of the file and describe a consistent wallet.
+    fn decode_unversioned(bytes: &[u8]) -> Result<WalletFile, String> {
+        let layouts = [
+            strict().deserialize::<WalletFile>(bytes).ok(),
+            strict().deserialize::<WatchOnlyLayout>(bytes).ok().map(WalletFile::from),
+            strict().deserialize::<LockedLayout>(bytes).ok().and_then(LockedLayout::convert),
+            strict()
+                .deserialize::<CoinsLayout>(bytes)
+                .ok()
+                .and_then(|file| LockedLayout::from(file).convert()),
+        ];
+        if let Some(file) = layouts.into_iter().flatten().find(WalletFile::is_consistent) {
+            return Ok(file);
+        }
+        // the original format held only the keys
+        let keys: HashMap<String, Wallet> = strict()
+            .deserialize(bytes)
+            .map_err(|_| "unrecognized wallet file layout".to_string())?;
+        Ok(WalletFile {
+            keys,
+            ..Default::default()
+        })
+    }
+
+    /// Checks what every wallet we wrote satisfies: coins are filed under
+    /// their outpoint and were created in a block we connected, and labels
+    /// belong to our addresses.
+    fn is_consistent(&self) -> bool {
+        let blocks: HashSet<&Vec<u8>> = self.blocks.iter().collect();
+        self.blocks.iter().all(|hash| hash.len() == 32)
+            && self.coins.iter().all(|((txid, vout), coin)| {
+                coin.txid == *txid && coin.vout == *vout && blocks.contains(&coin.block_hash)
+            })
+            && self
+                .metadata
+                .keys()
+                .all(|address| self.keys.contains_key(address) || self.watch_only.contains_key(address))
+    }
+}
+
+/// An unversioned wallet file from before addresses had labels and
+/// creation times.
+#[derive(Deserialize)]
+struct WatchOnlyLayout {
+    keys: HashMap<String, Wallet>,
+    watch_only: HashMap<String, WatchOnly>,
+    coins: BTreeMap<(Vec<u8>, usize), WalletCoin>,
+    pending: Vec<Transaction>,
+    blocks: Vec<Vec<u8>>,
+    locked: BTreeSet<(Vec<u8>, usize)>,
+}
+
+impl From<WatchOnlyLayout> for WalletFile {
+    fn from(file: WatchOnlyLayout) -> Self {
+        WalletFile {
+            keys: file.keys,
+            watch_only: file.watch_only,
+            metadata: HashMap::new(),
+            coins: file.coins,
+            pending: file.pending,
+            blocks: file.blocks,
+            locked: file.locked,
+        }
+    }
+}
+
+/// A coin of the unversioned files that only recorded the block spending
+/// it. Most fields are only decoded to get past them.
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct SpentInCoin {
+    txid: Vec<u8>,
+    vout: usize,
+    output: TXOutput,
+    address: String,
+    block_hash: Vec<u8>,
+    height: u64,
+    spent_in: Option<Vec<u8>>,
+}
+
+/// An unversioned wallet file from before watch-only addresses.
+#[derive(Deserialize)]
+struct LockedLayout {
+    keys: HashMap<String, Wallet>,
+    coins: BTreeMap<(Vec<u8>, usize), SpentInCoin>,
+    pending: Vec<Transaction>,
+    blocks: Vec<Vec<u8>>,
+    locked: BTreeSet<(Vec<u8>, usize)>,
+}
+
+/// The first wallet file that tracked coins, from before output locks.
+#[derive(Deserialize)]
+struct CoinsLayout {
+    keys: HashMap<String, Wallet>,
+    coins: BTreeMap<(Vec<u8>, usize), SpentInCoin>,
+    pending: Vec<Transaction>,
+    blocks: Vec<Vec<u8>>,
+}
+
+impl From<CoinsLayout> for LockedLayout {
+    fn from(file: CoinsLayout) -> Self {
+        LockedLayout {
+            keys: file.keys,
+            coins: file.coins,
+            pending: file.pending,
+            blocks: file.blocks,
+            locked: BTreeSet::new(),
+        }
+    }
+}
+
+impl LockedLayout {
+    /// Keeps the keys, pending transactions and locks. The coins lack the
+    /// transactions spending them, so they and the blocks are dropped and
+    /// the next sync scans the chain again. Returns `None` if the coins do
+    /// not match the blocks, as they would for another layout.
+    fn convert(self) -> Option<WalletFile> {
+        let blocks: HashSet<&Vec<u8>> = self.blocks.iter().collect();
+        let consistent = self.coins.iter().all(|((txid, vout), coin)| {
+            coin.txid == *txid
+                && coin.vout == *vout
+                && blocks.contains(&coin.block_hash)
+                && coin.spent_in.as_ref().is_none_or(|hash| blocks.contains(hash))
+        });
+        consistent.then(|| WalletFile {
+            keys: self.keys,
+            pending: self.pending,
+            locked: self.locked,
+            ..Default::default()
+        })
+    }
+}
+
+/// The encoding of [`bincode::serialize`], except that bytes left over
+/// after the value are an error rather than ignored.
+fn strict() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+}
+
+/// Returns `path` with `suffix` appended to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Copies the file at `path` to `path.1`, after moving `path.1` to
+/// `path.2` and so on, dropping the oldest backup.
+fn rotate_backups(path: &Path) -> io::Result<()> {
+    for n in (1..WALLET_BACKUPS).rev() {
+        let from = with_suffix(path, &format!(".{}", n));
+        if from.exists() {
+            fs::rename(&from, with_suffix(path, &format!(".{}", n + 1)))?;
+        }
+    }
+    fs::copy(path, with_suffix(path, ".1"))?;
+    Ok(())
+}
+
+/// Replaces the file at `path` with `bytes` so that a crash leaves either
+/// the old or the new contents.
+fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = with_suffix(path, ".tmp");
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)?;
+    // make the rename itself durable; directories cannot be opened everywhere
+    if let Some(dir) = path.parent().and_then(|dir| File::open(dir).ok()) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bcdb::{BlockchainDb, MIN_BLOCKS_TO_KEEP};
+    use crate::blockfiles::BlockFiles;
+    use crate::store::MemoryStore;
+    use crate::{coinselect::CoinSelector, network::Network, transaction};
+
+    #[test]
+    fn pending_spends_are_excluded_until_mined_and_restored_on_disconnect() {
+        let dir = utils::TempDir::new("wallets-test");
+        let config = Config::builder().data_dir(dir.path()).network(Network::Regtest).build();
+        let mut wallets = Wallets::new(&config);
+        let ours = wallets.create_wallet();
+        let payee = wallet::calc_address(0x6f, &[5; 20]);
+
+        let mut blockchain = Blockchain::in_memory(config.params(), &ours);
+        blockchain.generate(1, &ours, Vec::new());
+        wallets.sync(&blockchain);
+        assert_eq!(wallets.synced_height(), Some(1));
+        assert_eq!(wallets.unspent_coins().len(), 2);
+
+        // two payments before mining spend different coins
+        let selector = CoinSelector::default();
+        let recipients = [(payee, 10)];
+        for _ in 0..2 {
+            let tx = transaction::new_transaction(
+                &recipients,
+                &[],
+                &[],
+                None,
+                &selector,
+                &blockchain,
+                &wallets,
+            )
+            .unwrap();
+            wallets.add_pending(tx);
+        }
+        assert!(wallets.unspent_coins().is_empty());
+        let spent: HashSet<_> = wallets
+            .pending_transactions()
+            .iter()
+            .map(|tx| (tx.vin[0].txid.clone(), tx.vin[0].vout))
+            .collect();
+        assert_eq!(spent.len(), 2);
+
+        let pending = wallets.pending_transactions().to_vec();
+        let hashes = blockchain.generate(1, &ours, pending);
+        wallets.sync(&blockchain);
+        assert!(wallets.pending_transactions().is_empty());
+        assert_eq!(wallets.unspent_coins().len(), 1);
+
+        let block = blockchain.get_block(&hashes[0]).unwrap();
+        wallets.disconnect_block(&block);
+        assert_eq!(wallets.synced_height(), Some(1));
+        assert_eq!(wallets.pending_transactions().len(), 2);
+        assert!(wallets.unspent_coins().is_empty());
+    }
+
+    #[test]
+    fn watch_only_outputs_are_tracked_but_not_signed() {
+        let dir = utils::TempDir::new("watch-test");
+        let config = Config::builder().data_dir(dir.path()).network(Network::Regtest).build();
+        let mut wallets = Wallets::new(&config);
+        let watched = Wallet::new();
+        let address = watched.address(0x6f);
+        wallets.import_watch_only(&address, Some(watched.get_public_key()));
+        assert!(wallets.get_addresses().is_empty());
+
+        let blockchain = Blockchain::in_memory(config.params(), &address);
+        wallets.sync(&blockchain);
+        assert_eq!(wallets.history()[0].amount, 10);
+
+        let selector = CoinSelector::default();
+        let recipients = [(wallet::calc_address(0x6f, &[5; 20]), 4)];
+        let from = [address];
+        let tx = transaction::new_unsigned_transaction(
+            &recipients,
+            &[],
+            &[],
+            None,
+            &selector,
+            &blockchain,
+            &wallets,
+        )
+        .unwrap();
+        assert_eq!(tx.vin[0].pub_key, watched.get_public_key());
+        assert!(tx.vin[0].signature.is_empty());
+        let err = transaction::new_transaction(
+            &recipients,
+            &from,
+            &[],
+            None,
+            &selector,
+            &blockchain,
+            &wallets,
+        )
+        .unwrap_err();
+        assert!(err.contains("watch-only"), "{}", err);
+    }
+
+    #[test]
+    fn wallets_on_a_pruned_chain_start_from_the_utxo_set() {
+        let dir = utils::TempDir::new("pruned-wallet-test");
+        let config = Config::builder().data_dir(dir.path()).network(Network::Regtest).build();
+        let mut wallets = Wallets::new(&config);
+        let ours = wallets.create_wallet();
+        let files = BlockFiles::open(&config.blocks_dir()).unwrap().max_file_size(4096);
+        let db = BlockchainDb::with_store(MemoryStore::new())
+            .with_block_files(files)
+            .with_prune_target(Some(8192));
+        let mut blockchain = Blockchain::with_db(db, config.params().clone(), &ours);
+        let count = MIN_BLOCKS_TO_KEEP as usize + 40;
+        let hashes = blockchain.generate(count, &ours, Vec::new());
+        assert!(blockchain.get_block(&hashes[0]).is_none());
+
+        wallets.sync(&blockchain);
+        assert_eq!(wallets.synced_tip(), Some(blockchain.tip.as_slice()));
+        assert_eq!(wallets.unspent_coins().len(), count + 1);
+        let recipients = [(wallet::calc_address(0x6f, &[5; 20]), 15)];
+        let selector = CoinSelector::default();
+        let tx = transaction::new_transaction(
+            &recipients,
+            &[],
+            &[],
+            None,
+            &selector,
+            &blockchain,
+            &wallets,
+        )
+        .unwrap();
+        assert!(tx.verify(&blockchain));
+
+        // a rescan cannot read the pruned blocks either
+        wallets.add_pending(tx);
+        wallets.rescan(&blockchain);
+        assert_eq!(wallets.synced_height(), Some(count as u64));
+        assert_eq!(wallets.unspent_coins().len(), count - 1);
+        assert_eq!(wallets.pending_transactions().len(), 1);
+    }
+
+    #[test]
+    fn saves_keep_backups_and_old_formats_still_load() {
+        let dir = utils::TempDir::new("walletfile-test");
+        let config = Config::builder().data_dir(dir.path()).network(Network::Regtest).build();
+        let path = config.wallet_path().to_path_buf();
+
+        let mut wallets = Wallets::new(&config);
+        let first = wallets.create_wallet();
+        wallets.save_to_file();
+        wallets.create_wallet();
+        wallets.save_to_file();
+        assert!(fs::read(&path).unwrap().starts_with(&WALLET_MAGIC));
+        let backup = fs::read(with_suffix(&path, ".1")).unwrap();
+        assert_eq!(WalletFile::decode(&backup).unwrap().keys.len(), 1);
+        assert!(!with_suffix(&path, ".tmp").exists());
+        assert_eq!(Wallets::new(&config).get_addresses().len(), 2);
+
+        // the original format held only the keys
+        let legacy = bincode::serialize(&wallets.wallets).unwrap();
+        let migrated = WalletFile::decode(&legacy).unwrap();
+        assert_eq!(migrated.keys.len(), 2);
+        assert!(migrated.keys.contains_key(&first));
+
+        // files from before labels were added keep their watch-only
+        // addresses, coins and locks
+        let blockchain = Blockchain::in_memory(config.params(), &first);
+        wallets.sync(&blockchain);
+        let coin = wallets.unspent_coins()[0].clone();
+        assert!(wallets.lock_coin(&coin.txid, coin.vout));
+        let watched = wallet::calc_address(0x6f, &[8; 20]);
+        let watch_only = HashMap::from([(watched.clone(), WatchOnly { public_key: None })]);
+        let old = bincode::serialize(&(
+            &wallets.wallets,
+            &watch_only,
+            &wallets.coins,
+            &wallets.pending,
+            &wallets.blocks,
+            &wallets.locked,
+        ))
+        .unwrap();
+        let migrated = WalletFile::decode(&old).unwrap();
+        assert_eq!(migrated.keys.len(), 2);
+        assert!(migrated.watch_only.contains_key(&watched));
+        assert_eq!(migrated.coins.len(), 1);
+        assert_eq!(migrated.blocks, wallets.blocks);
+        assert_eq!(migrated.locked, wallets.locked);
+
+        // files from before watch-only addresses have coins without the
+        // spending transaction; they are scanned again, keeping what is
+        // pending
+        let tx = transaction::new_transaction(
+            &[(wallet::calc_address(0x6f, &[5; 20]), 4)],
+            &[],
+            &[(coin.txid.clone(), coin.vout)],
+            None,
+            &CoinSelector::default(),
+            &blockchain,
+            &wallets,
+        )
+        .unwrap();
+        wallets.add_pending(tx);
+        let coins: BTreeMap<_, _> = wallets
+            .coins
+            .iter()
+            .map(|(outpoint, coin)| {
+                let spent_in: Option<Vec<u8>> = None;
+                let fields = (&coin.txid, coin.vout, &coin.output, &coin.address);
+                (outpoint, (fields, &coin.block_hash, coin.height, spent_in))
+            })
+            .collect();
+        let first_layout =
+            bincode::serialize(&(&wallets.wallets, &coins, &wallets.pending, &wallets.blocks)).unwrap();
+        let migrated = WalletFile::decode(&first_layout).unwrap();
+        assert_eq!(migrated.keys.len(), 2);
+        assert!(migrated.coins.is_empty() && migrated.blocks.is_empty());
+        fs::write(&path, &first_layout).unwrap();
+        let mut reloaded = Wallets::new(&config);
+        reloaded.sync(&blockchain);
+        assert_eq!(reloaded.coins.len(), 1);
+        assert_eq!(reloaded.pending_transactions().len(), 1);
+
+        let mut unknown = old.clone();
+        unknown.push(0);
+        assert!(WalletFile::decode(&unknown).err().unwrap().contains("unrecognized"));
+
+        let mut newer = WALLET_MAGIC.to_vec();
+        newer.extend((WALLET_FORMAT_VERSION + 1).to_le_bytes());
+        assert!(WalletFile::decode(&newer).err().unwrap().contains("newer"));
+    }
+}